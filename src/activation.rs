@@ -0,0 +1,111 @@
+use crate::downloads::Version;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Shells `env` can write activation scripts for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Shell {
+    /// sh, bash, zsh and the like
+    Sh,
+    Fish,
+}
+
+impl Shell {
+    /// The login shell, going by `$SHELL`.
+    pub fn detect() -> Self {
+        match std::env::var_os("SHELL") {
+            Some(shell) if Path::new(&shell).ends_with("fish") => Self::Fish,
+            _ => Self::Sh,
+        }
+    }
+
+    const fn file_name(self) -> &'static str {
+        match self {
+            Self::Sh => "env.sh",
+            Self::Fish => "env.fish",
+        }
+    }
+
+    fn quote(self, s: &str) -> String {
+        match self {
+            Self::Sh => format!("'{}'", s.replace('\'', r"'\''")),
+            Self::Fish => format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'")),
+        }
+    }
+
+    /// A script pointing `PATH`, `PHPRC`, `PHP_INI_SCAN_DIR` and `PKG_CONFIG_PATH` at the
+    /// install in `prefix`.  PHP looks for php.ini in `{prefix}/lib` unless told otherwise.
+    pub fn script(self, version: Version, prefix: &Path) -> String {
+        let path = |rel: &str| self.quote(&prefix.join(rel).to_string_lossy());
+        let (bin, lib, scan, pkgconfig) = (
+            path("bin"),
+            path("lib"),
+            path("lib/conf.d"),
+            path("lib/pkgconfig"),
+        );
+
+        let usage = match self {
+            Self::Sh => format!("eval \"$(php-downloader env {version})\""),
+            Self::Fish => format!("php-downloader env --shell fish {version} | source"),
+        };
+
+        let exports = match self {
+            Self::Sh => format!(
+                "export PATH={bin}:\"$PATH\"\n\
+                 export PHPRC={lib}\n\
+                 export PHP_INI_SCAN_DIR={scan}\n\
+                 export PKG_CONFIG_PATH={pkgconfig}\"${{PKG_CONFIG_PATH:+:$PKG_CONFIG_PATH}}\"\n"
+            ),
+            Self::Fish => format!(
+                "set -gx PATH {bin} $PATH\n\
+                 set -gx PHPRC {lib}\n\
+                 set -gx PHP_INI_SCAN_DIR {scan}\n\
+                 set -gx PKG_CONFIG_PATH {pkgconfig} $PKG_CONFIG_PATH\n"
+            ),
+        };
+
+        format!("# PHP {version}, use with: {usage}\n{exports}")
+    }
+
+    /// Where this shell's script for the install in `prefix` lives.
+    pub fn path(self, prefix: &Path) -> PathBuf {
+        prefix.join(self.file_name())
+    }
+}
+
+/// Write an activation script for every shell into `prefix`.
+///
+/// # Errors
+///
+/// Fails if a script can't be written.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn write(version: Version, prefix: &Path) -> Result<()> {
+    for shell in Shell::value_variants() {
+        let path = shell.path(prefix);
+        fs::write(&path, shell.script(version, prefix))
+            .context(format!("Unable to write {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_prefix() {
+        let version = Version::from_major_minor_patch(8, 3, 4);
+        let sh = Shell::Sh.script(version, Path::new("/opt/it's php"));
+
+        assert!(sh.contains(r"export PATH='/opt/it'\''s php/bin':"));
+        assert!(sh.contains("export PHPRC='/opt/it'\\''s php/lib'\n"));
+
+        let fish = Shell::Fish.script(version, Path::new("/opt/it's php"));
+        assert!(fish.contains(r"set -gx PATH '/opt/it\'s php/bin' $PATH"));
+    }
+}
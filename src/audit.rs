@@ -0,0 +1,74 @@
+use crate::config::Config;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+/// How the user agreed to a destructive action.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Confirmation {
+    /// Answered yes at a prompt.
+    Interactive,
+    /// The prompt was skipped with `--yes`.
+    Assumed,
+    /// Asked for directly by a flag or subcommand, so there was no prompt.
+    Implicit,
+}
+
+impl Confirmation {
+    /// What a prompt answered right now amounts to.
+    pub fn prompted() -> Self {
+        if Config::assume_yes() {
+            Self::Assumed
+        } else {
+            Self::Interactive
+        }
+    }
+}
+
+/// One line of `~/.phpdownloader/audit.log`.
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    time: String,
+    user: Option<String>,
+    pid: u32,
+    command: Vec<String>,
+    action: &'a str,
+    path: &'a Path,
+    confirmation: Confirmation,
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+fn append(action: &str, path: &Path, confirmation: Confirmation) -> Result<()> {
+    let entry = AuditEntry {
+        time: Utc::now().to_rfc3339(),
+        user: std::env::var("USER")
+            .or_else(|_| std::env::var("LOGNAME"))
+            .ok(),
+        pid: std::process::id(),
+        command: std::env::args().collect(),
+        action,
+        path,
+        confirmation,
+    };
+
+    let dst = Config::audit_path()?;
+    let line = serde_json::to_string(&entry)? + "\n";
+
+    // A single short write with O_APPEND, so concurrent invocations don't interleave lines
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&dst)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .context(format!("Unable to append to {dst:?}"))
+}
+
+/// Log that `path` was removed.  The removal already happened, so a log we can't write to is
+/// only a warning.
+pub fn record(action: &str, path: &Path, confirmation: Confirmation) {
+    if let Err(e) = append(action, path, confirmation) {
+        eprintln!("Warning:  {e:#}");
+    }
+}
@@ -0,0 +1,129 @@
+use crate::config::Config;
+use reqwest::{RequestBuilder, Url};
+use serde::Deserialize;
+use std::{env, fs, path::PathBuf};
+
+/// Credentials for a mirror, keyed by host in the `auth` setting.  Secrets can be given inline
+/// or read from an environment variable so they stay out of config.json.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct MirrorAuth {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_env: Option<String>,
+    pub token: Option<String>,
+    pub token_env: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Credentials {
+    Basic(String, Option<String>),
+    Bearer(String),
+}
+
+fn from_env(name: Option<&String>) -> Option<String> {
+    name.and_then(|name| env::var(name).ok())
+}
+
+impl MirrorAuth {
+    fn credentials(&self) -> Option<Credentials> {
+        if let Some(token) = self
+            .token
+            .clone()
+            .or_else(|| from_env(self.token_env.as_ref()))
+        {
+            return Some(Credentials::Bearer(token));
+        }
+
+        let password = self
+            .password
+            .clone()
+            .or_else(|| from_env(self.password_env.as_ref()));
+
+        self.username
+            .clone()
+            .map(|username| Credentials::Basic(username, password))
+    }
+}
+
+fn netrc_path() -> Option<PathBuf> {
+    env::var_os("NETRC")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc")))
+}
+
+// Tokens are whitespace separated: `machine <host> login <user> password <pass>`, with a
+// `default` entry matching any host
+fn netrc_lookup(contents: &str, host: &str) -> Option<Credentials> {
+    let mut tokens = contents.split_whitespace();
+    let mut matched = false;
+    let mut login = None;
+    let mut password = None;
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" if matched => break,
+            "machine" => matched = tokens.next() == Some(host),
+            "default" => matched = true,
+            "login" if matched => login = tokens.next().map(String::from),
+            "password" if matched => password = tokens.next().map(String::from),
+            _ => {}
+        }
+    }
+
+    login.map(|login| Credentials::Basic(login, password))
+}
+
+fn credentials_for(host: &str) -> Option<Credentials> {
+    if let Some(auth) = Config::settings().auth.get(host) {
+        return auth.credentials();
+    }
+
+    let contents = fs::read_to_string(netrc_path()?).ok()?;
+    netrc_lookup(&contents, host)
+}
+
+/// Username and password for a proxy on `host`, from the `auth` setting or `.netrc`.  Proxies only
+/// take basic auth, so token credentials are ignored.
+pub fn proxy_credentials(host: &str) -> Option<(String, String)> {
+    match credentials_for(host)? {
+        Credentials::Basic(user, password) => Some((user, password.unwrap_or_default())),
+        Credentials::Bearer(_) => None,
+    }
+}
+
+/// Add whatever credentials we have for the host `url` points at.
+pub fn authorize(req: RequestBuilder, url: &str) -> RequestBuilder {
+    let Some(host) = Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(String::from))
+    else {
+        return req;
+    };
+
+    match credentials_for(&host) {
+        Some(Credentials::Basic(user, password)) => req.basic_auth(user, password),
+        Some(Credentials::Bearer(token)) => req.bearer_auth(token),
+        None => req,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netrc_entries() {
+        let netrc = "machine php.net login anon\nmachine mirror.local login ci password s3cret\n";
+
+        assert_eq!(
+            netrc_lookup(netrc, "mirror.local"),
+            Some(Credentials::Basic("ci".into(), Some("s3cret".into())))
+        );
+        assert_eq!(netrc_lookup(netrc, "example.com"), None);
+        assert_eq!(
+            netrc_lookup("default login me password pw", "example.com"),
+            Some(Credentials::Basic("me".into(), Some("pw".into())))
+        );
+    }
+}
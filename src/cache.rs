@@ -0,0 +1,317 @@
+use crate::{
+    auth,
+    config::Config,
+    downloads::{self, send, timed, DownloadInfo, Extension, Version},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Client, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    result::Result as StdResult,
+    sync::{Mutex, OnceLock},
+};
+use tempfile::NamedTempFile;
+
+type SeriesKey = (u8, u8, Extension);
+
+static LISTS: OnceLock<Mutex<HashMap<SeriesKey, Vec<DownloadInfo>>>> = OnceLock::new();
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedInfo {
+    version: Version,
+    location: String,
+    size: u64,
+    date: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedList {
+    fetched: i64,
+    entries: Vec<CachedInfo>,
+}
+
+/// A raw HTTP response body kept for revalidation with its `ETag` or `Last-Modified` date.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedResponse {
+    url: String,
+    fetched: i64,
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+    body: String,
+}
+
+/// Replace `path` with `data` via a temporary file and rename, so concurrent runs never see a
+/// half written file.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.as_file()
+        .set_permissions(fs::Permissions::from_mode(0o644))?;
+    tmp.write_all(data)?;
+    tmp.persist(path)?;
+
+    Ok(())
+}
+
+// Anything stamped in the future means the clock moved backwards, so treat it as stale rather
+// than trusting it for however long the clock was off.
+fn is_fresh(fetched: i64) -> bool {
+    let age = Utc::now().timestamp() - fetched;
+    age >= 0 && age.unsigned_abs() < Config::cache_ttl().as_secs()
+}
+
+fn lists() -> &'static Mutex<HashMap<SeriesKey, Vec<DownloadInfo>>> {
+    LISTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Listings from a `--base-url` server are kept apart from php.net's, so switching between them
+// never serves one's releases as the other's
+fn list_file((major, minor, extension): SeriesKey) -> Result<PathBuf> {
+    let mut path = Config::cache_path()?;
+    match Config::base_url() {
+        Some(url) => path.push(format!(
+            "list-{major}.{minor}-{extension}-{:08x}.json",
+            crc32fast::hash(url.as_bytes())
+        )),
+        None => path.push(format!("list-{major}.{minor}-{extension}.json")),
+    }
+    Ok(path)
+}
+
+fn load_disk(key: SeriesKey, any_age: bool) -> Option<Vec<DownloadInfo>> {
+    let bytes = fs::read(list_file(key).ok()?).ok()?;
+    let cached: CachedList = serde_json::from_slice(&bytes).ok()?;
+
+    if !any_age && !is_fresh(cached.fetched) {
+        return None;
+    }
+
+    let entries = cached
+        .entries
+        .into_iter()
+        .map(|e| {
+            let date = e.date.and_then(|ts| DateTime::from_timestamp(ts, 0));
+            DownloadInfo::new(e.version, &e.location, e.size, date, key.2)
+        })
+        .collect();
+
+    Some(entries)
+}
+
+fn store_disk(key: SeriesKey, urls: &[DownloadInfo]) -> Result<()> {
+    let cached = CachedList {
+        fetched: Utc::now().timestamp(),
+        entries: urls
+            .iter()
+            .map(|u| CachedInfo {
+                version: u.version,
+                location: u.location.clone(),
+                size: u.size,
+                date: u.date.map(|d| d.timestamp()),
+            })
+            .collect(),
+    };
+
+    write_atomic(&list_file(key)?, &serde_json::to_vec(&cached)?)?;
+
+    Ok(())
+}
+
+/// Look up a previously fetched listing for a series, first in this process and then on disk.
+/// Listings themselves simply expire after the cache TTL.  Rebuilding one fetches the releases
+/// API through `fetch`, so for an unchanged series that's a conditional request answered with
+/// 304 Not Modified rather than a full download.
+pub fn load_list(major: u8, minor: u8, extension: Extension) -> Option<Vec<DownloadInfo>> {
+    if Config::cache_ttl().is_zero() {
+        return None;
+    }
+
+    let key = (major, minor, extension);
+
+    if let Some(urls) = lists().lock().ok()?.get(&key) {
+        return Some(urls.clone());
+    }
+
+    let urls = load_disk(key, false)?;
+    lists().lock().ok()?.insert(key, urls.clone());
+
+    Some(urls)
+}
+
+/// The last listing fetched for a series however old it is, for when php.net can't be reached.
+pub fn load_stale_list(major: u8, minor: u8, extension: Extension) -> Option<Vec<DownloadInfo>> {
+    load_disk((major, minor, extension), true)
+}
+
+/// Remember a listing for a series.  Failing to write the on-disk copy isn't fatal, we'll just
+/// fetch it again next time.
+pub fn store_list(major: u8, minor: u8, extension: Extension, urls: &[DownloadInfo]) {
+    if Config::cache_ttl().is_zero() || urls.is_empty() {
+        return;
+    }
+
+    let key = (major, minor, extension);
+
+    if let Ok(mut lists) = lists().lock() {
+        lists.insert(key, urls.to_vec());
+    }
+
+    let _ = store_disk(key, urls);
+}
+
+fn response_name(url: &str) -> String {
+    format!("http-{:08x}.json", crc32fast::hash(url.as_bytes()))
+}
+
+fn response_file(url: &str) -> Result<PathBuf> {
+    let mut path = Config::cache_path()?;
+    path.push(response_name(url));
+    Ok(path)
+}
+
+fn load_response(url: &str) -> Option<CachedResponse> {
+    let bytes = fs::read(response_file(url).ok()?).ok()?;
+    let cached: CachedResponse = serde_json::from_slice(&bytes).ok()?;
+
+    (cached.url == url).then_some(cached)
+}
+
+fn store_response(cached: &CachedResponse) -> Result<()> {
+    write_atomic(&response_file(&cached.url)?, &serde_json::to_vec(cached)?)?;
+    Ok(())
+}
+
+fn header_string(headers: &HeaderMap, name: impl reqwest::header::AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// GET `url` through the on-disk cache.  Fresh responses are served as-is, stale ones are
+/// revalidated with `If-None-Match` or `If-Modified-Since`, and if the server can't be reached at
+/// all a stale copy is better than nothing.
+pub async fn fetch(client: &Client, url: &str) -> Result<String> {
+    if Config::cache_ttl().is_zero() {
+        let req = auth::authorize(client.get(url), url);
+        let res = send(req).await?.error_for_status()?;
+        return timed(res.text()).await;
+    }
+
+    let cached = load_response(url);
+
+    if let Some(cached) = cached.as_ref().filter(|c| is_fresh(c.fetched)) {
+        return Ok(cached.body.clone());
+    }
+
+    let mut req = auth::authorize(client.get(url), url);
+    if let Some(cached) = &cached {
+        if let Some(etag) = &cached.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = &cached.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, date);
+        }
+    }
+
+    let res = match send(req).await {
+        Ok(res) => res,
+        Err(e) => match cached {
+            Some(cached) => {
+                eprintln!("Warning:  Using a stale copy of {url} ({e})");
+                return Ok(cached.body);
+            }
+            None => return Err(e),
+        },
+    };
+
+    let cached = match cached {
+        Some(cached) if res.status() == StatusCode::NOT_MODIFIED => CachedResponse {
+            fetched: Utc::now().timestamp(),
+            ..cached
+        },
+        _ => {
+            let res = res.error_for_status()?;
+            CachedResponse {
+                url: url.to_string(),
+                fetched: Utc::now().timestamp(),
+                etag: header_string(res.headers(), ETAG),
+                last_modified: header_string(res.headers(), LAST_MODIFIED),
+                body: timed(res.text()).await?,
+            }
+        }
+    };
+
+    let _ = store_response(&cached);
+
+    Ok(cached.body)
+}
+
+/// Remove cached listings for one series, along with the API responses they were built from, or
+/// everything we've cached.  Returns how many files were removed.
+pub fn clear(series: Option<(u8, u8)>) -> Result<usize> {
+    clear_in(&Config::cache_path()?, series)
+}
+
+fn clear_in(dir: &Path, series: Option<(u8, u8)>) -> Result<usize> {
+    let prefix = series.map(|(major, minor)| format!("list-{major}.{minor}-"));
+    let responses: Vec<_> = series
+        .map(|(major, minor)| downloads::api_urls(&downloads::series_api_path(major, minor)))
+        .unwrap_or_default()
+        .iter()
+        .map(|url| response_name(url))
+        .collect();
+    let mut removed = 0;
+
+    for entry in fs::read_dir(dir)?.filter_map(StdResult::ok) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+
+        if prefix.as_ref().is_none_or(|p| name.starts_with(p)) || responses.contains(&name) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+
+    if let Ok(mut lists) = lists().lock() {
+        lists.retain(|(major, minor, _), _| series.is_some_and(|s| s != (*major, *minor)));
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clear_series_removes_its_responses() {
+        let dir = tempfile::tempdir().expect("Can't create cache dir");
+        let touch = |name: &str| fs::write(dir.path().join(name), "{}").expect("Can't write file");
+
+        let response = |major, minor| {
+            let urls = downloads::api_urls(&downloads::series_api_path(major, minor));
+            response_name(&urls[0])
+        };
+
+        touch("list-8.3-xz.json");
+        touch(&response(8, 3));
+        touch("list-8.2-xz.json");
+        touch(&response(8, 2));
+
+        assert_eq!(clear_in(dir.path(), Some((8, 3))).expect("Can't clear"), 2);
+        assert!(!dir.path().join(response(8, 3)).exists());
+        assert!(dir.path().join(response(8, 2)).exists());
+        assert!(dir.path().join("list-8.2-xz.json").exists());
+    }
+}
@@ -0,0 +1,331 @@
+use crate::{config::Config, downloads::Version};
+use anyhow::{bail, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A host library version that's known to break older PHP releases.
+struct Rule {
+    /// pkg-config module name
+    module: &'static str,
+    name: &'static str,
+    /// PHP releases before this major.minor are affected
+    php_before: (u8, u8),
+    /// ...when the library is at least this major.minor
+    lib_from: (u32, u32),
+}
+
+const RULES: &[Rule] = &[
+    Rule {
+        module: "openssl",
+        name: "OpenSSL",
+        php_before: (7, 1),
+        lib_from: (1, 1),
+    },
+    Rule {
+        module: "openssl",
+        name: "OpenSSL",
+        php_before: (8, 1),
+        lib_from: (3, 0),
+    },
+    Rule {
+        module: "icu-uc",
+        name: "ICU",
+        php_before: (7, 4),
+        lib_from: (68, 0),
+    },
+];
+
+/// What on the host makes a fix necessary.
+enum Needs {
+    /// At least this GCC major version
+    Gcc(u32),
+    /// At least this major.minor of a pkg-config module
+    Library(&'static str, (u32, u32)),
+}
+
+/// How a fix is applied.
+enum Remedy {
+    /// Extra `CFLAGS` for configure and make, only useful when the hooks run
+    Cflags(&'static str),
+    /// Wrap every line of `file` that mentions `symbol` in `#ifdef symbol`
+    Guard {
+        file: &'static str,
+        symbol: &'static str,
+    },
+}
+
+/// A build fix for older PHP releases on newer toolchains, applied with `--compat-patches`.
+struct Fix {
+    name: &'static str,
+    /// PHP releases before this major.minor need it
+    php_before: (u8, u8),
+    needs: Needs,
+    remedy: Remedy,
+}
+
+const FIXES: &[Fix] = &[
+    // GCC 10 defaults to -fno-common which breaks tentative definitions in older trees
+    Fix {
+        name: "gcc10-fcommon",
+        php_before: (7, 4),
+        needs: Needs::Gcc(10),
+        remedy: Remedy::Cflags("-fcommon"),
+    },
+    // GCC 14 turned several long-standing warnings into errors
+    Fix {
+        name: "gcc14-permissive",
+        php_before: (8, 0),
+        needs: Needs::Gcc(14),
+        remedy: Remedy::Cflags("-Wno-error=implicit-function-declaration -Wno-error=incompatible-pointer-types -Wno-error=int-conversion"),
+    },
+    // OpenSSL 3 removed RSA_SSLV23_PADDING, which ext/openssl registers as a constant
+    Fix {
+        name: "openssl3-sslv23-padding",
+        php_before: (8, 1),
+        needs: Needs::Library("openssl", (3, 0)),
+        remedy: Remedy::Guard {
+            file: "ext/openssl/openssl.c",
+            symbol: "RSA_SSLV23_PADDING",
+        },
+    },
+];
+
+/// The major version of `cc` if it's GCC.
+fn gcc_major() -> Option<u32> {
+    let output = Command::new("cc").arg("--version").output().ok()?;
+    if !String::from_utf8_lossy(&output.stdout).contains("Free Software Foundation") {
+        return None;
+    }
+
+    let output = Command::new("cc").arg("-dumpversion").output().ok()?;
+    parse_major_minor(String::from_utf8_lossy(&output.stdout).trim()).map(|(major, _)| major)
+}
+
+impl Fix {
+    fn needed(&self, version: Version, gcc: Option<u32>) -> bool {
+        if (version.major, version.minor) >= self.php_before {
+            return false;
+        }
+
+        match self.needs {
+            Needs::Gcc(from) => gcc.is_some_and(|gcc| gcc >= from),
+            Needs::Library(module, from) => module_version(module)
+                .and_then(|v| parse_major_minor(&v))
+                .is_some_and(|v| v >= from),
+        }
+    }
+}
+
+/// Wrap each line of `src` mentioning `symbol` in `#ifdef symbol`/`#endif`, leaving lines that
+/// are already guarded alone.  Returns `None` if nothing changed.
+fn guard_symbol(src: &str, symbol: &str) -> Option<String> {
+    let guard = format!("#ifdef {symbol}");
+    let mut out = String::with_capacity(src.len());
+    let mut prev = "";
+    let mut changed = false;
+
+    for line in src.split_inclusive('\n') {
+        let code = line.trim();
+        if code.contains(symbol) && !code.starts_with('#') && prev.trim() != guard {
+            out.push_str(&guard);
+            out.push('\n');
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str("#endif\n");
+            changed = true;
+        } else {
+            out.push_str(line);
+        }
+        prev = line;
+    }
+
+    changed.then_some(out)
+}
+
+fn patch_files(version: Version) -> Result<Vec<PathBuf>> {
+    let dir = Config::patches_path()?.join(format!("{}.{}", version.major, version.minor));
+
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Ok(vec![]);
+    };
+
+    let mut files: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "patch"))
+        .collect();
+
+    files.sort();
+
+    Ok(files)
+}
+
+/// Names of the built-in `CFLAGS` fixes `version` needs on this host.
+pub fn cflag_fixes(version: Version) -> Vec<String> {
+    let gcc = gcc_major();
+
+    FIXES
+        .iter()
+        .filter(|fix| matches!(fix.remedy, Remedy::Cflags(_)) && fix.needed(version, gcc))
+        .map(|fix| fix.name.to_string())
+        .collect()
+}
+
+/// Apply the fixes `version` needs on this host to the tree at `build_root`.  Built-in source
+/// fixes edit the tree, patch files from `~/.phpdownloader/patches/<major>.<minor>` are applied
+/// with `patch -p1`, and built-in `CFLAGS` fixes (see `cflags`) are only included when
+/// `with_cflags` is set, i.e. when the configure or make hooks will use them.  Returns the names
+/// of everything applied.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn apply_patches(
+    version: Version,
+    build_root: &Path,
+    with_cflags: bool,
+) -> Result<Vec<String>> {
+    let gcc = gcc_major();
+    let mut applied = vec![];
+
+    for fix in FIXES.iter().filter(|fix| fix.needed(version, gcc)) {
+        match fix.remedy {
+            Remedy::Cflags(_) => {
+                if with_cflags {
+                    applied.push(fix.name.to_string());
+                }
+            }
+            Remedy::Guard { file, symbol } => {
+                let path = build_root.join(file);
+                let Ok(src) = fs::read_to_string(&path) else {
+                    continue;
+                };
+
+                if let Some(patched) = guard_symbol(&src, symbol) {
+                    fs::write(&path, patched).context(format!("Unable to patch {path:?}"))?;
+                    applied.push(fix.name.to_string());
+                }
+            }
+        }
+    }
+
+    for file in patch_files(version)? {
+        let status = Command::new("patch")
+            .args(["-p1", "-N", "-s", "-i"])
+            .arg(&file)
+            .current_dir(build_root)
+            .status()
+            .context("Unable to run patch")?;
+
+        if !status.success() {
+            bail!("Failed to apply {file:?}");
+        }
+
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        applied.push(name.into_owned());
+    }
+
+    Ok(applied)
+}
+
+/// Extra `CFLAGS` for the built-in fixes among `applied`.
+pub fn cflags(applied: &[String]) -> Option<String> {
+    let flags: Vec<_> = FIXES
+        .iter()
+        .filter(|fix| applied.iter().any(|name| name == fix.name))
+        .filter_map(|fix| match fix.remedy {
+            Remedy::Cflags(flags) => Some(flags),
+            Remedy::Guard { .. } => None,
+        })
+        .collect();
+
+    (!flags.is_empty()).then(|| flags.join(" "))
+}
+
+fn module_version(module: &str) -> Option<String> {
+    let output = Command::new("pkg-config")
+        .args(["--modversion", module])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn parse_major_minor(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().and_then(|m| m.parse().ok()).unwrap_or(0);
+
+    Some((major, minor))
+}
+
+fn check_with<F>(version: Version, lookup: F) -> Vec<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    RULES
+        .iter()
+        .filter(|rule| (version.major, version.minor) < rule.php_before)
+        .filter_map(|rule| {
+            let host = lookup(rule.module)?;
+            let (major, minor) = parse_major_minor(&host)?;
+
+            ((major, minor) >= rule.lib_from).then(|| {
+                format!(
+                    "PHP {version} needs {} older than {}.{}, but the host has {host}",
+                    rule.name, rule.lib_from.0, rule.lib_from.1
+                )
+            })
+        })
+        .collect()
+}
+
+/// Known incompatibilities between `version` and the libraries installed on this host.
+pub fn check(version: Version) -> Vec<String> {
+    check_with(version, module_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incompatible_libraries() {
+        let host = |module: &str| match module {
+            "openssl" => Some("3.0.2".to_string()),
+            "icu-uc" => Some("74.2".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            check_with(Version::from_major_minor_patch(7, 4, 33), host).len(),
+            1
+        );
+        assert_eq!(
+            check_with(Version::from_major_minor_patch(7, 3, 0), host).len(),
+            2
+        );
+        assert!(check_with(Version::from_major_minor_patch(8, 3, 1), host).is_empty());
+    }
+
+    #[test]
+    fn guards_removed_symbols() {
+        let src = "\tREGISTER_LONG_CONSTANT(\"OPENSSL_SSLV23_PADDING\", RSA_SSLV23_PADDING, CONST_CS);\n\tREGISTER_LONG_CONSTANT(\"OPENSSL_NO_PADDING\", RSA_NO_PADDING, CONST_CS);\n";
+
+        let patched = guard_symbol(src, "RSA_SSLV23_PADDING").expect("Can't guard symbol");
+        assert_eq!(
+            patched,
+            "#ifdef RSA_SSLV23_PADDING\n\tREGISTER_LONG_CONSTANT(\"OPENSSL_SSLV23_PADDING\", RSA_SSLV23_PADDING, CONST_CS);\n#endif\n\tREGISTER_LONG_CONSTANT(\"OPENSSL_NO_PADDING\", RSA_NO_PADDING, CONST_CS);\n"
+        );
+
+        // Already guarded trees are left alone
+        assert!(guard_symbol(&patched, "RSA_SSLV23_PADDING").is_none());
+        assert!(guard_symbol(src, "RSA_PKCS1_OAEP_PADDING").is_none());
+    }
+}
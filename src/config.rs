@@ -1,26 +1,227 @@
-use anyhow::{Context, Result};
-use std::path::PathBuf;
+use crate::{auth::MirrorAuth, downloads::Extension, migrate};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+use tempfile::TempDir;
 
 pub struct Config;
 
+/// User settings loaded from `~/.phpdownloader/config.json`.  Every key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Settings {
+    /// Layout version of this file, see `migrate::CONFIG_VERSION`.
+    pub config_version: u64,
+
+    /// Let alpha, beta and RC releases win version resolution.
+    pub pre: bool,
+
+    /// Tarball format to use when `--extension` isn't given.
+    pub extension: Option<Extension>,
+
+    /// Subcommand to run for a bare version, e.g. `php-downloader 8.3.2`.
+    pub default_action: Option<String>,
+
+    /// Delete cached tarballs once they've been extracted successfully.
+    pub rm_tarball: bool,
+
+    /// Template for extracted directory names, e.g. `php-{major}.{minor}`.
+    pub dir_name: Option<String>,
+
+    /// Glob patterns for directories to skip when scanning for build roots.
+    pub ignore: Vec<String>,
+
+    /// Where `get` extracts build trees, defaults to `~/.phpdownloader/workspace`.
+    pub workspace: Option<PathBuf>,
+
+    /// Install prefix exposed to hooks as `{prefix}`.
+    pub prefix: Option<String>,
+
+    /// Parallel jobs exposed to hooks as `{jobs}`, defaults to the number of CPUs.
+    pub jobs: Option<usize>,
+
+    /// Shell commands to run for a hook when no hook script is found.  Placeholders are quoted as
+    /// they're expanded, so they shouldn't be quoted again.
+    pub hooks: HashMap<String, String>,
+
+    /// Extra arguments appended to a hook, e.g. configure flags.
+    pub hook_args: HashMap<String, Vec<String>>,
+
+    /// Don't add the configure arguments and environment known to be needed on this host.
+    pub no_platform_defaults: bool,
+
+    /// Fail `list` and `latest` when php.net can't be reached, rather than answering from the
+    /// cache and registry.
+    pub no_offline_fallback: bool,
+
+    /// Mirrors tried in order when php.net (or `--base-url`) fails, after any `--mirror`.
+    pub mirrors: Vec<String>,
+
+    /// Where temporary files and extraction staging go, like `--tmpdir`.
+    pub tmpdir: Option<PathBuf>,
+
+    /// User agent sent with every request, like `--user-agent`.
+    pub user_agent: Option<String>,
+
+    /// Seconds a request may wait on the network, like `--timeout`.
+    pub timeout: Option<u64>,
+
+    /// Seconds to wait for a connection to be established, like `--connect-timeout`.
+    pub connect_timeout: Option<u64>,
+
+    /// Download tarballs in this many concurrent ranged requests, like `--segments`.
+    pub segments: Option<u8>,
+
+    /// Proxy for all requests, like `--proxy`.  Credentials for it go in `auth` under its host.
+    pub proxy: Option<String>,
+
+    /// PEM file of extra CA certificates to trust, like `--ca-bundle`.
+    pub ca_bundle: Option<PathBuf>,
+
+    /// Trust only `ca-bundle`, not the system's certificates, like `--no-system-roots`.
+    pub no_system_roots: bool,
+
+    /// Don't verify TLS certificates at all, like `--danger-skip-tls-verify`.
+    pub danger_skip_tls_verify: bool,
+
+    /// Gateway used for `ipfs://` mirrors, defaults to `https://ipfs.io`.
+    pub ipfs_gateway: Option<String>,
+
+    /// Credentials for authenticated mirrors, keyed by host.
+    pub auth: HashMap<String, MirrorAuth>,
+
+    /// Refuse tarballs without a good release manager signature, like `--verify-signatures`.
+    pub verify_signatures: bool,
+
+    /// Refuse to download or extract series that no longer get security fixes unless
+    /// `--allow-eol` is given, rather than only warning.
+    pub deny_eol: bool,
+
+    /// Locale file in `~/.phpdownloader/locale` to take messages from, e.g. `de` for `de.json`.
+    pub locale: Option<String>,
+
+    /// Individual messages to replace, keyed as in `php-downloader messages`.
+    pub messages: HashMap<String, String>,
+
+    /// Default flags per subcommand, e.g. `{"extract": {"no-hooks": true}}`.
+    pub defaults: HashMap<String, HashMap<String, serde_json::Value>>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+static REGISTRY_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+static CACHE_TTL: OnceLock<Duration> = OnceLock::new();
+static JSON_PROGRESS: OnceLock<bool> = OnceLock::new();
+static BASE_URL: OnceLock<String> = OnceLock::new();
+static MIRRORS: OnceLock<Vec<String>> = OnceLock::new();
+static PROXY: OnceLock<String> = OnceLock::new();
+static CA_BUNDLE: OnceLock<PathBuf> = OnceLock::new();
+static NO_SYSTEM_ROOTS: OnceLock<bool> = OnceLock::new();
+static SKIP_TLS_VERIFY: OnceLock<bool> = OnceLock::new();
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+static VERBOSITY: OnceLock<u8> = OnceLock::new();
+static OFFLINE: OnceLock<bool> = OnceLock::new();
+static TMPDIR: OnceLock<PathBuf> = OnceLock::new();
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static CONNECT_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static STALL_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static SEGMENTS: OnceLock<u8> = OnceLock::new();
+static ASSUME_YES: OnceLock<bool> = OnceLock::new();
+static DENY_EOL: OnceLock<bool> = OnceLock::new();
+static NO_VERIFY: OnceLock<bool> = OnceLock::new();
+static VERIFY_SIGNATURES: OnceLock<bool> = OnceLock::new();
+static INSECURE: OnceLock<bool> = OnceLock::new();
+
 impl Config {
     pub const APP_CFG_PATH: &'static str = ".phpdownloader";
     pub const APP_REGISTRY_PATH: &'static str = "tarballs";
     pub const APP_HOOKS_PATH: &'static str = "hooks";
+    pub const APP_PATCHES_PATH: &'static str = "patches";
+    pub const APP_WORKSPACE_PATH: &'static str = "workspace";
+    pub const APP_CACHE_PATH: &'static str = "cache";
+    pub const APP_STAGING_PATH: &'static str = "staging";
+    pub const APP_LOCALE_PATH: &'static str = "locale";
     pub const APP_MANIFEST_FILE: &'static str = ".phpdownloader-manifest";
+    pub const APP_SOURCE_FILE: &'static str = ".phpdownloader-source";
+    pub const APP_PIN_FILE: &'static str = ".phpdownloader-pinned";
+    pub const APP_BACKUPS_FILE: &'static str = ".phpdownloader-backups";
+    pub const APP_IGNORE_FILE: &'static str = ".phpdownloaderignore";
+    pub const APP_PARTIAL_MARKER: &'static str = ".phpdownloader-partial";
+    pub const APP_SETTINGS_FILE: &'static str = "config.json";
+    pub const APP_QUEUE_FILE: &'static str = "queue.json";
+    pub const APP_AUDIT_FILE: &'static str = "audit.log";
+    pub const APP_KEYRING_FILE: &'static str = "php-keyring.gpg";
+    pub const DEFAULT_ACTION: &'static str = "download";
+    // Duration::from_mins would raise our MSRV just for this
+    #[allow(clippy::duration_suboptimal_units)]
+    pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+    pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const MAX_SEGMENTS: u8 = 16;
+    pub const DEFAULT_USER_AGENT: &'static str =
+        concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-    fn get_base_app_path() -> Result<PathBuf> {
-        let v = if let Ok(path) = std::env::var("PHPDOWNLOADER_ROOT") {
-            path
-        } else if cfg!(windows) {
-            std::env::var("USERPROFILE")?
-        } else {
-            std::env::var("HOME")?
+    fn env_path(name: &str) -> Option<PathBuf> {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    }
+
+    // Containers and service accounts often run without HOME, so fall back to the passwd entry
+    #[cfg(unix)]
+    pub fn home_dir() -> Option<PathBuf> {
+        use std::{ffi::CStr, os::unix::ffi::OsStrExt};
+
+        if let Some(home) = Self::env_path("HOME") {
+            return Some(home);
+        }
+
+        // SAFETY: passwd is plain old data, all zeroes is a valid (empty) value
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+        // SAFETY: every pointer is valid for the call and `buf.len()` is the real buffer size
+        let rc = unsafe {
+            libc::getpwuid_r(
+                libc::getuid(),
+                &raw mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &raw mut result,
+            )
         };
 
-        Ok(PathBuf::from(v))
+        if rc != 0 || result.is_null() || pwd.pw_dir.is_null() {
+            return None;
+        }
+
+        // SAFETY: on success pw_dir points to a NUL terminated string inside `buf`
+        let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+        let dir = std::ffi::OsStr::from_bytes(dir.to_bytes());
+
+        (!dir.is_empty()).then(|| PathBuf::from(dir))
+    }
+
+    #[cfg(not(unix))]
+    pub fn home_dir() -> Option<PathBuf> {
+        Self::env_path("USERPROFILE")
+    }
+
+    fn get_base_app_path() -> Result<PathBuf> {
+        Self::env_path("PHPDOWNLOADER_ROOT")
+            .or_else(Self::home_dir)
+            .context("Unable to determine a home directory, set HOME or PHPDOWNLOADER_ROOT")
     }
 
+    #[allow(clippy::unnecessary_debug_formatting)]
     fn app_path<S: AsRef<str>>(child: Option<S>) -> Result<PathBuf> {
         let mut dir = Self::get_base_app_path()?;
         dir.push(Self::APP_CFG_PATH);
@@ -34,11 +235,369 @@ impl Config {
         Ok(dir)
     }
 
+    pub fn settings_path() -> Result<PathBuf> {
+        let mut path = Self::get_base_app_path()?;
+        path.push(Self::APP_CFG_PATH);
+        path.push(Self::APP_SETTINGS_FILE);
+        Ok(path)
+    }
+
+    /// The raw settings file, `None` if there isn't one.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn read_settings_json() -> Result<Option<serde_json::Value>> {
+        let path = Self::settings_path()?;
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let data = fs::read_to_string(&path).context(format!("Unable to read {path:?}"))?;
+        let json = serde_json::from_str(&data).context(format!("Unable to parse {path:?}"))?;
+
+        Ok(Some(json))
+    }
+
+    // Older layouts are migrated in memory, `migrate` writes the result back
+    fn load_settings() -> Result<Settings> {
+        let Some(mut json) = Self::read_settings_json()? else {
+            return Ok(Settings::default());
+        };
+
+        // Only nag when something would actually change, not just the version stamp
+        if migrate::migrate(&mut json).is_some_and(|changes| !changes.is_empty()) {
+            eprintln!("Warning:  config.json uses an older layout, run `migrate` to update it");
+        }
+
+        serde_json::from_value(json).context("Unable to parse settings")
+    }
+
+    pub fn settings() -> &'static Settings {
+        SETTINGS.get_or_init(|| {
+            Self::load_settings().unwrap_or_else(|e| {
+                eprintln!("Warning:  {e:#}, using default settings");
+                Settings::default()
+            })
+        })
+    }
+
+    /// `~/.phpdownloader`, everything else lives under it by default.
+    pub fn app_root() -> Result<PathBuf> {
+        Self::app_path(None::<&str>)
+    }
+
+    pub fn queue_path() -> Result<PathBuf> {
+        let mut path = Self::app_path(None::<&str>)?;
+        path.push(Self::APP_QUEUE_FILE);
+        Ok(path)
+    }
+
+    pub fn keyring_path() -> Result<PathBuf> {
+        let mut path = Self::app_path(None::<&str>)?;
+        path.push(Self::APP_KEYRING_FILE);
+        Ok(path)
+    }
+
+    pub fn audit_path() -> Result<PathBuf> {
+        let mut path = Self::app_path(None::<&str>)?;
+        path.push(Self::APP_AUDIT_FILE);
+        Ok(path)
+    }
+
     pub fn registry_path() -> Result<PathBuf> {
+        if let Some(path) = REGISTRY_OVERRIDE.get() {
+            return Ok(path.clone());
+        }
+
         Self::app_path(Some(Self::APP_REGISTRY_PATH))
     }
 
+    /// Probe whether we can actually create files in `dir`.
+    pub fn is_writable(dir: &Path) -> bool {
+        tempfile::Builder::new()
+            .prefix(".phpdownloader-probe")
+            .tempfile_in(dir)
+            .is_ok()
+    }
+
+    pub fn registry_writable() -> bool {
+        Self::registry_path().is_ok_and(|path| Self::is_writable(&path))
+    }
+
+    /// Redirect the registry to a temporary directory for the rest of this process.  The
+    /// directory is removed when the returned `TempDir` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if we can't create the directory or the registry was already redirected.
+    pub fn use_ephemeral_registry() -> Result<TempDir> {
+        let dir = tempfile::Builder::new()
+            .prefix("phpdownloader-")
+            .tempdir_in(Self::tmp_dir())?;
+
+        REGISTRY_OVERRIDE
+            .set(dir.path().to_path_buf())
+            .map_err(|_| anyhow!("Registry path already set"))?;
+
+        Ok(dir)
+    }
+
+    /// Where interrupted extractions wait to be resumed.  Only we may read or write it, so nobody
+    /// else can plant a partial extraction for us to pick up.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the directory can't be created or belongs to someone else.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn staging_path() -> Result<PathBuf> {
+        let dir = Self::app_path(Some(Self::APP_STAGING_PATH))?;
+
+        // SAFETY: getuid has no preconditions and can't fail
+        let uid = unsafe { libc::getuid() };
+        let meta = fs::symlink_metadata(&dir)?;
+        if !meta.is_dir() || meta.uid() != uid {
+            bail!("Refusing to use staging directory {dir:?}, it isn't a directory we own");
+        }
+
+        if meta.mode() & 0o077 != 0 {
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))
+                .context(format!("Unable to restrict permissions on {dir:?}"))?;
+        }
+
+        Ok(dir)
+    }
+
     pub fn hooks_path() -> Result<PathBuf> {
         Self::app_path(Some(Self::APP_HOOKS_PATH))
     }
+
+    pub fn patches_path() -> Result<PathBuf> {
+        Self::app_path(Some(Self::APP_PATCHES_PATH))
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn workspace_path() -> Result<PathBuf> {
+        match &Self::settings().workspace {
+            Some(dir) => {
+                fs::create_dir_all(dir).context(format!("Unable to create directory {dir:?}"))?;
+                Ok(dir.clone())
+            }
+            None => Self::app_path(Some(Self::APP_WORKSPACE_PATH)),
+        }
+    }
+
+    pub fn locale_path() -> Result<PathBuf> {
+        Self::app_path(Some(Self::APP_LOCALE_PATH))
+    }
+
+    pub fn cache_path() -> Result<PathBuf> {
+        Self::app_path(Some(Self::APP_CACHE_PATH))
+    }
+
+    pub fn set_cache_ttl(ttl: Duration) {
+        let _ = CACHE_TTL.set(ttl);
+    }
+
+    /// How long remote version listings stay fresh.  A zero TTL disables caching.
+    pub fn cache_ttl() -> Duration {
+        CACHE_TTL.get().copied().unwrap_or(Self::DEFAULT_CACHE_TTL)
+    }
+
+    pub fn set_base_url(url: &str) {
+        let _ = BASE_URL.set(url.trim_end_matches('/').to_string());
+    }
+
+    /// Replaces the php.net hosts when testing against a local server or a mirror.
+    pub fn base_url() -> Option<&'static str> {
+        BASE_URL.get().map(String::as_str)
+    }
+
+    pub fn set_mirrors(mirrors: Vec<String>) {
+        let _ = MIRRORS.set(
+            mirrors
+                .into_iter()
+                .map(|m| m.trim_end_matches('/').to_string())
+                .collect(),
+        );
+    }
+
+    /// Hosts with php.net's layout to fall back to, in order, when the primary one fails.
+    pub fn mirrors() -> &'static [String] {
+        MIRRORS.get().map_or(&[], Vec::as_slice)
+    }
+
+    pub fn set_proxy(url: &str) {
+        let _ = PROXY.set(url.to_string());
+    }
+
+    /// An explicit proxy from `--proxy` or the settings, otherwise reqwest uses the environment.
+    pub fn proxy() -> Option<&'static str> {
+        PROXY.get().map(String::as_str)
+    }
+
+    pub fn set_ca_bundle(path: &Path) {
+        let _ = CA_BUNDLE.set(path.to_path_buf());
+    }
+
+    /// Extra CA certificates from `--ca-bundle` or the settings, e.g. for TLS interception.
+    pub fn ca_bundle() -> Option<&'static Path> {
+        CA_BUNDLE.get().map(PathBuf::as_path)
+    }
+
+    pub fn set_no_system_roots(enabled: bool) {
+        let _ = NO_SYSTEM_ROOTS.set(enabled);
+    }
+
+    /// Whether to trust only the CA bundle.
+    pub fn no_system_roots() -> bool {
+        NO_SYSTEM_ROOTS.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_skip_tls_verify(enabled: bool) {
+        let _ = SKIP_TLS_VERIFY.set(enabled);
+    }
+
+    /// Whether to accept any TLS certificate.
+    pub fn skip_tls_verify() -> bool {
+        SKIP_TLS_VERIFY.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_stall_timeout(timeout: Duration) {
+        let _ = STALL_TIMEOUT.set(timeout);
+    }
+
+    /// How long a download may go without receiving any data before we call it stalled.  A zero
+    /// timeout disables the check.
+    pub fn stall_timeout() -> Option<Duration> {
+        Some(
+            STALL_TIMEOUT
+                .get()
+                .copied()
+                .unwrap_or(Self::DEFAULT_STALL_TIMEOUT),
+        )
+        .filter(|t| !t.is_zero())
+    }
+
+    pub fn set_timeout(timeout: Duration) {
+        let _ = TIMEOUT.set(timeout);
+    }
+
+    /// How long a single request may wait on the network, see `downloads::timed`.
+    pub fn timeout() -> Option<Duration> {
+        TIMEOUT.get().copied()
+    }
+
+    pub fn set_connect_timeout(timeout: Duration) {
+        let _ = CONNECT_TIMEOUT.set(timeout);
+    }
+
+    /// How long to wait for a connection.  A dead host would otherwise take the OS's TCP timeout,
+    /// which can be minutes, so there is always a limit.
+    pub fn connect_timeout() -> Duration {
+        CONNECT_TIMEOUT
+            .get()
+            .copied()
+            .or_else(Self::timeout)
+            .unwrap_or(Self::DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    pub fn set_segments(segments: u8) {
+        let _ = SEGMENTS.set(segments.clamp(1, Self::MAX_SEGMENTS));
+    }
+
+    /// How many ranged requests to split a download into, 1 meaning a single stream.
+    pub fn segments() -> u8 {
+        SEGMENTS.get().copied().unwrap_or(1)
+    }
+
+    pub fn set_json_progress(enabled: bool) {
+        let _ = JSON_PROGRESS.set(enabled);
+    }
+
+    /// Whether progress should be reported as JSON lines on stderr instead of progress bars.
+    pub fn json_progress() -> bool {
+        JSON_PROGRESS.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_no_verify(enabled: bool) {
+        let _ = NO_VERIFY.set(enabled);
+    }
+
+    /// Whether downloads should be checked against the checksums php.net publishes.
+    pub fn verify_checksums() -> bool {
+        !NO_VERIFY.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_verify_signatures(enabled: bool) {
+        let _ = VERIFY_SIGNATURES.set(enabled);
+    }
+
+    /// Whether tarballs must carry a good release manager signature, see `signature::check`.
+    pub fn verify_signatures() -> bool {
+        VERIFY_SIGNATURES.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_user_agent(agent: &str) {
+        let _ = USER_AGENT.set(agent.to_string());
+    }
+
+    /// What we call ourselves in requests, for mirrors that filter on it.
+    pub fn user_agent() -> &'static str {
+        USER_AGENT
+            .get()
+            .map_or(Self::DEFAULT_USER_AGENT, String::as_str)
+    }
+
+    pub fn set_offline(enabled: bool) {
+        let _ = OFFLINE.set(enabled);
+    }
+
+    /// Whether `--offline` was given, so nothing may touch the network.
+    pub fn offline() -> bool {
+        OFFLINE.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_verbosity(level: u8) {
+        let _ = VERBOSITY.set(level);
+    }
+
+    /// How many times `-v` was given, `-vv` logs every request.
+    pub fn verbosity() -> u8 {
+        VERBOSITY.get().copied().unwrap_or(0)
+    }
+
+    pub fn set_tmpdir(path: &Path) {
+        let _ = TMPDIR.set(path.to_path_buf());
+    }
+
+    /// Where temporary files go, `--tmpdir` or the `tmpdir` setting, falling back to `TMPDIR`.
+    pub fn tmp_dir() -> PathBuf {
+        TMPDIR.get().cloned().unwrap_or_else(std::env::temp_dir)
+    }
+
+    pub fn set_insecure(enabled: bool) {
+        let _ = INSECURE.set(enabled);
+    }
+
+    /// Whether missing or bad signatures should only be warned about.
+    pub fn insecure() -> bool {
+        INSECURE.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_assume_yes(enabled: bool) {
+        let _ = ASSUME_YES.set(enabled);
+    }
+
+    /// Whether confirmation prompts should be answered with yes without asking.
+    pub fn assume_yes() -> bool {
+        ASSUME_YES.get().copied().unwrap_or(false)
+    }
+
+    pub fn set_deny_eol(enabled: bool) {
+        let _ = DENY_EOL.set(enabled);
+    }
+
+    /// Whether end of life series should be refused rather than warned about.
+    pub fn deny_eol() -> bool {
+        DENY_EOL.get().copied().unwrap_or(false)
+    }
 }
@@ -0,0 +1,165 @@
+use crate::{
+    audit::{self, Confirmation},
+    config::Config,
+    messages::{msg, Msg},
+    prompt,
+};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+/// What to do when something we're about to write is already there, `--on-conflict`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Stop without touching the existing path
+    Abort,
+    /// Replace the existing path
+    Overwrite,
+    /// Write next to it with the first free `-N` suffix
+    Suffix,
+    /// Keep the existing path and use it as if we'd just written it
+    Reuse,
+}
+
+/// Where to write once a conflict has been resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Write to this path, which is free or may be replaced.
+    Write(PathBuf),
+    /// Use what's already at this path.
+    Reuse(PathBuf),
+}
+
+// `php-8.3.2.tar.bz2` becomes `php-8.3.2-1.tar.bz2` so the extension still means something
+fn with_suffix(path: &Path, n: usize) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    let (stem, ext) = name
+        .find(".tar.")
+        .map_or((&*name, ""), |pos| name.split_at(pos));
+    let name = format!("{stem}-{n}{ext}");
+
+    path.with_file_name(name)
+}
+
+/// The first `-N` variant of `path` that doesn't exist yet.
+fn next_free(path: &Path) -> PathBuf {
+    (1..=usize::MAX)
+        .map(|n| with_suffix(path, n))
+        .find(|p| p.symlink_metadata().is_err())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+fn ask(path: &Path, default: OnConflict) -> Result<OnConflict> {
+    let choices = [
+        ("abort", OnConflict::Abort),
+        ("overwrite", OnConflict::Overwrite),
+        ("suffix", OnConflict::Suffix),
+        ("reuse", OnConflict::Reuse),
+    ];
+
+    let prompt = msg!(Msg::ConflictPrompt, path = format!("{path:?}"));
+    Ok(prompt::choose(&prompt, &choices, default)?.unwrap_or(default))
+}
+
+/// Decide what to do about `path` already existing, following `policy` or else the caller's
+/// `default` without asking.
+///
+/// Existing directories are removed when overwriting since nothing can be renamed over them, files
+/// are left for the writer to replace atomically.
+///
+/// # Errors
+///
+/// Fails if we're told to abort or the existing directory can't be removed.
+pub fn resolve(path: &Path, policy: Option<OnConflict>, default: OnConflict) -> Result<Resolution> {
+    apply(path, policy.unwrap_or(default), Confirmation::Implicit)
+}
+
+/// Like `resolve`, but without a `policy` ask when there is a terminal to ask on, offering the
+/// caller's `default` for an empty answer or the end of input.  With `--yes` or no terminal the
+/// `default` is used as is.
+///
+/// # Errors
+///
+/// Fails if we're told to abort, stdin can't be read or the existing directory can't be removed.
+pub fn resolve_interactive(
+    path: &Path,
+    policy: Option<OnConflict>,
+    default: OnConflict,
+) -> Result<Resolution> {
+    if path.symlink_metadata().is_err() {
+        return Ok(Resolution::Write(path.to_path_buf()));
+    }
+
+    match policy {
+        Some(policy) => apply(path, policy, Confirmation::Implicit),
+        None if !Config::assume_yes() && std::io::stdin().is_terminal() => {
+            apply(path, ask(path, default)?, Confirmation::Interactive)
+        }
+        None => apply(path, default, Confirmation::Implicit),
+    }
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+fn apply(path: &Path, policy: OnConflict, confirmation: Confirmation) -> Result<Resolution> {
+    let Ok(meta) = path.symlink_metadata() else {
+        return Ok(Resolution::Write(path.to_path_buf()));
+    };
+
+    match policy {
+        OnConflict::Abort => bail!(msg!(Msg::PathExists, path = format!("{path:?}"))),
+        OnConflict::Overwrite => {
+            if meta.is_dir() {
+                eprintln!("{}", msg!(Msg::Removing, path = format!("{path:?}")));
+                fs::remove_dir_all(path).context(format!("Unable to remove {path:?}"))?;
+                audit::record("overwrite", path, confirmation);
+            }
+            Ok(Resolution::Write(path.to_path_buf()))
+        }
+        OnConflict::Suffix => Ok(Resolution::Write(next_free(path))),
+        OnConflict::Reuse => Ok(Resolution::Reuse(path.to_path_buf())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffixes_keep_extensions() {
+        assert_eq!(
+            with_suffix(Path::new("/tmp/php-8.3.2.tar.bz2"), 2),
+            Path::new("/tmp/php-8.3.2-2.tar.bz2")
+        );
+        assert_eq!(
+            with_suffix(Path::new("/tmp/php-8.3.2"), 1),
+            Path::new("/tmp/php-8.3.2-1")
+        );
+    }
+
+    #[test]
+    fn resolve_uses_default_without_asking() {
+        let dir = tempfile::tempdir().expect("Can't create directory");
+        let path = dir.path().join("php-8.3.2.tar.bz2");
+
+        assert_eq!(
+            resolve(&path, None, OnConflict::Abort).expect("Can't resolve"),
+            Resolution::Write(path.clone())
+        );
+
+        fs::write(&path, "").expect("Can't create tarball");
+        assert_eq!(
+            resolve(&path, None, OnConflict::Reuse).expect("Can't resolve"),
+            Resolution::Reuse(path.clone())
+        );
+        assert!(resolve(&path, None, OnConflict::Abort).is_err());
+        assert_eq!(
+            resolve(&path, Some(OnConflict::Suffix), OnConflict::Reuse).expect("Can't resolve"),
+            Resolution::Write(dir.path().join("php-8.3.2-1.tar.bz2"))
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use crate::{
+    downloads::{DownloadInfo, DownloadList, Extension, Version},
+    messages::{msg, Msg},
+};
+use anyhow::{anyhow, bail, Result};
+use std::{fmt, ops::Range, str::FromStr};
+
+// Major versions that are finished, with their last minor.  Series from 8 on come from the
+// active releases.
+const FINAL_MINORS: [(u8, u8); 2] = [(5, 6), (7, 4)];
+
+// Versions packed so that ranges of them are plain integer ranges, e.g. 8.2.* is
+// key(8, 2, 0)..key(8, 3, 0).  Bumping a part past its maximum carries into the next one.
+const fn key(major: u16, minor: u16, patch: u16) -> u64 {
+    (major as u64) << 32 | (minor as u64) << 16 | patch as u64
+}
+
+const ANY: Range<u64> = 0..key(u16::MAX, u16::MAX, u16::MAX) + 1;
+
+/// A version constraint like `^8.1`, `>=8.0,<8.3` or `8.2.*`.  Comma separated comparators must
+/// all hold, `||` separates alternatives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+    source: String,
+    // Each alternative is the range of versions its comparators allow
+    ranges: Vec<Range<u64>>,
+}
+
+/// A version named by where it sits among the series php.net supports, so scripts don't need
+/// updating every release cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionAlias {
+    Latest,
+    OldestSupported,
+}
+
+/// A version argument, either a version as before (`8.3`, `8.3.4`), a constraint or an alias.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionArg {
+    Version(Version),
+    Req(VersionReq),
+    Alias(VersionAlias),
+}
+
+// One comparator, e.g. `>=8.1` or `8.2.*`, as the range of versions it allows
+fn comparator(s: &str) -> Result<Range<u64>> {
+    let split = s.find(|c: char| c.is_ascii_digit() || c == '*' || c == 'x');
+    let (op, version) = s.split_at(split.ok_or_else(|| anyhow!("No version in {s:?}"))?);
+
+    let mut parts = vec![];
+    for part in version.split('.') {
+        match part {
+            "*" | "x" => break,
+            _ => parts.push(
+                part.parse::<u16>()
+                    .map_err(|_| anyhow!("Invalid version {version:?} in {s:?}"))?,
+            ),
+        }
+    }
+
+    let wildcard = version.split('.').any(|p| p == "*" || p == "x");
+    if parts.len() > 3 || (wildcard && !op.is_empty() && op != "=") {
+        bail!("Invalid comparator {s:?}");
+    }
+
+    let part = |n: usize| parts.get(n).copied().unwrap_or(0);
+    let start = key(part(0), part(1), part(2));
+
+    // Everything the version covers, 8.2 being all of 8.2.*
+    let end = match parts.len() {
+        0 => ANY.end,
+        1 => key(part(0), 0, 0) + key(1, 0, 0),
+        2 => start + key(0, 1, 0),
+        _ => start + 1,
+    };
+
+    Ok(match op.trim() {
+        "" | "=" => start..end,
+        ">=" => start..ANY.end,
+        ">" => end..ANY.end,
+        "<" => 0..start,
+        "<=" => 0..end,
+        "^" if !parts.is_empty() => start..key(part(0), 0, 0) + key(1, 0, 0),
+        "~" if parts.len() > 1 => start..key(part(0), part(1), 0) + key(0, 1, 0),
+        "~" if parts.len() == 1 => start..end,
+        _ => bail!("Invalid comparator {s:?}"),
+    })
+}
+
+impl FromStr for VersionReq {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut ranges = vec![];
+
+        for alternative in s.split("||") {
+            let mut range = ANY;
+            let mut empty = true;
+
+            for part in alternative
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|p| !p.is_empty())
+            {
+                let other = comparator(part)?;
+                range = range.start.max(other.start)..range.end.min(other.end);
+                empty = false;
+            }
+
+            if empty {
+                bail!("Empty version constraint in {s:?}");
+            }
+
+            ranges.push(range);
+        }
+
+        Ok(Self {
+            source: s.to_string(),
+            ranges,
+        })
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl VersionReq {
+    /// Whether `version` satisfies the constraint.  Pre-releases count as the release they lead
+    /// up to, whether they're wanted at all is up to the caller.
+    pub fn matches(&self, version: Version) -> bool {
+        let v = key(
+            version.major.into(),
+            version.minor.into(),
+            version.patch.unwrap_or(0),
+        );
+        self.ranges.iter().any(|r| r.contains(&v))
+    }
+
+    fn matches_series(&self, major: u8, minor: u8) -> bool {
+        let start = key(major.into(), minor.into(), 0);
+        let series = start..start + key(0, 1, 0);
+        self.ranges
+            .iter()
+            .any(|r| r.start < series.end && series.start < r.end)
+    }
+
+    /// Every release series that could hold a match, newest first.
+    async fn series(&self) -> Result<Vec<(u8, u8)>> {
+        let active = DownloadList::active_series().await?;
+
+        let mut series: Vec<_> = FINAL_MINORS
+            .into_iter()
+            .chain(
+                active
+                    .into_iter()
+                    .filter(|&(major, _)| FINAL_MINORS.iter().all(|&(m, _)| m != major)),
+            )
+            .flat_map(|(major, last)| (0..=last).map(move |minor| (major, minor)))
+            .filter(|&(major, minor)| self.matches_series(major, minor))
+            .collect();
+
+        series.sort_unstable_by(|a, b| b.cmp(a));
+        series.dedup();
+
+        Ok(series)
+    }
+
+    /// The newest release satisfying the constraint.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the releases can't be listed or none of them match.
+    pub async fn resolve(&self, extension: Extension, pre: bool) -> Result<Version> {
+        for (major, minor) in self.series().await? {
+            let newest = DownloadList::new(major, minor, extension)
+                .with_pre(pre)
+                .list()
+                .await?
+                .into_iter()
+                .map(|dl| dl.version)
+                .filter(|&v| self.matches(v))
+                .max();
+
+            if let Some(version) = newest {
+                return Ok(version);
+            }
+        }
+
+        bail!(msg!(Msg::NoMatchingRelease, constraint = self))
+    }
+
+    /// The newest release satisfying the constraint in each series it covers, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the releases can't be listed.
+    pub async fn latest(&self, extension: Extension, pre: bool) -> Result<Vec<DownloadInfo>> {
+        let mut latest = vec![];
+
+        for (major, minor) in self.series().await? {
+            latest.extend(
+                DownloadList::new(major, minor, extension)
+                    .with_pre(pre)
+                    .list()
+                    .await?
+                    .into_iter()
+                    .filter(|dl| self.matches(dl.version))
+                    .max_by_key(|dl| dl.version),
+            );
+        }
+
+        latest.sort_unstable_by_key(|dl| dl.version);
+
+        Ok(latest)
+    }
+
+    /// Every release satisfying the constraint, oldest first.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the releases can't be listed.
+    pub async fn list(&self, extension: Extension, qa: bool) -> Result<Vec<DownloadInfo>> {
+        let mut matching = vec![];
+
+        for (major, minor) in self.series().await? {
+            matching.extend(
+                DownloadList::new(major, minor, extension)
+                    .with_qa(qa)
+                    .list()
+                    .await?
+                    .into_iter()
+                    .filter(|dl| self.matches(dl.version)),
+            );
+        }
+
+        matching.sort_unstable_by_key(|dl| dl.version);
+
+        Ok(matching)
+    }
+}
+
+impl FromStr for VersionAlias {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "latest" => Ok(Self::Latest),
+            "oldest-supported" => Ok(Self::OldestSupported),
+            _ => bail!("Unknown version alias {s:?}"),
+        }
+    }
+}
+
+impl fmt::Display for VersionAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Latest => write!(f, "latest"),
+            Self::OldestSupported => write!(f, "oldest-supported"),
+        }
+    }
+}
+
+impl VersionAlias {
+    /// The newest release of the newest or oldest supported series.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the supported series can't be fetched or the series has no release.
+    pub async fn resolve(self, extension: Extension, pre: bool) -> Result<Version> {
+        let releases = DownloadList::active_releases().await?;
+
+        let release = match self {
+            Self::Latest => releases.last(),
+            Self::OldestSupported => releases.first(),
+        }
+        .ok_or_else(|| anyhow!("php.net lists no supported releases"))?;
+
+        let mut version = Version::from_major_minor(release.major, release.minor);
+        let downloads = DownloadList::new(release.major, release.minor, extension).with_pre(pre);
+        version.resolve_latest(&downloads).await?;
+
+        Ok(version)
+    }
+}
+
+impl FromStr for VersionArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(version) = s.parse() {
+            return Ok(Self::Version(version));
+        }
+
+        if let Ok(alias) = s.parse() {
+            return Ok(Self::Alias(alias));
+        }
+
+        s.parse()
+            .map(Self::Req)
+            .map_err(|e| anyhow!("Invalid version or constraint '{s}' ({e})"))
+    }
+}
+
+impl fmt::Display for VersionArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(version) => write!(f, "{version}"),
+            Self::Req(req) => write!(f, "{req}"),
+            Self::Alias(alias) => write!(f, "{alias}"),
+        }
+    }
+}
+
+impl VersionArg {
+    /// Whether `version` is what the argument names.  Aliases need resolving with `concrete`
+    /// first, on their own they match nothing.
+    pub fn matches(&self, version: Version) -> bool {
+        match self {
+            Self::Version(v) => v.matches(version),
+            Self::Req(req) => req.matches(version),
+            Self::Alias(_) => false,
+        }
+    }
+
+    /// The argument with any alias replaced by the version it stands for, for filtering.
+    ///
+    /// # Errors
+    ///
+    /// Fails if an alias can't be resolved.
+    pub async fn concrete(self, extension: Extension, pre: bool) -> Result<Self> {
+        match self {
+            Self::Alias(alias) => Ok(Self::Version(alias.resolve(extension, pre).await?)),
+            arg => Ok(arg),
+        }
+    }
+
+    pub fn optional_matches(arg: Option<&Self>, version: Version) -> bool {
+        arg.is_none_or(|arg| arg.matches(version))
+    }
+
+    /// The version to work with.  A plain version is returned as given, so `8.3` still means
+    /// the newest 8.3 wherever it did before, a constraint becomes its newest matching release.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a constraint can't be resolved.
+    pub async fn resolve(&self, extension: Extension, pre: bool) -> Result<Version> {
+        match self {
+            Self::Version(version) => Ok(*version),
+            Self::Req(req) => req.resolve(extension, pre).await,
+            Self::Alias(alias) => alias.resolve(extension, pre).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(s: &str) -> Version {
+        s.parse().expect("Can't parse version")
+    }
+
+    #[test]
+    fn matches_constraints() {
+        let caret: VersionReq = "^8.1".parse().expect("Can't parse caret constraint");
+        assert!(caret.matches(v("8.1.0")) && caret.matches(v("8.4.2")));
+        assert!(!caret.matches(v("8.0.30")) && !caret.matches(v("7.4.33")));
+
+        let range: VersionReq = ">=8.0,<8.3".parse().expect("Can't parse range");
+        assert!(range.matches(v("8.0.0")) && range.matches(v("8.2.27")));
+        assert!(!range.matches(v("8.3.0")));
+
+        let wildcard: VersionReq = "8.2.*".parse().expect("Can't parse wildcard");
+        assert!(wildcard.matches(v("8.2.0")) && !wildcard.matches(v("8.3.0")));
+
+        let either: VersionReq = "~7.4 || >8.2".parse().expect("Can't parse alternatives");
+        assert!(either.matches(v("7.4.33")) && either.matches(v("8.3.0")));
+        assert!(!either.matches(v("8.2.9")) && !either.matches(v("7.3.0")));
+
+        assert!(!range.matches_series(8, 3) && range.matches_series(8, 2));
+    }
+
+    #[test]
+    fn rejects_bad_constraints() {
+        assert!(">=8.*".parse::<VersionReq>().is_err());
+        assert!(">=".parse::<VersionReq>().is_err());
+        assert!("^8.1,".parse::<VersionReq>().is_ok());
+        assert!("||".parse::<VersionReq>().is_err());
+        assert!(matches!(
+            "8.3"
+                .parse::<VersionArg>()
+                .expect("Can't parse version argument"),
+            VersionArg::Version(_)
+        ));
+        assert_eq!(
+            "oldest-supported"
+                .parse::<VersionArg>()
+                .expect("Can't parse alias"),
+            VersionArg::Alias(VersionAlias::OldestSupported)
+        );
+    }
+}
@@ -1,17 +1,98 @@
-use anyhow::{anyhow, Result};
-use chrono::{DateTime, Utc};
-use futures::future::join_all;
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::{
+    auth, cache,
+    config::Config,
+    extract,
+    messages::{msg, Msg},
+    progress, signature, transport,
+    view::ToHumanSize,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::{future::join_all, stream, StreamExt};
+use indicatif::ProgressBar;
+use openssl::sha::Sha256;
 use regex::Regex;
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, Response};
 use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    fmt, fs, io::Write, os::unix::fs::PermissionsExt, path::Path, result::Result as StdResult,
+    fmt, fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{FileExt, PermissionsExt},
+    path::{Path, PathBuf},
+    result::Result as StdResult,
     str::FromStr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
 };
 use tempfile::NamedTempFile;
 
+/// How many times a stalled download is started over before we give up.
+const STALL_ATTEMPTS: usize = 3;
+
+// Patch levels probed at once when php.net's API can't list a series
+const PROBE_BATCH: u16 = 16;
+
+// Below this a segment spends more time on its request than on transferring data
+const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// A download that stopped receiving data, see `Config::stall_timeout`.
+#[derive(Debug)]
+pub struct Stalled(pub Duration);
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download stalled, no data for {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// A download that ended before the `Content-Length` the server announced.
 #[derive(Debug)]
+pub struct Truncated {
+    pub received: u64,
+    pub expected: u64,
+}
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Download truncated, received {} of {} bytes",
+            self.received, self.expected
+        )
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+// An interrupted transfer is worth another attempt, possibly from another mirror
+fn is_interrupted(e: &anyhow::Error) -> bool {
+    e.is::<Stalled>() || e.is::<Truncated>()
+}
+
+// hyper notices a connection closing before `Content-Length` itself, which is the same thing
+fn body_error(e: anyhow::Error, received: u64, expected: u64) -> anyhow::Error {
+    let cut_short = e
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(reqwest::Error::is_body);
+
+    if cut_short && expected > 0 {
+        Truncated { received, expected }.into()
+    } else {
+        e
+    }
+}
+
+fn check_length(received: u64, expected: u64) -> Result<()> {
+    if expected > 0 && received != expected {
+        return Err(Truncated { received, expected }.into());
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
 pub struct DownloadInfo {
     pub location: String,
     pub version: Version,
@@ -20,25 +101,89 @@ pub struct DownloadInfo {
     pub extension: Extension,
 }
 
+/// How much support php.net still gives a release series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Support {
+    /// The series gets bug fixes.
+    Active,
+    /// The series only gets security fixes.
+    Security,
+}
+
+/// A supported release series as reported by php.net's releases API.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveRelease {
+    pub major: u8,
+    pub minor: u8,
+    pub latest: Option<Version>,
+    pub date: Option<String>,
+    pub support: Support,
+}
+
+impl ActiveRelease {
+    fn from_json(branch: &str, info: &serde_json::Value) -> Option<Self> {
+        let (major, minor) = branch.split_once('.')?;
+
+        let security = info["tags"]
+            .as_array()
+            .is_some_and(|tags| tags.iter().any(|t| t == "security"));
+
+        Some(Self {
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+            latest: info["version"].as_str().and_then(|v| v.parse().ok()),
+            date: info["date"].as_str().map(String::from),
+            support: if security {
+                Support::Security
+            } else {
+                Support::Active
+            },
+        })
+    }
+}
+
+/// A release series that no longer gets security fixes.
+#[derive(Debug, Clone, Copy)]
+pub struct Eol {
+    pub major: u8,
+    pub minor: u8,
+    /// When security support ended, if php.net still lists the series
+    pub since: Option<NaiveDate>,
+}
+
+// A series php.net supports and when its security support ends, from `releases/states.php`
+#[derive(Debug, Clone, Copy)]
+struct SeriesState {
+    major: u8,
+    minor: u8,
+    security_end: Option<NaiveDate>,
+}
+
 #[derive(Debug)]
 pub struct DownloadList {
     client: Client,
     major: u8,
     minor: u8,
     extension: Extension,
+    pre: bool,
+    qa: bool,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
 pub enum Extension {
     GZ,
+    #[default]
     BZ,
     XZ,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
 pub enum VersionModifier {
-    Alpha,
-    Beta,
+    /// A php-src development snapshot, see `snapshot`.
+    Dev,
+    Alpha(u8),
+    Beta(u8),
     RC(u8),
 }
 
@@ -46,16 +191,10 @@ pub enum VersionModifier {
 pub struct Version {
     pub major: u8,
     pub minor: u8,
-    pub patch: Option<u8>,
+    pub patch: Option<u16>,
     pub rc: Option<VersionModifier>,
 }
 
-impl std::default::Default for Extension {
-    fn default() -> Self {
-        Self::BZ
-    }
-}
-
 impl FromStr for Extension {
     type Err = anyhow::Error;
 
@@ -74,7 +213,7 @@ impl fmt::Display for Extension {
         let ext = match self {
             Self::BZ => "bz2",
             Self::GZ => "gz",
-            Self::XZ => "xy",
+            Self::XZ => "xz",
         };
 
         write!(f, "{ext}")
@@ -112,37 +251,39 @@ impl FromStr for VersionModifier {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re = Regex::new(r"(?i)(alpha|beta|rc)(\d*)?").expect("Can't parse regex");
+        let re = Regex::new(r"(?i)(dev|alpha|beta|rc)(\d*)?").expect("Can't parse regex");
+
+        let caps = re
+            .captures(s)
+            .ok_or_else(|| anyhow!("Don't understand version modifier {s:?}"))?;
+        let num = || {
+            caps.get(2)
+                .and_then(|n| n.as_str().parse::<u8>().ok())
+                .ok_or_else(|| anyhow!("Failed to parse version modifier {s:?}"))
+        };
 
-        match re.captures(s) {
-            Some(caps) => match &*caps.get(1).unwrap().as_str().to_lowercase() {
-                "alpha" => Ok(Self::Alpha),
-                "beta" => Ok(Self::Beta),
-                "rc" => match caps.get(2) {
-                    Some(n) => {
-                        let num = n.as_str().parse::<u8>()?;
-                        Ok(Self::RC(num))
-                    }
-                    None => Err(anyhow!("Failed to parse version modifier {s:?}")),
-                },
-                _ => unreachable!(),
-            },
-            None => Err(anyhow!("Don't understand version modifier {s:?}")),
+        match &*caps.get(1).unwrap().as_str().to_lowercase() {
+            "dev" => Ok(Self::Dev),
+            "alpha" => Ok(Self::Alpha(num()?)),
+            "beta" => Ok(Self::Beta(num()?)),
+            "rc" => Ok(Self::RC(num()?)),
+            _ => unreachable!(),
         }
     }
 }
 
 impl VersionModifier {
-    fn from_patch(s: &str) -> Result<(Option<Self>, Option<u8>)> {
+    fn from_patch(s: &str) -> Result<(Option<Self>, Option<u16>)> {
         let re = Regex::new(r"^(\d+)(.*)$").expect("Can't parse regex");
 
         match re.captures(s) {
             Some(caps) => {
-                let patch_u8 = caps
+                let patch = caps
                     .get(1)
                     .ok_or_else(|| anyhow!("No match for the first capture group"))?
                     .as_str()
-                    .parse::<u8>()?;
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("Invalid patch version {s:?}"))?;
 
                 let modifier = caps
                     .get(2)
@@ -150,21 +291,24 @@ impl VersionModifier {
                     .as_str();
 
                 if modifier.is_empty() {
-                    Ok((None, Some(patch_u8)))
+                    Ok((None, Some(patch)))
                 } else {
                     let modifier = Self::from_str(modifier)?;
-                    Ok((Some(modifier), Some(patch_u8)))
+                    Ok((Some(modifier), Some(patch)))
                 }
             }
             None => Err(anyhow!(format!("Unable to parse patch {s:?}"))),
         }
     }
 
+    /// How far this modifier puts a version below its release.  Each stage gets a range of its
+    /// own so e.g. beta2 sorts after beta1 but before any RC.
     pub fn to_u32(&self) -> u32 {
         match self {
-            Self::Alpha => 10,
-            Self::Beta => 9,
-            Self::RC(n) => 9 - u32::from(*n),
+            Self::Dev => 0x400,
+            Self::Alpha(n) => 0x300 - u32::from(*n),
+            Self::Beta(n) => 0x200 - u32::from(*n),
+            Self::RC(n) => 0x100 - u32::from(*n),
         }
     }
 }
@@ -186,6 +330,15 @@ impl DownloadInfo {
         }
     }
 
+    /// The size for display, empty when php.net's releases API didn't tell us.
+    pub fn size_string(&self) -> String {
+        if self.size == 0 {
+            String::new()
+        } else {
+            self.size.to_human_size()
+        }
+    }
+
     pub fn date_string(&self) -> String {
         self.date
             .map_or_else(String::new, |d| d.format("%d %b %y").to_string())
@@ -218,12 +371,16 @@ impl DownloadInfo {
     pub fn from_file(file: &Path) -> Result<Self> {
         let ext = file.extension().unwrap_or_default().to_string_lossy();
 
+        let version = Self::clean_file_name(file).parse()?;
+        let extension = ext.parse()?;
+        let size = fs::metadata(file)?.len();
+
         Ok(Self::new(
-            Self::clean_file_name(file).parse()?,
+            version,
             &file.to_string_lossy(),
-            std::fs::metadata(file).map(|m| m.len()).unwrap_or(0),
+            size,
             None,
-            ext.parse()?,
+            extension,
         ))
     }
 
@@ -232,30 +389,197 @@ impl DownloadInfo {
     /// # Errors
     ///
     /// This will fail if we can't create the file or execute the download.
-    pub async fn download_to_file(&self, dst: &Path) -> Result<()> {
-        let mut tmp = NamedTempFile::new()?;
+    pub async fn download_to_file(&self, dst: &Path) -> Result<String> {
+        let mut locations = vec![self.location.clone()];
+        // php.net and its mirrors only have releases
+        if !self.version.is_snapshot() {
+            locations.extend(
+                self.version
+                    .get_urls(self.extension)
+                    .into_iter()
+                    .filter(|url| *url != self.location),
+            );
+        }
 
-        let mut perms = fs::metadata(tmp.path())?.permissions();
-        perms.set_mode(0o644);
-        fs::set_permissions(tmp.path(), perms)?;
+        let mut last = None;
 
-        self.download(tmp.as_file_mut()).await?;
+        for location in locations {
+            if is_unreachable(&location) {
+                continue;
+            }
+
+            let info = Self {
+                location,
+                ..self.clone()
+            };
+
+            match info.download_from_location(dst).await {
+                Ok(digest) => return Ok(digest),
+                Err(e) if should_fail_over(&e) => {
+                    mark_unreachable(&info.location, &e);
+                    progress::note(msg!(
+                        Msg::MirrorFailed,
+                        url = info.location,
+                        error = format!("{e:#}")
+                    ));
+                    last = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
 
-        tmp.persist(dst)?;
+        Err(last.unwrap_or_else(|| anyhow!("No reachable mirror for PHP {}", self.version)))
+    }
 
-        Ok(())
+    async fn download_from_location(&self, dst: &Path) -> Result<String> {
+        let mut attempt = 1;
+
+        // A stalled transfer is usually a dead connection, so start over on a fresh one
+        loop {
+            let mut tmp = NamedTempFile::new_in(Config::tmp_dir())?;
+
+            let mut perms = fs::metadata(tmp.path())?.permissions();
+            perms.set_mode(0o644);
+            fs::set_permissions(tmp.path(), perms)?;
+
+            match self.download(tmp.as_file_mut()).await {
+                Ok(digest) => {
+                    self.verify_official(&digest).await?;
+                    // Snapshots are neither signed nor listed with a checksum
+                    let signature = if self.version.is_snapshot() {
+                        None
+                    } else {
+                        signature::check(&self.location, tmp.path()).await?
+                    };
+
+                    extract::move_path(tmp.path(), dst)?;
+                    save_checksum(dst, &digest)?;
+                    match signature {
+                        Some(signature) => signature::save(dst, &signature)?,
+                        // Don't leave a signature for a tarball we just replaced
+                        None => {
+                            let _ = fs::remove_file(signature::signature_path(dst));
+                        }
+                    }
+                    return Ok(digest);
+                }
+                Err(e) if is_interrupted(&e) && attempt < STALL_ATTEMPTS => {
+                    progress::note(msg!(
+                        Msg::DownloadRetry,
+                        error = e,
+                        attempt = attempt + 1,
+                        attempts = STALL_ATTEMPTS
+                    ));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    /// Download data to a generic writer
+    /// The SHA-256 php.net publishes for this tarball, `None` for releases old enough to only have
+    /// MD5 sums.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the releases API can't be fetched or parsed.
+    pub async fn official_checksum(&self) -> Result<Option<String>> {
+        let (url, body) =
+            fetch_api(&format!("releases/index.php?json&version={}", self.version)).await?;
+        let json: serde_json::Value =
+            serde_json::from_str(&body).context(format!("Unexpected response from {url}"))?;
+
+        let file_name = self.version.get_file_name(self.extension);
+
+        Ok(json["source"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|s| s["filename"].as_str() == Some(&file_name))
+            .and_then(|s| s["sha256"].as_str())
+            .map(str::to_ascii_lowercase))
+    }
+
+    /// Compare the SHA-256 of what we downloaded with the one php.net publishes.  Only a mismatch
+    /// is fatal, if there's nothing to compare against we say so and carry on.
+    async fn verify_official(&self, digest: &str) -> Result<()> {
+        if !Config::verify_checksums() || self.version.is_snapshot() {
+            return Ok(());
+        }
+
+        match self.official_checksum().await {
+            Ok(Some(expected)) if expected == digest => Ok(()),
+            Ok(Some(expected)) => bail!(msg!(
+                Msg::ChecksumMismatch,
+                url = self.location,
+                expected = expected,
+                actual = digest
+            )),
+            Ok(None) => {
+                progress::note(msg!(Msg::ChecksumMissing, version = self.version));
+                Ok(())
+            }
+            Err(e) => {
+                progress::note(msg!(
+                    Msg::ChecksumUnavailable,
+                    version = self.version,
+                    error = format!("{e:#}")
+                ));
+                Ok(())
+            }
+        }
+    }
+
+    /// Download data to `file`, returning the SHA-256 of what was written.  A single stream is
+    /// hashed as chunks arrive, segments land out of order so those are hashed by reading the file
+    /// back once they're all in.
     ///
     /// # Errors
     ///
     /// This can fail if the download fails.
-    pub async fn download<W>(&self, writer: &mut W) -> Result<()>
+    pub async fn download(&self, file: &mut fs::File) -> Result<String> {
+        if let Some(segments) = self.segment_ranges().await {
+            if self.download_segments(file, &segments).await? {
+                file.seek(SeekFrom::Start(0))?;
+                return sha256_reader(file);
+            }
+
+            // Start over, dropping whatever the other segments managed to write
+            file.set_len(0)?;
+        }
+
+        let mut hashing = HashingWriter {
+            inner: file,
+            hasher: Sha256::new(),
+        };
+        self.download_chunks(&mut hashing).await?;
+
+        Ok(to_hex(&hashing.hasher.finish()))
+    }
+
+    async fn download_chunks<W>(&self, writer: &mut W) -> Result<()>
     where
         W: Write + Send,
     {
-        let mut response = reqwest::get(&self.location).await?;
+        let req = auth::authorize(http_client().get(&self.location), &self.location);
+        let mut response = send(req).await?.error_for_status()?;
+
+        if is_html(&response) {
+            bail!(
+                "Received HTML instead of a tarball from {} (misconfigured mirror or captive portal?)",
+                self.location
+            );
+        }
+
+        // Look at the first chunk before writing anything so we never keep a bogus file
+        let first = next_chunk(&mut response).await?.unwrap_or_default();
+        if !self.extension.matches_magic(&first) {
+            if first.trim_ascii_start().starts_with(b"<") {
+                bail!("Received HTML instead of a tarball from {}", self.location);
+            }
+            bail!("{} is not a {} tarball", self.location, self.extension);
+        }
+        writer.write_all(&first)?;
 
         let total_size = response
             .headers()
@@ -264,24 +588,260 @@ impl DownloadInfo {
             .and_then(|val| val.parse::<u64>().ok())
             .unwrap_or(0);
 
-        let tmpl = "{msg} {spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})";
-
-        let pb = ProgressBar::new(total_size);
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(tmpl)?
-                .progress_chars("#>-"),
-        );
-        pb.set_message(self.version.to_string());
+        if Config::json_progress() {
+            return self
+                .download_json(&mut response, first.len() as u64, total_size, writer)
+                .await;
+        }
 
-        while let Some(chunk) = response.chunk().await? {
+        let pb = self.progress_bar(total_size)?;
+        pb.inc(first.len() as u64);
+        let mut received = first.len() as u64;
+
+        loop {
+            let chunk = match next_chunk(&mut response).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    pb.abandon_with_message("stalled");
+                    return Err(body_error(e, received, total_size));
+                }
+            };
             pb.inc(chunk.len() as u64);
+            received += chunk.len() as u64;
             writer.write_all(&chunk)?;
         }
 
+        if let Err(e) = check_length(received, total_size) {
+            pb.abandon_with_message("truncated");
+            return Err(e);
+        }
+
         pb.finish_with_message("download completed");
         Ok(())
     }
+
+    /// Like `download` but reports progress as JSON lines on stderr rather than drawing a bar.
+    async fn download_json<W>(
+        &self,
+        response: &mut reqwest::Response,
+        mut bytes: u64,
+        total: u64,
+        writer: &mut W,
+    ) -> Result<()>
+    where
+        W: Write + Send,
+    {
+        const INTERVAL: Duration = Duration::from_millis(500);
+
+        let report = |bytes: u64, done: bool| self.report_json(bytes, total, done);
+
+        let mut last = Instant::now();
+
+        report(bytes, false);
+
+        while let Some(chunk) = next_chunk(response)
+            .await
+            .map_err(|e| body_error(e, bytes, total))?
+        {
+            bytes += chunk.len() as u64;
+            writer.write_all(&chunk)?;
+
+            if last.elapsed() >= INTERVAL {
+                report(bytes, false);
+                last = Instant::now();
+            }
+        }
+
+        check_length(bytes, total)?;
+        report(bytes, true);
+        Ok(())
+    }
+
+    fn report_json(&self, bytes: u64, total: u64, done: bool) {
+        #[allow(clippy::cast_precision_loss)]
+        let percent = (total > 0).then(|| (bytes as f64 * 100.0 / total as f64).min(100.0));
+
+        let record = serde_json::json!({
+            "event": "download",
+            "version": self.version,
+            "bytes": bytes,
+            "total": (total > 0).then_some(total),
+            "percent": percent.map(|p| (p * 10.0).round() / 10.0),
+            "done": done,
+        });
+
+        eprintln!("{record}");
+    }
+
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn progress_bar(&self, total: u64) -> Result<ProgressBar> {
+        let tmpl = "{msg} {spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})";
+
+        let pb = progress::bar(total, tmpl)?;
+        pb.set_message(self.version.to_string());
+
+        Ok(pb)
+    }
+
+    /// The byte ranges to fetch concurrently when `--segments` is in effect, or `None` to use a
+    /// single stream.  That's also what we do whenever the server doesn't advertise range
+    /// support or the tarball is too small for splitting it to be worth it.
+    async fn segment_ranges(&self) -> Option<Segments> {
+        let segments = u64::from(Config::segments());
+        if segments < 2 {
+            return None;
+        }
+
+        let req = auth::authorize(http_client().head(&self.location), &self.location);
+        let res = send(req).await.ok()?.error_for_status().ok()?;
+
+        let ranges = res
+            .headers()
+            .get(reqwest::header::ACCEPT_RANGES)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.split(',').any(|unit| unit.trim() == "bytes"));
+
+        let total = res
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())?;
+
+        let segments = segments.min(total / MIN_SEGMENT_SIZE);
+        if !ranges || is_html(&res) || segments < 2 {
+            return None;
+        }
+
+        // If-Range takes a strong ETag or a date, anything else can't pin the segments
+        let header = |name| res.headers().get(name).and_then(|v| v.to_str().ok());
+        let validator = header(reqwest::header::ETAG)
+            .filter(|etag| !etag.starts_with("W/"))
+            .or_else(|| header(reqwest::header::LAST_MODIFIED))
+            .map(str::to_string);
+
+        let size = total.div_ceil(segments);
+
+        Some(Segments {
+            ranges: (0..total)
+                .step_by(usize::try_from(size).ok()?)
+                .map(|start| (start, (start + size).min(total) - 1))
+                .collect(),
+            validator,
+        })
+    }
+
+    /// Fetch `segments` concurrently, writing each straight to its place in `file` so no more than
+    /// a chunk per segment is ever held in memory.  Returns `false` if the server answers the
+    /// first range with the whole file, so the caller can fall back to a single stream.
+    async fn download_segments(&self, file: &fs::File, segments: &Segments) -> Result<bool> {
+        let ranges = &segments.ranges;
+        let total = ranges.last().map_or(0, |&(_, end)| end + 1);
+
+        file.set_len(total)?;
+
+        let pb = if Config::json_progress() {
+            self.report_json(0, total, false);
+            None
+        } else {
+            Some(self.progress_bar(total)?)
+        };
+
+        let validator = segments.validator.as_deref();
+        let mut segments = stream::iter(ranges)
+            .map(|&(start, end)| self.download_segment(file, start, end, validator, pb.as_ref()))
+            .buffered(ranges.len());
+
+        let mut written = 0;
+        let mut first = true;
+
+        while let Some(segment) = segments.next().await {
+            match segment {
+                Ok(Some(len)) => written += len,
+                Ok(None) if first => {
+                    if let Some(pb) = &pb {
+                        pb.finish_and_clear();
+                    }
+                    progress::note(msg!(Msg::SegmentsIgnored, url = &self.location));
+                    return Ok(false);
+                }
+                Ok(None) => {
+                    if let Some(pb) = &pb {
+                        pb.abandon_with_message("failed");
+                    }
+                    bail!("{} changed during the download, try again", self.location);
+                }
+                Err(e) => {
+                    if let Some(pb) = &pb {
+                        pb.abandon_with_message("failed");
+                    }
+                    return Err(e);
+                }
+            }
+
+            first = false;
+
+            if pb.is_none() {
+                self.report_json(written, total, written == total);
+            }
+        }
+
+        if let Some(pb) = &pb {
+            pb.finish_with_message("download completed");
+        }
+
+        Ok(true)
+    }
+
+    // Write one range of the tarball to its offset in `file`, returning its length.  `None` if
+    // the server sent something else instead, either because it ignores ranges or because the
+    // file no longer matches `validator`.
+    async fn download_segment(
+        &self,
+        file: &fs::File,
+        start: u64,
+        end: u64,
+        validator: Option<&str>,
+        pb: Option<&ProgressBar>,
+    ) -> Result<Option<u64>> {
+        let mut req = auth::authorize(http_client().get(&self.location), &self.location)
+            .header(reqwest::header::RANGE, format!("bytes={start}-{end}"));
+        if let Some(validator) = validator {
+            req = req.header(reqwest::header::IF_RANGE, validator);
+        }
+        let mut response = send(req).await?.error_for_status()?;
+
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Ok(None);
+        }
+
+        let expected = end - start + 1;
+        let mut offset = start;
+
+        while let Some(chunk) = next_chunk(&mut response)
+            .await
+            .map_err(|e| body_error(e, offset - start, expected))?
+        {
+            // Look at the start of the file before writing any of it
+            if offset == 0 && !self.extension.matches_magic(&chunk) {
+                if chunk.trim_ascii_start().starts_with(b"<") {
+                    bail!("Received HTML instead of a tarball from {}", self.location);
+                }
+                bail!("{} is not a {} tarball", self.location, self.extension);
+            }
+
+            file.write_all_at(&chunk, offset)?;
+            offset += chunk.len() as u64;
+
+            if let Some(pb) = pb {
+                pb.inc(chunk.len() as u64);
+            }
+        }
+
+        check_length(offset - start, expected)?;
+
+        Ok(Some(expected))
+    }
 }
 
 //function rc_value($rc) {
@@ -311,12 +871,100 @@ impl DownloadInfo {
 //    }
 //}
 
+/// How `--segments` splits a download, with the `ETag` or `Last-Modified` value that pins every
+/// range to the same file.
+struct Segments {
+    ranges: Vec<(u64, u64)>,
+    validator: Option<String>,
+}
+
+/// Passes writes through while hashing them.
+struct HashingWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write as _;
+
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+/// Where the SHA-256 of a downloaded tarball is kept, in `sha256sum` format.
+pub fn checksum_path(tarball: &Path) -> PathBuf {
+    let mut path = tarball.as_os_str().to_owned();
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+/// Record the SHA-256 of `tarball`, as if we'd just downloaded it.
+///
+/// # Errors
+///
+/// Fails if the checksum file can't be written.
+pub fn save_checksum(tarball: &Path, digest: &str) -> Result<()> {
+    let name = tarball.file_name().unwrap_or_default().to_string_lossy();
+    cache::write_atomic(
+        &checksum_path(tarball),
+        format!("{digest}  {name}\n").as_bytes(),
+    )
+}
+
+/// The SHA-256 recorded when `tarball` was downloaded, if any.
+pub fn saved_checksum(tarball: &Path) -> Option<String> {
+    let data = fs::read_to_string(checksum_path(tarball)).ok()?;
+    let digest = data.split_whitespace().next()?;
+
+    (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| digest.to_ascii_lowercase())
+}
+
+/// Hash a file that's already on disk.
+///
+/// # Errors
+///
+/// Fails if the file can't be read.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    sha256_reader(&mut fs::File::open(path)?)
+}
+
+fn sha256_reader<R: Read>(reader: &mut R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(to_hex(&hasher.finish()))
+}
+
 impl From<VersionModifier> for i32 {
     fn from(m: VersionModifier) -> Self {
         match m {
-            VersionModifier::Alpha => -10,
-            VersionModifier::Beta => -9,
-            VersionModifier::RC(n) => -9 + Self::from(n),
+            VersionModifier::Dev => -0x400,
+            VersionModifier::Alpha(n) => -0x300 + Self::from(n),
+            VersionModifier::Beta(n) => -0x200 + Self::from(n),
+            VersionModifier::RC(n) => -0x100 + Self::from(n),
         }
     }
 }
@@ -326,11 +974,16 @@ impl Version {
         Self::new(major, minor, None, None)
     }
 
-    pub const fn from_major_minor_patch(major: u8, minor: u8, patch: u8) -> Self {
+    pub const fn from_major_minor_patch(major: u8, minor: u8, patch: u16) -> Self {
         Self::new(major, minor, Some(patch), None)
     }
 
-    pub const fn new(major: u8, minor: u8, patch: Option<u8>, rc: Option<VersionModifier>) -> Self {
+    pub const fn new(
+        major: u8,
+        minor: u8,
+        patch: Option<u16>,
+        rc: Option<VersionModifier>,
+    ) -> Self {
         Self {
             major,
             minor,
@@ -343,17 +996,40 @@ impl Version {
         format!("php-{self}.tar.{extension}")
     }
 
-    fn get_url(self, extension: Extension) -> String {
-        if self.major <= 7 && self.minor < 4 {
-            format!(
+    const fn is_museum(self) -> bool {
+        self.major <= 7 && self.minor < 4
+    }
+
+    /// Where this release lives relative to the root of php.net, and so of any `--base-url`
+    /// mirror.
+    pub fn mirror_path(self, extension: Extension) -> String {
+        if self.is_museum() {
+            format!("museum/php{}/php-{self}.tar.{extension}", self.major)
+        } else {
+            format!("distributions/php-{self}.tar.{extension}")
+        }
+    }
+
+    pub fn get_url(self, extension: Extension) -> String {
+        match Config::base_url() {
+            Some(base) => format!("{base}/{}", self.mirror_path(extension)),
+            None if self.is_museum() => format!(
                 "https://museum.php.net/php{}/php-{self}.tar.{extension}",
                 self.major
-            )
-        } else {
-            format!("https://php.net/distributions/php-{self}.tar.{extension}")
+            ),
+            None => format!("https://php.net/distributions/php-{self}.tar.{extension}"),
         }
     }
 
+    /// Every URL this release can be fetched from, the primary one first and then each mirror.
+    pub fn get_urls(self, extension: Extension) -> Vec<String> {
+        let path = self.mirror_path(extension);
+
+        std::iter::once(self.get_url(extension))
+            .chain(Config::mirrors().iter().map(|m| format!("{m}/{path}")))
+            .collect()
+    }
+
     /// Given potentially partial version information attempt to figure out what the actual latest
     /// version available for download is.
     ///
@@ -367,13 +1043,22 @@ impl Version {
             *self = dl
                 .latest()
                 .await?
-                .ok_or_else(|| anyhow!("Failed to resolve the latest patch for version {}", self))?
+                .ok_or_else(|| anyhow!("Failed to resolve the latest patch for version {self}"))?
                 .version;
         }
 
         Ok(())
     }
 
+    pub const fn is_prerelease(self) -> bool {
+        self.rc.is_some()
+    }
+
+    /// Whether this is the pseudo-version of a php-src snapshot rather than a release.
+    pub const fn is_snapshot(self) -> bool {
+        matches!(self.rc, Some(VersionModifier::Dev))
+    }
+
     pub const fn matches(self, other: Self) -> bool {
         if self.major != other.major || self.minor != other.minor {
             return false;
@@ -392,11 +1077,12 @@ impl Version {
         }
     }
 
-    pub fn to_u32(&self) -> u32 {
-        u32::from(self.major) * 1_000_000
-            + u32::from(self.minor) * 10_000
-            + u32::from(self.patch.unwrap_or(0)) * 100
-            - self.rc.map_or(0, |m| m.to_u32())
+    // Each part gets bits of its own so no patch level, however high, spills into the minor
+    fn sort_key(self) -> u64 {
+        (u64::from(self.major) << 40
+            | u64::from(self.minor) << 32
+            | u64::from(self.patch.unwrap_or(0)) << 16)
+            - self.rc.map_or(0, |m| u64::from(m.to_u32()))
     }
 }
 
@@ -405,11 +1091,12 @@ impl Serialize for DownloadInfo {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("DownloadInfo", 4)?;
+        let mut state = serializer.serialize_struct("DownloadInfo", 5)?;
 
         state.serialize_field("version", &self.version)?;
         state.serialize_field("location", &self.location)?;
         state.serialize_field("size", &self.size)?;
+        state.serialize_field("extension", &self.extension.to_string())?;
 
         // Serializing date as a String in the "YYYY/MM/DD" format
         if let Some(date) = &self.date {
@@ -432,6 +1119,25 @@ impl Serialize for Version {
     }
 }
 
+impl Serialize for Extension {
+    fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Extension {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
 impl<'de> Deserialize<'de> for Version {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -455,9 +1161,9 @@ impl fmt::Display for Version {
             ),
             None => {
                 if let Some(patch) = self.patch {
-                    write!(f, "{}.{}.{}", self.major, self.minor, patch,)
+                    write!(f, "{}.{}.{}", self.major, self.minor, patch)
                 } else {
-                    write!(f, "{}.{}", self.major, self.minor,)
+                    write!(f, "{}.{}", self.major, self.minor)
                 }
             }
         }
@@ -481,15 +1187,16 @@ impl PartialOrd for Version {
 
 impl Ord for Version {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.to_u32().cmp(&other.to_u32())
+        self.sort_key().cmp(&other.sort_key())
     }
 }
 
 impl fmt::Display for VersionModifier {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let v = match self {
-            Self::Alpha => "alpha".into(),
-            Self::Beta => "beta".into(),
+            Self::Dev => "-dev".into(),
+            Self::Alpha(n) => format!("alpha{n}"),
+            Self::Beta(n) => format!("beta{n}"),
             Self::RC(n) => format!("RC{n}"),
         };
 
@@ -498,26 +1205,392 @@ impl fmt::Display for VersionModifier {
 }
 
 impl Extension {
+    /// Whether `data` starts like a file of this type.
+    pub fn matches_magic(self, data: &[u8]) -> bool {
+        let magic: &[u8] = match self {
+            Self::GZ => &[0x1f, 0x8b],
+            Self::BZ => b"BZh",
+            Self::XZ => &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+        };
+
+        data.starts_with(magic)
+    }
+
     pub fn variants() -> Vec<Self> {
         vec![Self::GZ, Self::BZ, Self::XZ]
     }
+
+    /// php.net only started publishing xz tarballs with PHP 5.5, so older series fall back to
+    /// bzip2.
+    #[must_use]
+    pub const fn for_series(self, major: u8, minor: u8) -> Self {
+        match self {
+            Self::XZ if major < 5 || (major == 5 && minor < 5) => Self::BZ,
+            ext => ext,
+        }
+    }
+}
+
+static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Build the client every request goes through, honoring `--connect-timeout`, `--user-agent`,
+/// `--proxy` and the TLS options.  Called once they've all been set.
+///
+/// # Errors
+///
+/// Fails if the proxy or CA bundle is unusable or the client can't be built.
+pub fn init_http_client() -> Result<()> {
+    let mut builder = Client::builder()
+        .user_agent(Config::user_agent())
+        .connect_timeout(Config::connect_timeout());
+    // Without one reqwest picks up HTTP_PROXY, HTTPS_PROXY and NO_PROXY itself
+    if let Some(url) = Config::proxy() {
+        builder = builder.proxy(transport::proxy(url)?);
+    }
+    if let Some(path) = Config::ca_bundle() {
+        for cert in transport::ca_certificates(path)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    let client = builder
+        .tls_built_in_root_certs(!Config::no_system_roots())
+        .danger_accept_invalid_certs(Config::skip_tls_verify())
+        .build()
+        .context("Unable to set up the HTTP client")?;
+
+    HTTP_CLIENT
+        .set(client)
+        .map_err(|_| anyhow!("HTTP client already set up"))
+}
+
+/// The client set up by `init_http_client`.
+///
+/// # Panics
+///
+/// Panics if `init_http_client` hasn't been called, since any other client would quietly ignore
+/// the proxy and TLS options.
+pub fn http_client() -> Client {
+    HTTP_CLIENT
+        .get()
+        .cloned()
+        .expect("HTTP client used before init_http_client")
+}
+
+/// Await a network operation, giving up after `--timeout`.  Applied to each read of a download
+/// rather than the whole thing, so large tarballs on slow links still work but stalls don't.
+///
+/// # Errors
+///
+/// Fails if the operation fails or times out.
+pub async fn timed<T, F>(fut: F) -> Result<T>
+where
+    F: std::future::Future<Output = reqwest::Result<T>>,
+{
+    match Config::timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| anyhow!("Timed out after {}s", timeout.as_secs()))?
+            .map_err(Into::into),
+        None => Ok(fut.await?),
+    }
+}
+
+/// Send a request, subject to `--timeout`, logging the method, URL, status and duration at `-vv`.
+///
+/// # Errors
+///
+/// Fails if the request can't be built, fails or times out.
+pub async fn send(req: RequestBuilder) -> Result<Response> {
+    let (client, req) = req.build_split();
+    let req = req?;
+
+    // Refuse up front rather than hanging on DNS
+    if Config::offline() {
+        bail!(msg!(Msg::OfflineRefused, url = req.url()));
+    }
+
+    if Config::verbosity() < 2 {
+        return timed(client.execute(req)).await;
+    }
+
+    let (method, url) = (req.method().clone(), req.url().clone());
+    let start = Instant::now();
+    let res = timed(client.execute(req)).await;
+
+    let outcome = match &res {
+        Ok(res) => res.status().to_string(),
+        Err(e) => format!("failed ({e})"),
+    };
+    eprintln!(
+        "{method} {url} {outcome} in {}ms",
+        start.elapsed().as_millis()
+    );
+
+    res
+}
+
+/// Read the next chunk of a download, failing with `Stalled` if nothing arrives within the stall
+/// timeout.
+async fn next_chunk(response: &mut reqwest::Response) -> Result<Option<bytes::Bytes>> {
+    match Config::stall_timeout() {
+        Some(stall) => tokio::time::timeout(stall, timed(response.chunk()))
+            .await
+            .map_err(|_| Stalled(stall))?,
+        None => timed(response.chunk()).await,
+    }
+}
+
+// Hosts we couldn't connect to, so the rest of the run doesn't keep waiting on them
+static UNREACHABLE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()
+        .map(|u| u.origin().ascii_serialization())
+}
+
+fn is_unreachable(url: &str) -> bool {
+    host(url).is_some_and(|host| UNREACHABLE.lock().is_ok_and(|hosts| hosts.contains(&host)))
+}
+
+fn mark_unreachable(url: &str, e: &anyhow::Error) {
+    let connect = e
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(reqwest::Error::is_connect);
+
+    if let (true, Some(host), Ok(mut hosts)) = (connect, host(url), UNREACHABLE.lock()) {
+        if !hosts.contains(&host) {
+            hosts.push(host);
+        }
+    }
+}
+
+/// Whether a failed request is worth retrying against the next mirror: the server returned an
+/// error, couldn't be reached, or stopped sending data.
+fn should_fail_over(e: &anyhow::Error) -> bool {
+    is_interrupted(e)
+        || e.downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_status() || e.is_connect() || e.is_timeout())
+}
+
+/// Where the releases API lists a series.
+pub fn series_api_path(major: u8, minor: u8) -> String {
+    format!("releases/index.php?json&version={major}.{minor}&max=1000")
+}
+
+/// The URLs `path` of the releases API is fetched from, php.net or the `--base-url` standing in
+/// for it followed by each mirror.
+pub fn api_urls(path: &str) -> Vec<String> {
+    let primary = Config::base_url().unwrap_or("https://www.php.net");
+
+    std::iter::once(primary)
+        .chain(Config::mirrors().iter().map(String::as_str))
+        .map(|base| format!("{base}/{path}"))
+        .collect()
+}
+
+/// Fetch `path` from php.net's releases API, or the `--base-url` standing in for it, falling back
+/// to each mirror.  Returns the URL that answered along with the body.
+async fn fetch_api(path: &str) -> Result<(String, String)> {
+    let mut last = None;
+
+    for url in api_urls(path) {
+        if is_unreachable(&url) {
+            continue;
+        }
+
+        match cache::fetch(&http_client(), &url).await {
+            Ok(body) => return Ok((url, body)),
+            Err(e) if should_fail_over(&e) => {
+                mark_unreachable(&url, &e);
+                last = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(last.unwrap_or_else(|| anyhow!("No reachable host for {path}")))
+}
+
+/// Mirrors and captive portals like to answer with a 200 and an HTML page.
+fn is_html(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.to_ascii_lowercase().starts_with("text/html"))
 }
 
 impl DownloadList {
+    /// The release series php.net currently supports along with their newest release.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the releases API can't be fetched or parsed.
+    pub async fn active_releases() -> Result<Vec<ActiveRelease>> {
+        let (url, body) = fetch_api("releases/active/").await?;
+        let json: serde_json::Value = serde_json::from_str(&body)?;
+
+        let mut releases: Vec<_> = json
+            .as_object()
+            .ok_or_else(|| anyhow!("Unexpected response from {url}"))?
+            .values()
+            .filter_map(serde_json::Value::as_object)
+            .flatten()
+            .filter_map(|(branch, info)| ActiveRelease::from_json(branch, info))
+            .collect();
+
+        releases.sort_unstable_by_key(|r| (r.major, r.minor));
+
+        Ok(releases)
+    }
+
+    async fn series_states() -> Result<Vec<SeriesState>> {
+        let (url, body) = fetch_api("releases/states.php").await?;
+        let json: serde_json::Value = serde_json::from_str(&body)?;
+
+        Ok(json
+            .as_object()
+            .ok_or_else(|| anyhow!("Unexpected response from {url}"))?
+            .values()
+            .filter_map(serde_json::Value::as_object)
+            .flatten()
+            .filter_map(|(branch, info)| {
+                let (major, minor) = branch.split_once('.')?;
+                Some(SeriesState {
+                    major: major.parse().ok()?,
+                    minor: minor.parse().ok()?,
+                    security_end: info["security_support_end"]
+                        .as_str()
+                        .and_then(|end| end.get(..10))
+                        .and_then(|end| NaiveDate::parse_from_str(end, "%Y-%m-%d").ok()),
+                })
+            })
+            .collect())
+    }
+
+    /// Whether `major.minor` no longer gets security fixes.  php.net only lists the series it
+    /// supports, so anything older than all of them is end of life and anything newer is yet to
+    /// be released.
+    ///
+    /// # Errors
+    ///
+    /// Fails if neither the release states nor the active releases can be fetched.
+    pub async fn eol(major: u8, minor: u8) -> Result<Option<Eol>> {
+        let states = match Self::series_states().await {
+            Ok(states) => states,
+            // Not every mirror carries states.php, the active releases tell us nearly as much
+            Err(_) => Self::active_series()
+                .await?
+                .into_iter()
+                .map(|(major, minor)| SeriesState {
+                    major,
+                    minor,
+                    security_end: None,
+                })
+                .collect(),
+        };
+
+        let eol = |since| Eol {
+            major,
+            minor,
+            since,
+        };
+
+        if let Some(state) = states.iter().find(|s| (s.major, s.minor) == (major, minor)) {
+            let today = Utc::now().date_naive();
+            return Ok(state
+                .security_end
+                .filter(|&end| end <= today)
+                .map(|end| eol(Some(end))));
+        }
+
+        Ok(states
+            .iter()
+            .map(|s| (s.major, s.minor))
+            .min()
+            .filter(|&oldest| (major, minor) < oldest)
+            .map(|_| eol(None)))
+    }
+
+    /// The release series php.net currently supports, e.g. `[(8, 2), (8, 3)]`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the releases API can't be fetched or parsed.
+    pub async fn active_series() -> Result<Vec<(u8, u8)>> {
+        Ok(Self::active_releases()
+            .await?
+            .into_iter()
+            .map(|r| (r.major, r.minor))
+            .collect())
+    }
+
     pub fn new(major: u8, minor: u8, extension: Extension) -> Self {
         Self {
-            client: Client::new(),
+            client: http_client(),
             major,
             minor,
-            extension,
+            extension: extension.for_series(major, minor),
+            pre: false,
+            qa: false,
         }
     }
 
+    pub const fn extension(&self) -> Extension {
+        self.extension
+    }
+
+    /// Allow pre-releases (alpha, beta, RC) to be picked as the latest version.  This also lists
+    /// the QA releases, since that's where current pre-releases are.
+    #[must_use]
+    pub const fn with_pre(mut self, pre: bool) -> Self {
+        self.pre = pre;
+        self
+    }
+
+    /// List the pre-releases php.net is currently testing alongside the releases.
+    #[must_use]
+    pub const fn with_qa(mut self, qa: bool) -> Self {
+        self.qa = qa;
+        self
+    }
+
+    // Try each mirror in turn, it's only an error if none of them could be reached at all
     async fn get_header(&self, version: Version) -> Result<Option<DownloadInfo>> {
-        let url = version.get_url(self.extension);
-        let res = self.client.head(&url).send().await?;
+        let (mut reached, mut last) = (false, None);
+
+        for url in version.get_urls(self.extension) {
+            if is_unreachable(&url) {
+                continue;
+            }
 
-        if res.status().is_success() {
+            match send(auth::authorize(self.client.head(&url), &url)).await {
+                Ok(res) => {
+                    if let Some(info) = self.header_info(version, &url, &res) {
+                        return Ok(Some(info));
+                    }
+                    reached = true;
+                }
+                Err(e) => {
+                    mark_unreachable(&url, &e);
+                    last = last.or(Some(e));
+                }
+            }
+        }
+
+        match last {
+            Some(e) if !reached => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    fn header_info(
+        &self,
+        version: Version,
+        url: &str,
+        res: &reqwest::Response,
+    ) -> Option<DownloadInfo> {
+        if res.status().is_success() && !is_html(res) {
             let content_length = res
                 .headers()
                 .get(reqwest::header::CONTENT_LENGTH)
@@ -532,43 +1605,205 @@ impl DownloadList {
                 .and_then(|str_val| DateTime::parse_from_rfc2822(str_val).ok())
                 .map(|datetime| datetime.with_timezone(&Utc));
 
-            Ok(Some(DownloadInfo::new(
+            Some(DownloadInfo::new(
                 version,
-                &url,
+                url,
                 content_length,
                 last_modified,
                 self.extension,
-            )))
+            ))
         } else {
-            Ok(None)
+            None
         }
     }
 
-    fn get_check_versions(&self) -> Box<dyn Iterator<Item = Version> + '_> {
-        Box::new(
-            (0..31).map(|patch| Version::from_major_minor_patch(self.major, self.minor, patch)),
-        )
+    /// Our tarballs of this series php.net's releases API knows about.  It doesn't publish sizes,
+    /// so those stay zero, and museum releases are left to `probe_list`.
+    async fn api_list(&self) -> Result<Vec<DownloadInfo>> {
+        let (url, body) = fetch_api(&series_api_path(self.major, self.minor)).await?;
+        let json: serde_json::Value =
+            serde_json::from_str(&body).context(format!("Unexpected response from {url}"))?;
+
+        Ok(json
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(version, info)| Some((version.parse::<Version>().ok()?, info)))
+            .filter(|(v, _)| v.major == self.major && v.minor == self.minor && !v.is_museum())
+            .filter_map(|(version, info)| {
+                let file_name = version.get_file_name(self.extension);
+                let source = info["source"]
+                    .as_array()?
+                    .iter()
+                    .find(|s| s["filename"].as_str() == Some(&file_name))?;
+
+                let date = source["date"]
+                    .as_str()
+                    .or_else(|| info["date"].as_str())
+                    .and_then(|d| NaiveDate::parse_from_str(d, "%d %b %Y").ok())
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .map(|d| d.and_utc());
+
+                Some(DownloadInfo::new(
+                    version,
+                    &version.get_url(self.extension),
+                    0,
+                    date,
+                    self.extension,
+                ))
+            })
+            .collect())
+    }
+
+    /// Pre-releases of this series from php.net's QA API.  Their tarballs live under the release
+    /// manager's home on downloads.php.net rather than in `distributions`.
+    async fn qa_list(&self) -> Result<Vec<DownloadInfo>> {
+        let (url, body) = fetch_api("release-candidates.php?format=json").await?;
+        let json: serde_json::Value =
+            serde_json::from_str(&body).context(format!("Unexpected response from {url}"))?;
+
+        Ok(json["releases"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|r| r["active"].as_bool() != Some(false))
+            .filter_map(|r| {
+                let version: Version = r["version"].as_str()?.parse().ok()?;
+                if version.major != self.major || version.minor != self.minor {
+                    return None;
+                }
+
+                let location = r["files"][self.extension.to_string()]["path"].as_str()?;
+                let date = r["release"]["date"]
+                    .as_str()
+                    .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .map(|d| d.and_utc());
+
+                Some(DownloadInfo::new(
+                    version,
+                    location,
+                    0,
+                    date,
+                    self.extension,
+                ))
+            })
+            .collect())
+    }
+
+    // Failing to reach the QA API shouldn't stop us listing releases
+    async fn qa_list_or_warn(&self) -> Vec<DownloadInfo> {
+        self.qa_list().await.unwrap_or_else(|e| {
+            eprintln!("{}", msg!(Msg::QaUnavailable, error = format!("{e:#}")));
+            vec![]
+        })
+    }
+
+    // Without the API all we can do is ask for each patch level in turn.  Patch levels have no
+    // gaps, so a batch without a single release means we've gone past the last one.
+    async fn probe_list(&self) -> Vec<DownloadInfo> {
+        let mut found = vec![];
+
+        for start in (0..=u16::MAX).step_by(PROBE_BATCH.into()) {
+            let batch = (start..=u16::MAX).take(PROBE_BATCH.into()).map(|patch| {
+                self.get_header(Version::from_major_minor_patch(
+                    self.major, self.minor, patch,
+                ))
+            });
+
+            let hits: Vec<_> = join_all(batch)
+                .await
+                .into_iter()
+                .filter_map(Result::ok)
+                .flatten()
+                .collect();
+
+            if hits.is_empty() {
+                break;
+            }
+
+            found.extend(hits);
+        }
+
+        found
+    }
+
+    /// What we know about this series without the network, the last listing we fetched however
+    /// old it is and any tarballs in the registry.
+    fn offline_list(&self) -> Vec<DownloadInfo> {
+        let mut urls =
+            cache::load_stale_list(self.major, self.minor, self.extension).unwrap_or_default();
+
+        let registry = Config::registry_path().and_then(|path| extract::Tarball::list(&path));
+        for info in registry.into_iter().flat_map(|listing| listing.tarballs) {
+            let ours = info.version.major == self.major
+                && info.version.minor == self.minor
+                && info.extension == self.extension;
+
+            if ours && !urls.iter().any(|u| u.version == info.version) {
+                urls.push(info);
+            }
+        }
+
+        urls.sort_unstable_by_key(|u| u.version);
+        urls
+    }
+
+    async fn fetch_list(&self) -> Result<Vec<DownloadInfo>> {
+        let mut urls = match self.api_list().await {
+            Ok(urls) if !urls.is_empty() => urls,
+            Ok(_) => self.probe_list().await,
+            // Nothing from the probes either means php.net is unreachable, not an empty series
+            Err(e) => {
+                let urls = self.probe_list().await;
+                if urls.is_empty() {
+                    return Err(e);
+                }
+                urls
+            }
+        };
+
+        urls.sort_unstable_by_key(|u| u.version);
+
+        // QA releases come and go, so they're never cached with the releases
+        cache::store_list(self.major, self.minor, self.extension, &urls);
+        Ok(urls)
     }
 
     /// List versions available for download.
     ///
     /// # Errors
     ///
-    /// This can fail if we have troulbe reading data from the remote host.
+    /// This can fail if we have trouble reading data from the remote host.
     pub async fn list(&self) -> Result<Vec<DownloadInfo>> {
-        let urls: Vec<_> = self
-            .get_check_versions()
-            .map(|version| self.get_header(version))
-            .collect();
+        if Config::offline() {
+            return Ok(self.offline_list());
+        }
 
-        let mut urls: Vec<_> = join_all(urls)
-            .await
-            .into_iter()
-            .filter_map(Result::ok)
-            .flatten()
-            .collect();
+        let mut urls = match cache::load_list(self.major, self.minor, self.extension) {
+            Some(urls) => urls,
+            None => match self.fetch_list().await {
+                Ok(urls) => urls,
+                Err(e) if Config::settings().no_offline_fallback => return Err(e),
+                Err(e) => {
+                    let urls = self.offline_list();
+                    if urls.is_empty() {
+                        return Err(e);
+                    }
+                    eprintln!("{}", msg!(Msg::OfflineData, error = e));
+                    urls
+                }
+            },
+        };
 
-        urls.sort_unstable_by(|b, a| b.version.cmp(&a.version));
+        if self.qa || self.pre {
+            for info in self.qa_list_or_warn().await {
+                if !urls.iter().any(|u| u.version == info.version) {
+                    urls.push(info);
+                }
+            }
+            urls.sort_unstable_by_key(|u| u.version);
+        }
 
         Ok(urls)
     }
@@ -580,6 +1815,11 @@ impl DownloadList {
     /// This can fail if our list is empty
     pub async fn latest(&self) -> Result<Option<DownloadInfo>> {
         let mut urls = self.list().await?;
+
+        if !self.pre {
+            urls.retain(|u| !u.version.is_prerelease());
+        }
+
         Ok(urls.pop())
     }
 
@@ -589,6 +1829,27 @@ impl DownloadList {
     ///
     /// This can fail if we can't read the header.
     pub async fn get(&self, version: Version) -> Result<Option<DownloadInfo>> {
+        if Config::offline() {
+            return Ok(self
+                .offline_list()
+                .into_iter()
+                .find(|u| u.version == version));
+        }
+
+        let cached = cache::load_list(self.major, self.minor, self.extension)
+            .and_then(|urls| urls.into_iter().find(|u| u.version == version));
+
+        if cached.is_some() {
+            return Ok(cached);
+        }
+
+        if version.is_prerelease() {
+            let qa = self.qa_list_or_warn().await;
+            if let Some(info) = qa.into_iter().find(|u| u.version == version) {
+                return Ok(Some(info));
+            }
+        }
+
         self.get_header(version).await
     }
 }
@@ -603,12 +1864,12 @@ mod tests {
             ("7.4.0", Version::new(7, 4, Some(0), None)),
             ("7.4.1", Version::new(7, 4, Some(1), None)),
             (
-                "8.0.0alpha",
-                Version::new(8, 0, Some(0), Some(VersionModifier::Alpha)),
+                "8.0.0alpha1",
+                Version::new(8, 0, Some(0), Some(VersionModifier::Alpha(1))),
             ),
             (
-                "8.0.0beta",
-                Version::new(8, 0, Some(0), Some(VersionModifier::Beta)),
+                "8.0.0beta2",
+                Version::new(8, 0, Some(0), Some(VersionModifier::Beta(2))),
             ),
             (
                 "8.0.0RC1",
@@ -618,6 +1879,10 @@ mod tests {
                 "8.0.0RC2",
                 Version::new(8, 0, Some(0), Some(VersionModifier::RC(2))),
             ),
+            (
+                "8.5.0-dev",
+                Version::new(8, 5, Some(0), Some(VersionModifier::Dev)),
+            ),
         ];
 
         for (s, expected) in versions {
@@ -634,18 +1899,24 @@ mod tests {
         let versions = &[
             "7.4.1",
             "7.4.0",
-            "8.3.0beta",
+            "7.4.333",
+            "7.4.33",
+            "8.3.0beta1",
             "8.3.0",
             "8.3.0RC2",
-            "8.3.0alpha",
+            "8.3.0alpha1",
             "8.3.0RC1",
+            "8.3.0-dev",
         ];
 
         let sorted = &[
             "7.4.0",
             "7.4.1",
-            "8.3.0alpha",
-            "8.3.0beta",
+            "7.4.33",
+            "7.4.333",
+            "8.3.0-dev",
+            "8.3.0alpha1",
+            "8.3.0beta1",
             "8.3.0RC1",
             "8.3.0RC2",
             "8.3.0",
@@ -657,7 +1928,7 @@ mod tests {
             .collect(); // Collect into a Vec<(str, Version)>
 
         // Sort the vector by the Version part
-        mapped.sort_by(|a, b| a.1.cmp(&b.1));
+        mapped.sort_by_key(|m| m.1);
 
         // Extract the string parts from the sorted tuples
         let sorted_strings: Vec<&str> = mapped.iter().map(|(s, _)| **s).collect();
@@ -666,6 +1937,14 @@ mod tests {
         assert_eq!(sorted_strings, sorted);
     }
 
+    #[test]
+    fn extension_for_series() {
+        assert_eq!(Extension::XZ.for_series(5, 4).to_string(), "bz2");
+        assert_eq!(Extension::XZ.for_series(5, 5).to_string(), "xz");
+        assert_eq!(Extension::XZ.for_series(8, 3).to_string(), "xz");
+        assert_eq!(Extension::GZ.for_series(5, 3).to_string(), "gz");
+    }
+
     #[test]
     fn parse_rc_version() {
         let version_str = "8.3.0RC5";
@@ -677,4 +1956,28 @@ mod tests {
 
         assert_eq!("8.3.0RC5", version.to_string());
     }
+
+    #[test]
+    fn parse_beta_version() {
+        let beta2 = Version::from_str("8.4.0beta2").expect("Can't parse beta2");
+        let beta3 = Version::from_str("8.4.0beta3").expect("Can't parse beta3");
+
+        assert_eq!(
+            beta2,
+            Version::new(8, 4, Some(0), Some(VersionModifier::Beta(2)))
+        );
+        assert_ne!(beta2, beta3);
+        assert_eq!(beta3.to_string(), "8.4.0beta3");
+        assert_eq!(beta3.get_file_name(Extension::XZ), "php-8.4.0beta3.tar.xz");
+    }
+
+    #[test]
+    fn pre_release_numbers_sort() {
+        let parse = |s: &str| Version::from_str(s).expect("Can't parse version string");
+
+        assert!(parse("8.4.0beta2") < parse("8.4.0beta3"));
+        assert!(parse("8.4.0alpha3") < parse("8.4.0beta1"));
+        assert!(parse("8.4.0beta3") < parse("8.4.0RC1"));
+        assert!(parse("8.4.0RC12") < parse("8.4.0"));
+    }
 }
@@ -1,33 +1,181 @@
 use crate::{
-    downloads::{DownloadInfo, DownloadList, Extension, Version},
+    cache,
+    downloads::{self, DownloadInfo, DownloadList, Extension, Version},
+    messages::{msg, Msg},
+    platform::Platform,
+    progress, signature, snapshot,
+    template::Template,
     view::ToHumanSize,
     Config,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use bzip2::read::BzDecoder;
+use clap::ValueEnum;
+use filetime::FileTime;
 use flate2::read::GzDecoder;
 use indicatif::ProgressBar;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    ffi::CString,
     fs::File,
     fs::{self},
-    io::{self, BufRead, BufReader, Read, Write},
-    path::{Path, PathBuf},
+    hash::{Hash, Hasher},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt, io::AsRawFd},
+    path::{Component, Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
     result::Result as StdResult,
+    sync::mpsc::{self, SyncSender},
+    thread,
+    time::{Duration, Instant},
 };
-use tar::Archive;
+use tar::{Archive, Entry};
 
 use walkdir::WalkDir;
 use xz::read::XzDecoder;
 
-#[derive(Debug)]
+/// What `Tarball::list` found in a registry directory.
+#[derive(Debug, Default)]
+pub struct Listing {
+    pub tarballs: Vec<DownloadInfo>,
+    /// Entries we had to skip, for the caller to report or not.
+    pub warnings: Vec<String>,
+}
+
+impl Listing {
+    /// Print the warnings and return the tarballs.
+    pub fn report(self) -> Vec<DownloadInfo> {
+        for warning in &self.warnings {
+            eprintln!("Warning:  {warning}");
+        }
+
+        self.tarballs
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Tarball {
     src: PathBuf,
     ext: Extension,
+    version: Version,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+/// The tarball a build root was extracted from, saved alongside the tree so we can tell later
+/// exactly which sources it was built from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub tarball: String,
+    pub size: u64,
+    pub checksum: String,
+    /// The host's libc when the tree was extracted, see `Platform::libc`.
+    #[serde(default)]
+    pub libc: Option<String>,
+    /// Compatibility fixes applied to the tree, see `compat::apply_patches`.
+    #[serde(default)]
+    pub patches: Vec<String>,
+    /// What the tree was extracted as, so it's still recognized under a `dir-name` template.
+    #[serde(default)]
+    pub version: Option<Version>,
+    #[serde(default)]
+    pub modifiers: Option<String>,
+}
+
+/// Suffix of the directories `upgrade` saves a replaced root's scripts to.
+pub const BACKUP_SUFFIX: &str = "-backup-scripts";
+
+/// A scripts backup `upgrade` left inside a build root.  Each upgrade copies the previous root's
+/// backups along, so older backups end up nested inside newer ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptBackup {
+    /// Relative to the build root
+    pub path: PathBuf,
+    pub version: Option<Version>,
+    pub created: i64,
+}
+
+impl ScriptBackup {
+    /// How many upgrades ago this backup was made, 1 being the most recent.
+    pub fn generation(&self) -> usize {
+        self.path.components().count()
+    }
+}
+
+/// How a tarball or build root fared under `verify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Ok,
+    Failed,
+    /// Nothing recorded to check against
+    Unchecked,
+}
+
+/// What modification time unpacked files end up with.
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum MtimeMode {
+    /// Keep the times stored in the archive
+    #[default]
+    Archive,
+    /// Use the time of extraction
+    Now,
+    /// Set every file to `SOURCE_DATE_EPOCH`, or the Unix epoch if it isn't set
+    Epoch,
+    /// Keep archive times but clamp them to `SOURCE_DATE_EPOCH`
+    Clamp,
+}
+
+/// Controls how entries are written out of the archive.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnpackOptions {
+    pub preserve_xattrs: bool,
+    pub mtime: MtimeMode,
+    pub resume: bool,
+    pub fast_decompress: bool,
+}
+
+/// Multi-threaded decompressors `--fast-decompress` pipes tarballs through, in order of
+/// preference.  `xz` only decodes in parallel from 5.4 on, older versions accept `-T0` and decode
+/// on one thread.
+const FAST_DECODERS: &[(Extension, &str, &[&str])] = &[
+    (Extension::XZ, "xz", &["-T0", "-dc"]),
+    (Extension::BZ, "lbzip2", &["-dc"]),
+    (Extension::BZ, "pbzip2", &["-dc"]),
+    (Extension::GZ, "pigz", &["-dc"]),
+];
+
+/// Upper bound on file writer threads, past this we're just contending on the filesystem.
+const MAX_WRITERS: usize = 8;
+
+/// Files read ahead of the writers, shared between them.  Bounds memory, PHP's largest sources
+/// are a few MB.
+const WRITER_QUEUE: usize = 256;
+
+/// A regular file read out of the archive, waiting for a writer thread.
+struct PendingFile {
+    path: PathBuf,
+    data: Vec<u8>,
+    mode: u32,
+    mtime: Option<FileTime>,
+}
+
+/// Entries that can only be unpacked once every regular file has been written.
+struct Deferred<'a, R: Read> {
+    links: Vec<Entry<'a, R>>,
+    directories: Vec<Entry<'a, R>>,
+    skipped: u64,
+}
+
+/// Output of an external decompressor.  The process is killed if we stop reading early, and a
+/// failed exit is a read error so a truncated stream is never mistaken for the end of the archive.
+struct ExternalDecoder {
+    name: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BuildRoot {
     pub src: PathBuf,
     pub version: Version,
@@ -39,14 +187,107 @@ struct ProgressReader<R> {
     progress_bar: ProgressBar,
 }
 
-struct ProgressWriter<W> {
-    name: String,
-    writer: W,
-    progress_bar: ProgressBar,
-    bytes_written: usize,
+/// Glob patterns (`*` and `?`) for directory names to skip while scanning for build roots.  They
+/// come from `.phpdownloaderignore` in the scanned directory and the `ignore` setting.
+#[derive(Debug, Default)]
+struct IgnoreList {
+    patterns: Vec<Regex>,
+}
+
+impl IgnoreList {
+    fn glob_to_regex(glob: &str) -> Result<Regex> {
+        let mut re = String::from("^");
+
+        for c in glob.chars() {
+            match c {
+                '*' => re.push_str("[^/]*"),
+                '?' => re.push_str("[^/]"),
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+
+        re.push('$');
+
+        Ok(Regex::new(&re)?)
+    }
+
+    fn from_globs<'a, I: IntoIterator<Item = &'a str>>(globs: I) -> Result<Self> {
+        let patterns = globs
+            .into_iter()
+            .map(str::trim)
+            .filter(|g| !g.is_empty() && !g.starts_with('#'))
+            .map(|g| Self::glob_to_regex(g.trim_end_matches('/')))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn load(dir: &Path) -> Result<Self> {
+        let file = dir.join(Config::APP_IGNORE_FILE);
+
+        let contents = match fs::read_to_string(&file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context(format!("Unable to read {file:?}")),
+        };
+
+        let settings = Config::settings();
+
+        Self::from_globs(
+            contents
+                .lines()
+                .chain(settings.ignore.iter().map(String::as_str)),
+        )
+    }
+
+    fn is_ignored(&self, name: &str) -> bool {
+        self.patterns.iter().any(|re| re.is_match(name))
+    }
+}
+
+impl MtimeMode {
+    fn source_date_epoch() -> Result<Option<i64>> {
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(v) => Ok(Some(
+                v.trim()
+                    .parse()
+                    .context(format!("Invalid SOURCE_DATE_EPOCH {v:?}"))?,
+            )),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Rewrite the modification times of everything under `path`, if this mode calls for it.
+    fn apply(self, path: &Path) -> Result<()> {
+        let (epoch, clamp) = match self {
+            Self::Archive | Self::Now => return Ok(()),
+            Self::Epoch => (Self::source_date_epoch()?.unwrap_or(0), false),
+            Self::Clamp => (
+                Self::source_date_epoch()?
+                    .context("--mtime clamp requires SOURCE_DATE_EPOCH to be set")?,
+                true,
+            ),
+        };
+
+        let epoch = FileTime::from_unix_time(epoch, 0);
+
+        for entry in WalkDir::new(path).into_iter().filter_map(StdResult::ok) {
+            let meta = entry.path().symlink_metadata()?;
+
+            if clamp && FileTime::from_last_modification_time(&meta) <= epoch {
+                continue;
+            }
+
+            filetime::set_symlink_file_times(entry.path(), epoch, epoch)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Tarball {
+    #[allow(clippy::unnecessary_debug_formatting)]
     pub fn new(version: Version, extension: Extension) -> Result<Self> {
         let mut src = PathBuf::from(&Config::registry_path()?);
         src.push(format!("php-{version}.tar.{extension}"));
@@ -58,13 +299,26 @@ impl Tarball {
         Ok(Self {
             src,
             ext: extension,
+            version,
         })
     }
 
     // Download a specific resolved version if we don't have it
     pub async fn get_or_download(version: Version, extension: Extension) -> Result<Self> {
         if Self::new(version, extension).is_err() {
-            eprintln!("Unable to find {version} locally, downloading.");
+            if version.is_snapshot() {
+                bail!(msg!(Msg::SnapshotMissing, version = version));
+            }
+
+            if Config::offline() {
+                bail!(msg!(
+                    Msg::NotInRegistry,
+                    version = version,
+                    extension = extension
+                ));
+            }
+
+            eprintln!("{}", msg!(Msg::Downloading, version = version));
             let downloads = DownloadList::new(version.major, version.minor, extension);
             let dl = downloads
                 .get(version)
@@ -75,20 +329,106 @@ impl Tarball {
             dst.push(version.get_file_name(extension));
 
             dl.download_to_file(&dst).await?;
+        } else if version.is_snapshot() {
+            // The pseudo-version names whichever head we fetched last, so say which that was
+            let src = Self::new(version, extension)?.src;
+            let commit = snapshot::saved_commit(&src)
+                .context(msg!(Msg::SnapshotNoCommit, version = version))?;
+            eprintln!(
+                "{}",
+                msg!(
+                    Msg::SnapshotUsing,
+                    version = version,
+                    commit = snapshot::short(&commit)
+                )
+            );
+        } else if Config::verify_signatures() {
+            let src = Self::new(version, extension)?.src;
+            let url = version.get_url(extension);
+
+            if let Some(sig) = signature::check(&url, &src).await? {
+                signature::save(&src, &sig)?;
+            }
         }
 
         Self::new(version, extension)
     }
 
-    fn progress_spinner(&self, size: u64, dst: &Path) -> Result<ProgressBar> {
+    /// Name, size and SHA-256 of the tarball.  The hash saved at download time is used when we
+    /// have one, so this usually doesn't read the tarball at all.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn source_info(&self) -> Result<SourceInfo> {
+        let size = fs::metadata(&self.src)
+            .context(format!("Unable to open {:?}", self.src))?
+            .len();
+
+        let digest = match downloads::saved_checksum(&self.src) {
+            Some(digest) => digest,
+            None => downloads::sha256_file(&self.src)
+                .context(format!("Unable to read {:?}", self.src))?,
+        };
+
+        Ok(SourceInfo {
+            tarball: self.src.file_name().map_or_else(
+                || self.src.to_string_lossy().into_owned(),
+                |n| n.to_string_lossy().into_owned(),
+            ),
+            size,
+            checksum: format!("sha256:{digest}"),
+            libc: None,
+            patches: vec![],
+            version: None,
+            modifiers: None,
+        })
+    }
+
+    /// Check the tarball is the kind of archive its name says, and still matches the SHA-256
+    /// recorded when it was downloaded.
+    pub fn verify(&self) -> (Verdict, String) {
+        let mut magic = [0u8; 6];
+        match File::open(&self.src).and_then(|mut f| f.read(&mut magic)) {
+            Err(e) => return (Verdict::Failed, format!("unreadable ({e})")),
+            Ok(n) if !self.ext.matches_magic(&magic[..n]) => {
+                return (Verdict::Failed, format!("not a .{} archive", self.ext))
+            }
+            Ok(_) => {}
+        }
+
+        let Some(expected) = downloads::saved_checksum(&self.src) else {
+            return (Verdict::Unchecked, "no recorded checksum".into());
+        };
+
+        match downloads::sha256_file(&self.src) {
+            Ok(actual) if actual == expected => (Verdict::Ok, "sha256 matches".into()),
+            Ok(actual) => (
+                Verdict::Failed,
+                format!("sha256 mismatch, expected {expected} got {actual}"),
+            ),
+            Err(e) => (Verdict::Failed, format!("unreadable ({e})")),
+        }
+    }
+
+    /// Delete the tarball from disk, returning where it was.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn remove(self) -> Result<PathBuf> {
+        fs::remove_file(&self.src).context(format!("Unable to remove {:?}", self.src))?;
+        let _ = fs::remove_file(downloads::checksum_path(&self.src));
+        let _ = fs::remove_file(signature::signature_path(&self.src));
+        let _ = fs::remove_file(snapshot::commit_path(&self.src));
+        Ok(self.src)
+    }
+
+    fn progress_bar(&self, size: u64, dst: &Path) -> Result<ProgressBar> {
         let file = self
             .src
             .file_name()
             .ok_or_else(|| anyhow!("Can't get filename"))?
             .to_string_lossy();
 
-        let pb = ProgressBar::new_spinner();
-
+        let pb = progress::bar(
+            size,
+            "{msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent}% ({eta})",
+        )?;
         pb.set_message(format!(
             "{file} ({}) -> {}",
             size.to_human_size(),
@@ -98,6 +438,28 @@ impl Tarball {
         Ok(pb)
     }
 
+    /// Roughly how many bytes the unpacked tree will take.  gzip records the uncompressed size
+    /// in its trailer, for the other formats we go by typical compression ratios of PHP sources.
+    pub fn estimated_size(&self) -> Result<u64> {
+        let mut file = File::open(&self.src)?;
+        let size = file.metadata()?.len();
+
+        let ratio = match self.ext {
+            Extension::GZ if size >= 4 => {
+                // ISIZE is the input size modulo 2^32, which is fine for a PHP tarball
+                let mut isize = [0u8; 4];
+                file.seek(SeekFrom::End(-4))?;
+                file.read_exact(&mut isize)?;
+                return Ok(u64::from(u32::from_le_bytes(isize)));
+            }
+            Extension::GZ => 6,
+            Extension::BZ => 8,
+            Extension::XZ => 10,
+        };
+
+        Ok(size * ratio)
+    }
+
     fn clean_file_name(&self) -> Result<PathBuf> {
         let file = self
             .src
@@ -109,7 +471,7 @@ impl Tarball {
         if let Some((version, _)) = file.split_once(".tar") {
             Ok(PathBuf::from(version))
         } else {
-            anyhow::bail!("Unable to determine stem for '{}'", file);
+            anyhow::bail!("Unable to determine stem for '{file}'");
         }
     }
 
@@ -119,71 +481,799 @@ impl Tarball {
         full
     }
 
-    pub fn list(dir: &Path) -> Result<Vec<DownloadInfo>> {
-        let res: Vec<_> = std::fs::read_dir(dir)?
-            .filter_map(StdResult::ok)
-            .filter(|p| !p.path().is_dir())
-            .filter_map(|path| {
-                DownloadInfo::from_file(&path.path()).map_or_else(
-                    |_| {
-                        eprintln!("Can't parse file '{path:?}'");
-                        None
-                    },
-                    Some,
-                )
-            })
-            .collect();
+    /// The tarballs in `dir`.  Other processes may be downloading into or pruning it while we
+    /// look, so an entry that goes away is skipped and one we can't read becomes a warning
+    /// rather than failing the whole listing.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `dir` itself can't be read.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn list(dir: &Path) -> Result<Listing> {
+        let mut listing = Listing::default();
+
+        for entry in fs::read_dir(dir).context(format!("Unable to read {dir:?}"))? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    listing
+                        .warnings
+                        .push(format!("Unable to read {dir:?} ({e})"));
+                    continue;
+                }
+            };
+
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+
+            // Temporary files from `cache::write_atomic` and the like are dot files
+            if name.starts_with('.')
+                || name.ends_with(".sha256")
+                || name.ends_with(".asc")
+                || name.ends_with(".commit")
+                || entry.file_type().is_ok_and(|t| t.is_dir())
+            {
+                continue;
+            }
 
-        Ok(res)
+            match DownloadInfo::from_file(&path) {
+                Ok(info) => listing.tarballs.push(info),
+                Err(e)
+                    if e.downcast_ref::<io::Error>()
+                        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => {}
+                Err(e) => listing.warnings.push(format!("Skipping {path:?} ({e:#})")),
+            }
+        }
+
+        Ok(listing)
     }
 
-    fn build_dst_path(&self, dst_root: &Path, dst_leaf: Option<&Path>) -> Result<PathBuf> {
-        let default = self.clean_file_name()?;
+    fn build_dst_path(
+        version: Version,
+        dst_root: &Path,
+        dst_leaf: Option<&Path>,
+    ) -> Result<PathBuf> {
+        let default = PathBuf::from(dir_name(version, "")?);
         Ok(Self::full_path(dst_root, dst_leaf.unwrap_or(&default)))
     }
 
+    /// Resolve the directory we're extracting into.  If it is a symlink we either follow it to
+    /// its target or refuse, depending on `follow_symlinks`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the path can't be read, or it's a symlink and we were told not to follow it.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn resolve_dst_root(dst_root: &Path, follow_symlinks: bool) -> Result<PathBuf> {
+        let meta = fs::symlink_metadata(dst_root)
+            .with_context(|| format!("Unable to read destination {dst_root:?}"))?;
+
+        if !meta.file_type().is_symlink() {
+            return Ok(dst_root.to_path_buf());
+        }
+
+        if !follow_symlinks {
+            bail!("Destination {dst_root:?} is a symlink (use --follow-symlinks to extract into its target)");
+        }
+
+        let target = dst_root
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve symlink {dst_root:?}"))?;
+
+        eprintln!("Resolved symlink {dst_root:?} -> {target:?}");
+
+        Ok(target)
+    }
+
     pub fn check_dst_path(
-        &self,
+        version: Version,
         dst_root: &Path,
         dst_leaf: Option<&Path>,
     ) -> Result<Option<PathBuf>> {
-        let path = self.build_dst_path(dst_root, dst_leaf)?;
+        let path = Self::build_dst_path(version, dst_root, dst_leaf)?;
 
-        if path.exists() {
+        // Don't follow the leaf, a dangling symlink is still in the way.
+        if path.symlink_metadata().is_ok() {
             Ok(Some(path))
         } else {
             Ok(None)
         }
     }
 
-    pub fn extract(&self, dst_root: &Path, dst_leaf: Option<&Path>) -> Result<PathBuf> {
-        let file = File::open(&self.src)?;
-        let total_size = file.metadata()?.len();
+    // Files unpacked under the temp directory carry its SELinux label, so ask the policy for the
+    // right one once they've been moved into place.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn restore_security_context(path: &Path) {
+        if !Path::new("/sys/fs/selinux/enforce").exists() {
+            return;
+        }
 
-        let decoder: Box<dyn Read> = match self.ext {
-            Extension::GZ => Box::new(GzDecoder::new(file)),
-            Extension::BZ => Box::new(BzDecoder::new(file)),
-            Extension::XZ => Box::new(XzDecoder::new(file)),
-        };
+        let status = Command::new("restorecon")
+            .arg("-R")
+            .arg(path)
+            .stdout(Stdio::null())
+            .status();
+
+        if !status.is_ok_and(|s| s.success()) {
+            eprintln!("Warning:  Unable to restore SELinux contexts on {path:?}");
+        }
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn extract(
+        &self,
+        dst_root: &Path,
+        dst_leaf: Option<&Path>,
+        opts: UnpackOptions,
+    ) -> Result<PathBuf> {
+        let total_size = fs::metadata(&self.src)?.len();
+
+        let staging = self.staging_path()?;
+        let _lock = StagingLock::acquire(&staging)?.ok_or_else(|| {
+            anyhow!("{staging:?} is in use by another extraction of this tarball")
+        })?;
+
+        if !opts.resume {
+            Self::remove_staging(&staging)?;
+        }
+
+        // The marker stays behind if we're interrupted so the next run can pick up from here
+        fs::create_dir_all(&staging)?;
+        fs::write(
+            staging.join(Config::APP_PARTIAL_MARKER),
+            self.partial_marker()?,
+        )?;
 
-        let tmp = tempfile::tempdir()?;
         let def = self.clean_file_name()?;
-        let src = Self::full_path(tmp.path(), &def);
-        let dst = self.build_dst_path(dst_root, dst_leaf)?;
+        let src = Self::full_path(&staging, &def);
+        let dst = Self::build_dst_path(self.version, dst_root, dst_leaf)?;
+
+        let progress_bar = self.progress_bar(total_size, &dst)?;
+        let mut archive = Archive::new(self.decoder(opts.fast_decompress, progress_bar.clone())?);
+        archive.set_unpack_xattrs(opts.preserve_xattrs);
+        archive.set_preserve_mtime(!matches!(opts.mtime, MtimeMode::Now));
 
-        let reader = ProgressReader {
-            reader: decoder,
-            progress_bar: self.progress_spinner(total_size, &dst)?,
+        // Writers don't handle xattrs, so those still unpack one entry at a time
+        let skipped = if opts.preserve_xattrs {
+            Self::unpack(&mut archive, &staging, opts.resume)?
+        } else {
+            let mtime = !matches!(opts.mtime, MtimeMode::Now);
+            Self::unpack_parallel(&mut archive, &staging, opts.resume, mtime)?
         };
+        progress_bar.finish();
+        if skipped > 0 {
+            eprintln!("{}", msg!(Msg::SkippedPrevious, count = skipped));
+        }
 
-        let mut archive = Archive::new(reader);
-        archive.unpack(&tmp)?;
+        let src = Self::unpacked_root(&staging, src)?;
+        opts.mtime.apply(&src)?;
 
-        std::fs::rename(src, &dst)?;
-        eprintln!("Files extracted to '{}'", dst.display());
+        move_path(&src, &dst)?;
+        Self::remove_staging(&staging)?;
+        Self::restore_security_context(&dst);
+        eprintln!("{}", msg!(Msg::FilesExtracted, path = dst.display()));
 
         Ok(dst)
     }
+
+    // Release tarballs unpack into a directory named after the file, GitHub snapshots into
+    // `php-src-<branch>`, so failing the former take whichever single directory we got.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn unpacked_root(staging: &Path, expected: PathBuf) -> Result<PathBuf> {
+        if expected.is_dir() {
+            return Ok(expected);
+        }
+
+        let dirs: Vec<_> = fs::read_dir(staging)?
+            .filter_map(StdResult::ok)
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|e| e.path())
+            .collect();
+
+        match <[PathBuf; 1]>::try_from(dirs) {
+            Ok([dir]) => Ok(dir),
+            Err(_) => bail!("Expected the tarball to unpack into {expected:?}"),
+        }
+    }
+
+    fn external_decoder(&self) -> Option<(&'static str, &'static [&'static str])> {
+        FAST_DECODERS
+            .iter()
+            .filter(|(ext, ..)| *ext == self.ext)
+            .find_map(|(_, name, args)| Platform::which(name).map(|_| (*name, *args)))
+    }
+
+    /// A reader for the decompressed tarball.  With `fast` we use a multi-threaded external
+    /// decompressor when one is installed.  Either way `progress_bar` follows the compressed
+    /// bytes read, so it can show how far through the file we are.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn decoder(&self, fast: bool, progress_bar: ProgressBar) -> Result<Box<dyn Read>> {
+        let file = File::open(&self.src).context(format!("Unable to open {:?}", self.src))?;
+        let input = ProgressReader {
+            reader: file,
+            progress_bar,
+        };
+
+        if fast {
+            match self.external_decoder() {
+                Some((name, args)) => {
+                    return Ok(Box::new(ExternalDecoder::spawn(name, args, input)?))
+                }
+                None => eprintln!(
+                    "Warning:  No multi-threaded .{} decoder found, using the built-in one",
+                    self.ext
+                ),
+            }
+        }
+
+        Ok(match self.ext {
+            Extension::GZ => Box::new(GzDecoder::new(input)),
+            Extension::BZ => Box::new(BzDecoder::new(input)),
+            Extension::XZ => Box::new(XzDecoder::new(input)),
+        })
+    }
+
+    /// Decompress the whole tarball without unpacking it, returning the decompressed size and how
+    /// long it took.  Used by `bench-extract`.
+    pub fn bench_decode(&self, fast: bool) -> Result<(u64, Duration)> {
+        let start = Instant::now();
+        let bytes = io::copy(
+            &mut self.decoder(fast, ProgressBar::hidden())?,
+            &mut io::sink(),
+        )?;
+        Ok((bytes, start.elapsed()))
+    }
+
+    /// Whether `--fast-decompress` has an external decoder to use for this tarball.
+    pub fn has_fast_decoder(&self) -> bool {
+        self.external_decoder().is_some()
+    }
+
+    fn staging_path(&self) -> Result<PathBuf> {
+        let file = self
+            .src
+            .file_name()
+            .ok_or_else(|| anyhow!("Can't get filename"))?
+            .to_string_lossy();
+
+        Ok(Config::staging_path()?.join(file.as_ref()))
+    }
+
+    // Identifies the tarball a partial extraction came from, so we never resume from a
+    // different archive that happens to have the same name.
+    fn partial_marker(&self) -> Result<String> {
+        let meta = fs::metadata(&self.src)?;
+        let mtime = FileTime::from_last_modification_time(&meta);
+
+        Ok(format!(
+            "{}\n{}\n{}\n",
+            self.src.display(),
+            meta.len(),
+            mtime.unix_seconds()
+        ))
+    }
+
+    /// If a previous extraction of this tarball was interrupted, return where it left off.
+    ///
+    /// # Errors
+    ///
+    /// Fails if we find a stale staging directory and can't remove it.  A directory another run is
+    /// still working in is left alone and not offered for resuming.
+    pub fn partial_extraction(&self) -> Result<Option<PathBuf>> {
+        let staging = self.staging_path()?;
+        let Some(_lock) = StagingLock::acquire(&staging)? else {
+            return Ok(None);
+        };
+
+        match fs::read_to_string(staging.join(Config::APP_PARTIAL_MARKER)) {
+            Ok(marker) if marker == self.partial_marker()? => Ok(Some(staging)),
+            _ => {
+                Self::remove_staging(&staging)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Only call this holding the staging directory's lock
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn remove_staging(staging: &Path) -> Result<()> {
+        if staging.symlink_metadata().is_ok() {
+            fs::remove_dir_all(staging)
+                .context(format!("Unable to remove staging directory {staging:?}"))?;
+        }
+
+        Ok(())
+    }
+
+    // A file left by an interrupted run only counts if it's complete, which tar signals by
+    // setting the mtime after writing the contents.
+    fn already_unpacked<R: Read>(entry: &Entry<R>, dst: &Path) -> bool {
+        let Ok(path) = entry.path() else {
+            return false;
+        };
+
+        let Ok(meta) = dst.join(path).symlink_metadata() else {
+            return false;
+        };
+
+        let header = entry.header();
+        let mtime = FileTime::from_last_modification_time(&meta).unix_seconds();
+
+        meta.is_file()
+            && header.size().is_ok_and(|size| size == meta.len())
+            && header
+                .mtime()
+                .is_ok_and(|m| i64::try_from(m).is_ok_and(|m| m == mtime))
+    }
+
+    // Equivalent to `Archive::unpack` but able to skip entries a previous run already wrote.
+    // Returns the number of entries skipped.
+    fn unpack<R: Read>(archive: &mut Archive<R>, dst: &Path, resume: bool) -> Result<u64> {
+        let mut skipped = 0;
+        let mut directories = vec![];
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+
+            // Directories go last so their permissions can't get in the way
+            if entry.header().entry_type().is_dir() {
+                directories.push(entry);
+            } else if resume && Self::already_unpacked(&entry, dst) {
+                skipped += 1;
+            } else {
+                entry.unpack_in(dst)?;
+            }
+        }
+
+        directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+        for mut dir in directories {
+            dir.unpack_in(dst)?;
+        }
+
+        Ok(skipped)
+    }
+
+    // Decoding is inherently serial but writing ~120k small files isn't, so regular files are
+    // read here and handed to a few writer threads.  Everything else is unpacked by tar on this
+    // thread: symlinks inline, hard links once their targets are written, directories last.
+    // Each path always goes to the same writer, so when an archive has several entries for one
+    // path they're written in order and the last one wins, as with tar.
+    fn unpack_parallel<R: Read>(
+        archive: &mut Archive<R>,
+        dst: &Path,
+        resume: bool,
+        preserve_mtime: bool,
+    ) -> Result<u64> {
+        let root = dst.canonicalize()?;
+        let count = writer_count();
+        let (tx, rxs): (Vec<_>, Vec<_>) = (0..count)
+            .map(|_| mpsc::sync_channel::<PendingFile>(WRITER_QUEUE.div_ceil(count)))
+            .unzip();
+
+        thread::scope(|s| {
+            let root = &root;
+            let writers: Vec<_> = rxs
+                .into_iter()
+                .map(|rx| {
+                    s.spawn(move || -> Result<()> {
+                        for file in rx {
+                            file.write(root)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+
+            let res = Self::dispatch_entries(archive, dst, root, resume, preserve_mtime, &tx);
+            // Closing the channel is what lets the writers finish
+            drop(tx);
+
+            // A writer's error explains why sending failed, so it wins
+            for writer in writers {
+                writer
+                    .join()
+                    .map_err(|_| anyhow!("File writer panicked"))??;
+            }
+            let mut deferred = res?;
+
+            for link in &mut deferred.links {
+                link.unpack_in(dst)?;
+            }
+
+            deferred
+                .directories
+                .sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+            for dir in &mut deferred.directories {
+                dir.unpack_in(dst)?;
+            }
+
+            Ok(deferred.skipped)
+        })
+    }
+
+    // Sends regular files to the writers and returns what has to wait for them
+    fn dispatch_entries<'a, R: Read>(
+        archive: &'a mut Archive<R>,
+        dst: &Path,
+        root: &Path,
+        resume: bool,
+        preserve_mtime: bool,
+        tx: &[SyncSender<PendingFile>],
+    ) -> Result<Deferred<'a, R>> {
+        let mut deferred = Deferred {
+            links: vec![],
+            directories: vec![],
+            skipped: 0,
+        };
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let kind = entry.header().entry_type();
+
+            if kind.is_dir() {
+                deferred.directories.push(entry);
+            } else if resume && Self::already_unpacked(&entry, dst) {
+                deferred.skipped += 1;
+            } else if kind.is_file() {
+                if let Some(file) = PendingFile::read(&mut entry, root, preserve_mtime)? {
+                    tx[file.writer(tx.len())]
+                        .send(file)
+                        .map_err(|_| anyhow!("File writers stopped"))?;
+                }
+            } else if kind.is_hard_link() {
+                deferred.links.push(entry);
+            } else {
+                entry.unpack_in(dst)?;
+            }
+        }
+
+        Ok(deferred)
+    }
+}
+
+fn writer_count() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get().clamp(1, MAX_WRITERS))
+}
+
+/// Where an archive path lands under `root`, or `None` if it would escape it.  Mirrors the
+/// checks `tar::Entry::unpack_in` makes.
+fn entry_dst(root: &Path, path: &Path) -> Option<PathBuf> {
+    let mut dst = root.to_path_buf();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => dst.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    (dst != root).then_some(dst)
+}
+
+impl PendingFile {
+    fn read<R: Read>(
+        entry: &mut Entry<R>,
+        root: &Path,
+        preserve_mtime: bool,
+    ) -> Result<Option<Self>> {
+        let Some(path) = entry_dst(root, &entry.path()?) else {
+            return Ok(None);
+        };
+
+        let header = entry.header();
+        let mode = header.mode()? & 0o777;
+        // Like tar, never set a zero mtime since some tools treat it as missing
+        let mtime = preserve_mtime
+            .then(|| header.mtime().ok())
+            .flatten()
+            .map(|m| FileTime::from_unix_time(i64::try_from(m.max(1)).unwrap_or(1), 0));
+
+        let mut data = Vec::with_capacity(usize::try_from(entry.size()).unwrap_or(0));
+        entry.read_to_end(&mut data)?;
+
+        Ok(Some(Self {
+            path,
+            data,
+            mode,
+            mtime,
+        }))
+    }
+
+    // Which of `writers` gets this file, the same one for every entry with its path
+    fn writer(&self, writers: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        self.path.hash(&mut hasher);
+
+        usize::try_from(hasher.finish() % writers as u64).unwrap_or(0)
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn write(self, root: &Path) -> Result<()> {
+        let parent = self.path.parent().unwrap_or(root);
+
+        // An earlier symlink entry could point a directory somewhere else entirely, so check
+        // where the part that exists already leads before creating anything below it.  Checked
+        // again once the rest exists in case a symlink was unpacked meanwhile.
+        let existing = parent
+            .ancestors()
+            .find(|dir| dir.symlink_metadata().is_ok())
+            .unwrap_or(root);
+        if !existing.canonicalize()?.starts_with(root) {
+            bail!("Refusing to write {:?} outside of {root:?}", self.path);
+        }
+
+        fs::create_dir_all(parent).context(format!("Unable to create directory {parent:?}"))?;
+
+        if !parent.canonicalize()?.starts_with(root) {
+            bail!("Refusing to write {:?} outside of {root:?}", self.path);
+        }
+
+        if self.path.symlink_metadata().is_ok() {
+            fs::remove_file(&self.path)?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.path)
+            .context(format!("Unable to create {:?}", self.path))?;
+
+        file.write_all(&self.data)?;
+        file.set_permissions(fs::Permissions::from_mode(self.mode))?;
+
+        // Set last, `already_unpacked` relies on the mtime meaning the contents are complete
+        if let Some(mtime) = self.mtime {
+            filetime::set_file_handle_times(&file, Some(mtime), Some(mtime))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The directory name a build tree for `version` is extracted to.  Defaults to
+/// `php-VERSION[-MODIFIERS]` unless a `dir-name` template is configured.
+///
+/// # Errors
+///
+/// Fails if the configured template can't be expanded, doesn't tell patch releases apart or
+/// doesn't expand to a single path component.
+#[allow(clippy::literal_string_with_formatting_args)]
+pub fn dir_name(version: Version, modifiers: &str) -> Result<String> {
+    let Some(tmpl) = &Config::settings().dir_name else {
+        return Ok(if modifiers.is_empty() {
+            format!("php-{version}")
+        } else {
+            format!("php-{version}-{modifiers}")
+        });
+    };
+
+    // Otherwise an upgrade would land on the root it's replacing
+    if !tmpl.contains("{version}") && !tmpl.contains("{patch}") {
+        bail!("The dir-name template {tmpl:?} must use {{version}} or {{patch}}");
+    }
+
+    let name = Template::for_version(version)
+        .set("modifiers", modifiers)
+        .expand(tmpl)?;
+
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        bail!("The dir-name template {tmpl:?} expands to {name:?}, which isn't a directory name");
+    }
+
+    Ok(name)
+}
+
+/// An exclusive claim on a staging directory, held on `<dir>.lock` next to it and released when
+/// dropped.  It's an advisory `flock`, so it also goes away if the holder dies.
+struct StagingLock {
+    _file: File,
+}
+
+impl StagingLock {
+    /// Take the lock, or `None` if another process has it.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn acquire(staging: &Path) -> Result<Option<Self>> {
+        let mut path = staging.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .context(format!("Unable to open lock file {path:?}"))?;
+
+        // SAFETY: the descriptor is valid for as long as `file` lives
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(None);
+            }
+            return Err(err).context(format!("Unable to lock {path:?}"));
+        }
+
+        Ok(Some(Self { _file: file }))
+    }
+}
+
+/// Remove a staging directory unless an extraction is still using it, returning whether it was
+/// removed.
+///
+/// # Errors
+///
+/// Fails if the lock can't be taken or the directory can't be removed.
+pub fn remove_staging_dir(staging: &Path) -> Result<bool> {
+    let Some(_lock) = StagingLock::acquire(staging)? else {
+        return Ok(false);
+    };
+
+    Tarball::remove_staging(staging)?;
+    Ok(true)
+}
+
+/// The interrupted extractions waiting in the staging directory, leaving out the ones another
+/// run is working in right now.
+///
+/// # Errors
+///
+/// Fails if the staging directory can't be read.
+pub fn idle_staging_dirs() -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+
+    for entry in fs::read_dir(Config::staging_path()?)? {
+        let path = entry?.path();
+        if path.is_dir() && StagingLock::acquire(&path)?.is_some() {
+            dirs.push(path);
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// Total size of the files under `path`.
+pub fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(StdResult::ok)
+        .filter_map(|e| e.path().symlink_metadata().ok())
+        .filter(std::fs::Metadata::is_file)
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Whether `a` and `b` are on the same filesystem, so one can be renamed onto the other.  Paths
+/// that don't exist yet are judged by their nearest existing ancestor.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn same_device(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = |path: &Path| {
+        path.ancestors()
+            .find_map(|p| fs::metadata(p).ok())
+            .map(|m| m.dev())
+            .context(format!("Unable to stat {path:?}"))
+    };
+
+    Ok(dev(a)? == dev(b)?)
+}
+
+// Copy one entry of a tree being moved, file contents are synced before we move on
+fn copy_entry(src: &Path, dst: &Path, meta: &fs::Metadata, pb: &ProgressBar) -> Result<()> {
+    let file_type = meta.file_type();
+
+    if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(src)?, dst)?;
+        return Ok(());
+    }
+
+    if file_type.is_dir() {
+        fs::create_dir(dst)?;
+    } else {
+        let mut out = File::create(dst)?;
+        let copied = io::copy(&mut File::open(src)?, &mut out)?;
+        out.sync_all()?;
+        pb.inc(copied);
+    }
+
+    fs::set_permissions(dst, meta.permissions())?;
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+fn copy_tree(src: &Path, dst: &Path, pb: &ProgressBar) -> Result<()> {
+    let mut dirs = vec![];
+
+    for entry in WalkDir::new(src) {
+        let entry = entry?;
+        let meta = entry.path().symlink_metadata()?;
+        // Joining the empty path of the root would add a trailing slash, wrong for a single file
+        let target = match entry.path().strip_prefix(src)? {
+            rel if rel.as_os_str().is_empty() => dst.to_path_buf(),
+            rel => dst.join(rel),
+        };
+
+        copy_entry(entry.path(), &target, &meta, pb)
+            .context(format!("Unable to copy {:?} to {target:?}", entry.path()))?;
+
+        let mtime = FileTime::from_last_modification_time(&meta);
+        if meta.is_dir() {
+            dirs.push((target, mtime));
+        } else if !meta.file_type().is_symlink() {
+            filetime::set_file_mtime(&target, mtime)?;
+        }
+    }
+
+    // Directory mtimes change as we fill them, so they go last, deepest first
+    for (dir, mtime) in dirs.iter().rev() {
+        filetime::set_file_mtime(dir, *mtime)?;
+    }
+
+    Ok(())
+}
+
+/// Move the file or tree at `src` to `dst`.  On the same filesystem that's a rename, across
+/// devices (e.g. a tmpfs `/tmp` or a bind mount) we copy next to `dst` with progress, fsync, rename
+/// into place and only then remove `src`, so `dst` never holds a partial copy.
+///
+/// # Errors
+///
+/// Fails if anything can't be copied or renamed.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn move_path(src: &Path, dst: &Path) -> Result<()> {
+    let parent = dst
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    if same_device(src, parent)? {
+        return fs::rename(src, dst).context(format!("Unable to move {src:?} to {dst:?}"));
+    }
+
+    let size = dir_size(src).max(src.symlink_metadata()?.len());
+    let pb = progress::bar(
+        size,
+        "{msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes}",
+    )?;
+    pb.set_message(format!("Copying to {}", parent.display()));
+
+    let staging = tempfile::Builder::new()
+        .prefix(".phpdownloader-")
+        .tempdir_in(parent)?;
+    let copy = staging.path().join("copy");
+
+    copy_tree(src, &copy, &pb)?;
+    fs::rename(&copy, dst).context(format!("Unable to move {copy:?} to {dst:?}"))?;
+    File::open(parent)?.sync_all()?;
+    pb.finish_and_clear();
+
+    if src.is_dir() {
+        fs::remove_dir_all(src)?;
+    } else {
+        fs::remove_file(src)?;
+    }
+
+    Ok(())
+}
+
+/// Bytes available to an unprivileged user on the filesystem holding `path`.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn available_space(path: &Path) -> Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `c_path` is a valid C string and `stat` is only read if the call succeeds
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error())
+                .context(format!("Unable to stat filesystem for {path:?}"));
+        }
+        stat.assume_init()
+    };
+
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
 }
 
 impl From<&DownloadInfo> for Tarball {
@@ -191,6 +1281,7 @@ impl From<&DownloadInfo> for Tarball {
         Self {
             src: PathBuf::from(&src.location),
             ext: src.extension,
+            version: src.version,
         }
     }
 }
@@ -200,33 +1291,70 @@ impl From<DownloadInfo> for Tarball {
         Self {
             src: PathBuf::from(src.location),
             ext: src.extension,
+            version: src.version,
         }
     }
 }
 
-impl<R: Read> Read for ProgressReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let bytes = self.reader.read(buf)?;
-        self.progress_bar.tick();
-        Ok(bytes)
+impl ExternalDecoder {
+    // We feed the decoder ourselves rather than naming the file, so progress can follow the input
+    fn spawn<R: Read + Send + 'static>(
+        name: &'static str,
+        args: &[&str],
+        mut input: R,
+    ) -> Result<Self> {
+        let mut child = Command::new(name)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context(format!("Unable to run {name}"))?;
+
+        let mut stdin = child.stdin.take().context("No stdin for decoder")?;
+        let stdout = child.stdout.take().context("No stdout from decoder")?;
+
+        // A write error just means the decoder exited early, which its status reports
+        thread::spawn(move || {
+            let _ = io::copy(&mut input, &mut stdin);
+        });
+
+        Ok(Self {
+            name,
+            child,
+            stdout,
+        })
     }
 }
 
-impl<W: Write> Write for ProgressWriter<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let bytes = self.writer.write(buf)?;
-        self.bytes_written += bytes;
-        self.progress_bar.set_message(format!(
-            "Writing {} ({})",
-            self.name,
-            (self.bytes_written as u64).to_human_size()
-        ));
+impl Read for ExternalDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.stdout.read(buf)?;
+
+        if bytes == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("{} failed ({status})", self.name)));
+            }
+        }
 
         Ok(bytes)
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+impl Drop for ExternalDecoder {
+    fn drop(&mut self) {
+        if matches!(self.child.try_wait(), Ok(None)) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.reader.read(buf)?;
+        self.progress_bar.inc(bytes as u64);
+        Ok(bytes)
     }
 }
 
@@ -250,6 +1378,13 @@ impl BuildRoot {
     }
 
     pub fn save_manifest(&self) -> Result<(PathBuf, u64)> {
+        self.save_manifest_without(&HashSet::new())
+    }
+
+    // Every file in the tree except `skip`, relative to the root.  Regular files are followed by
+    // a tab and their SHA-256, except our own bookkeeping files which change as we go.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn save_manifest_without(&self, skip: &HashSet<PathBuf>) -> Result<(PathBuf, u64)> {
         let mut dst = self.src.clone();
         dst.push(Config::APP_MANIFEST_FILE);
 
@@ -265,31 +1400,76 @@ impl BuildRoot {
                 let suffix = entry
                     .path()
                     .strip_prefix(&self.src)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
-                    .to_string_lossy()
-                    .into_owned();
+                    .map_err(io::Error::other)?;
+                if skip.contains(suffix) {
+                    return Ok(());
+                }
                 files += 1;
-                writeln!(file, "{suffix}")
+
+                let ours = suffix.to_string_lossy().starts_with(".phpdownloader-");
+                if entry.file_type().is_file() && !ours {
+                    let hash = downloads::sha256_file(entry.path()).map_err(io::Error::other)?;
+                    writeln!(file, "{}\t{hash}", suffix.to_string_lossy())
+                } else {
+                    writeln!(file, "{}", suffix.to_string_lossy())
+                }
             })?;
 
         Ok((dst, files))
     }
 
-    fn load_manifest(&self) -> Result<HashSet<PathBuf>> {
+    /// Files in the tree the manifest doesn't list, i.e. ones the user added since extracting.
+    ///
+    /// # Errors
+    ///
+    /// Fails if there's no manifest to compare against.
+    pub fn unlisted_files(&self) -> Result<HashSet<PathBuf>> {
+        let listed = self.load_manifest()?;
+
+        Ok(WalkDir::new(&self.src)
+            .into_iter()
+            .filter_map(StdResult::ok)
+            .filter(|e| !e.path().is_dir())
+            .filter_map(|e| e.path().strip_prefix(&self.src).ok().map(Path::to_path_buf))
+            .filter(|path| !listed.contains_key(path))
+            .collect())
+    }
+
+    /// Rewrite the manifest after a rebuild, taking in what the build produced but still leaving
+    /// out the files that were `unlisted` before it, so `upgrade` carries them over.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the manifest can't be written.
+    pub fn update_manifest(&self, unlisted: &HashSet<PathBuf>) -> Result<(PathBuf, u64)> {
+        self.save_manifest_without(unlisted)
+    }
+
+    // The listed files and their hashes, which older manifests don't have
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn load_manifest(&self) -> Result<HashMap<PathBuf, Option<String>>> {
         let mut src = self.src.clone();
         src.push(Config::APP_MANIFEST_FILE);
 
         let file = File::open(&src).context(format!("Failed to open file {src:?}"))?;
         let reader = BufReader::new(file);
-        let set = reader
+        let files = reader
             .lines()
             .map_while(StdResult::ok)
-            .map(PathBuf::from)
+            .map(|line| match line.rsplit_once('\t') {
+                Some((path, hash))
+                    if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) =>
+                {
+                    (PathBuf::from(path), Some(hash.to_string()))
+                }
+                _ => (PathBuf::from(line), None),
+            })
             .collect();
 
-        Ok(set)
+        Ok(files)
     }
 
+    #[allow(clippy::unnecessary_debug_formatting)]
     pub fn save_scripts<P: AsRef<Path>>(&self, dst_path: P) -> Result<u64> {
         let mut files: u64 = 0;
 
@@ -297,7 +1477,7 @@ impl BuildRoot {
 
         fs::create_dir_all(dst_path.as_ref())?;
 
-        let pb = ProgressBar::new_spinner();
+        let pb = progress::spinner();
 
         for entry in WalkDir::new(&self.src)
             .into_iter()
@@ -307,7 +1487,7 @@ impl BuildRoot {
             let path = entry.path();
             let rel_path = path.strip_prefix(&self.src)?;
 
-            if !set.contains(rel_path) {
+            if !set.contains_key(rel_path) {
                 let dst_file_path = dst_path.as_ref().join(rel_path);
                 if let Some(parent) = dst_file_path.parent() {
                     fs::create_dir_all(parent)?;
@@ -328,13 +1508,198 @@ impl BuildRoot {
         Ok(files)
     }
 
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn save_source(&self, info: &SourceInfo) -> Result<PathBuf> {
+        let dst = self.src.join(Config::APP_SOURCE_FILE);
+        let data = serde_json::to_string_pretty(info)?;
+
+        cache::write_atomic(&dst, data.as_bytes()).context(format!("Failed to write {dst:?}"))?;
+
+        Ok(dst)
+    }
+
+    fn recorded_backups(&self) -> Vec<ScriptBackup> {
+        fs::read_to_string(self.src.join(Config::APP_BACKUPS_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default()
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn save_backups(&self, backups: &[ScriptBackup]) -> Result<()> {
+        let dst = self.src.join(Config::APP_BACKUPS_FILE);
+        let data = serde_json::to_string_pretty(backups)?;
+
+        cache::write_atomic(&dst, data.as_bytes()).context(format!("Failed to write {dst:?}"))
+    }
+
+    /// Record that `dir` holds the scripts of `from`, which this root replaced.  Backups `from`
+    /// was carrying were copied along, so they're recorded under `dir`.
+    pub fn record_backup(&self, dir: &Path, from: &Self) -> Result<()> {
+        let dir = dir.strip_prefix(&self.src).unwrap_or(dir);
+
+        let mut backups = vec![ScriptBackup {
+            path: dir.to_path_buf(),
+            version: Some(from.version),
+            created: chrono::Utc::now().timestamp(),
+        }];
+
+        backups.extend(from.recorded_backups().into_iter().map(|b| ScriptBackup {
+            path: dir.join(b.path),
+            ..b
+        }));
+
+        self.save_backups(&backups)
+    }
+
+    /// Scripts backups in this root, most recent generation first.  Backups made before we
+    /// recorded them are found by name and dated by their mtime.
+    pub fn backups(&self) -> Vec<ScriptBackup> {
+        let mut backups: Vec<_> = self
+            .recorded_backups()
+            .into_iter()
+            .filter(|b| self.src.join(&b.path).is_dir())
+            .collect();
+
+        let found: Vec<_> = WalkDir::new(&self.src)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(StdResult::ok)
+            .filter(|e| e.file_type().is_dir())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(BACKUP_SUFFIX))
+            .filter_map(|e| {
+                let path = e.path().strip_prefix(&self.src).ok()?.to_path_buf();
+                let created = e.metadata().ok().map_or(0, |m| {
+                    FileTime::from_last_modification_time(&m).unix_seconds()
+                });
+
+                Some(ScriptBackup {
+                    version: e
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| Self::parse_path_info(name).ok())
+                        .map(|(version, _)| version),
+                    path,
+                    created,
+                })
+            })
+            .collect();
+
+        for backup in found {
+            if !backups.iter().any(|b| b.path == backup.path) {
+                backups.push(backup);
+            }
+        }
+
+        backups.sort_by(|a, b| (a.generation(), &a.path).cmp(&(b.generation(), &b.path)));
+        backups
+    }
+
+    /// Delete a scripts backup, along with any older ones nested inside it.
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn remove_backup(&self, backup: &ScriptBackup) -> Result<()> {
+        let dir = self.src.join(&backup.path);
+        fs::remove_dir_all(&dir).context(format!("Unable to remove {dir:?}"))?;
+
+        let backups: Vec<_> = self
+            .recorded_backups()
+            .into_iter()
+            .filter(|b| !b.path.starts_with(&backup.path))
+            .collect();
+
+        self.save_backups(&backups)
+    }
+
+    /// Pinned roots are left alone by `upgrade`.
+    pub fn is_pinned(&self) -> bool {
+        self.src.join(Config::APP_PIN_FILE).exists()
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn pin(&self) -> Result<()> {
+        let dst = self.src.join(Config::APP_PIN_FILE);
+        File::create(&dst).context(format!("Failed to create {dst:?}"))?;
+        Ok(())
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    pub fn unpin(&self) -> Result<()> {
+        let dst = self.src.join(Config::APP_PIN_FILE);
+        match fs::remove_file(&dst) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).context(format!("Failed to remove {dst:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check every file recorded in the manifest is still there, and unchanged where we recorded
+    /// its hash.
+    pub fn verify(&self) -> (Verdict, String) {
+        let Ok(files) = self.load_manifest() else {
+            return (Verdict::Unchecked, "no manifest".into());
+        };
+
+        let mut missing = vec![];
+        let mut changed = vec![];
+        for (file, hash) in &files {
+            let path = self.src.join(file);
+            if path.symlink_metadata().is_err() {
+                missing.push(file);
+            } else if hash.as_ref().is_some_and(|hash| {
+                downloads::sha256_file(&path).map_or(true, |actual| actual != *hash)
+            }) {
+                changed.push(file);
+            }
+        }
+        missing.sort();
+        changed.sort();
+
+        if let Some(first) = missing.first() {
+            return (
+                Verdict::Failed,
+                format!(
+                    "{} of {} files missing, e.g. {}",
+                    missing.len(),
+                    files.len(),
+                    first.display()
+                ),
+            );
+        }
+
+        let Some(first) = changed.first() else {
+            return (Verdict::Ok, format!("{} files intact", files.len()));
+        };
+
+        (
+            Verdict::Failed,
+            format!(
+                "{} of {} files changed, e.g. {}",
+                changed.len(),
+                files.len(),
+                first.display()
+            ),
+        )
+    }
+
+    /// The tarball this tree was extracted from, if we recorded one.
+    pub fn source(&self) -> Option<SourceInfo> {
+        Self::read_source(&self.src)
+    }
+
+    fn read_source(path: &Path) -> Option<SourceInfo> {
+        let data = fs::read_to_string(path.join(Config::APP_SOURCE_FILE)).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
     pub fn remove(self) -> Result<()> {
         fs::remove_dir_all(self.src)?;
         Ok(())
     }
 
+    // A snapshot's `-dev` is part of its version rather than a modifier
     fn parse_path_info(dir: &str) -> Result<(Version, &str)> {
-        let re = Regex::new(r"php-([0-9]\.[0-9]\.[0-9|a-z|A-Z]+)\-?(.*)")?;
+        let re = Regex::new(r"^php-([0-9]\.[0-9]\.[0-9|a-z|A-Z]+(?:-dev\b)?)\-?(.*)")?;
 
         if let Some(caps) = re.captures(dir) {
             let version = caps
@@ -351,12 +1716,8 @@ impl BuildRoot {
         }
     }
 
-    pub fn version_path_name(&self, version: Version) -> String {
-        if self.modifiers.is_empty() {
-            format!("php-{version}")
-        } else {
-            format!("php-{version}-{}", self.modifiers)
-        }
+    pub fn version_path_name(&self, version: Version) -> Result<String> {
+        dir_name(version, &self.modifiers)
     }
 
     pub fn new<P: AsRef<Path>>(path: P, version: Version, modifiers: &str) -> Self {
@@ -367,21 +1728,63 @@ impl BuildRoot {
         }
     }
 
-    pub fn from_parent_path<P: AsRef<Path>>(path: P) -> Result<Vec<Self>> {
-        let entries = fs::read_dir(&path)
-            .with_context(|| format!("Failed to read directory {:?}", &path.as_ref()))?
+    /// Find build roots below `path`, descending at most `depth` directories.  Directories that
+    /// are themselves build roots aren't descended into, and each directory is only visited once
+    /// so symlink loops can't recurse forever.
+    pub fn from_parent_path<P: AsRef<Path>>(path: P, depth: usize) -> Result<Vec<Self>> {
+        let mut roots = vec![];
+        let mut seen = HashSet::new();
+
+        Self::scan_dir(path.as_ref(), depth, &mut seen, &mut roots)?;
+
+        Ok(roots)
+    }
+
+    #[allow(clippy::unnecessary_debug_formatting)]
+    fn scan_dir(
+        path: &Path,
+        depth: usize,
+        seen: &mut HashSet<PathBuf>,
+        roots: &mut Vec<Self>,
+    ) -> Result<()> {
+        if depth == 0 || !seen.insert(path.canonicalize()?) {
+            return Ok(());
+        }
+
+        let ignore = IgnoreList::load(path)?;
+
+        let entries = fs::read_dir(path)
+            .with_context(|| format!("Failed to read directory {path:?}"))?
             .filter_map(StdResult::ok)
-            .filter(|entry| entry.path().is_dir())
-            .filter_map(|entry| {
-                let path_str = entry.path().to_string_lossy().into_owned();
-                match Self::parse_path_info(&path_str) {
-                    Ok((version, modifiers)) => Some(Self::new(entry.path(), version, modifiers)),
-                    _ => None,
-                }
-            })
-            .collect();
+            .filter(|entry| entry.path().is_dir());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if ignore.is_ignored(&name) {
+                continue;
+            }
+
+            if let Ok(root) = Self::from_path(entry.path()) {
+                roots.push(root);
+            } else if let Err(e) = Self::scan_dir(&entry.path(), depth - 1, seen, roots) {
+                eprintln!("Warning:  Skipping {:?} ({e})", entry.path());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Treat `path` as a build root, or failing that, as a directory containing build roots.
+    pub fn discover<P: AsRef<Path>>(path: P, depth: usize) -> Result<Vec<Self>> {
+        Self::from_path(&path).map_or_else(
+            |_| Self::from_parent_path(path, depth),
+            |root| Ok(vec![root]),
+        )
+    }
 
-        Ok(entries)
+    /// Total size of the files in the tree.
+    pub fn size(&self) -> u64 {
+        dir_size(&self.src)
     }
 
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
@@ -392,7 +1795,200 @@ impl BuildRoot {
             .to_str()
             .ok_or_else(|| anyhow!("Filename is not a valid UTF-8 string"))?;
 
-        let (version, modifiers) = Self::parse_path_info(root)?;
-        Ok(Self::new(path.as_ref(), version, modifiers))
+        if let Ok((version, modifiers)) = Self::parse_path_info(root) {
+            return Ok(Self::new(path.as_ref(), version, modifiers));
+        }
+
+        // Named by a `dir-name` template, so only what we recorded says what it is
+        let info = Self::read_source(path.as_ref())
+            .and_then(|s| Some((s.version?, s.modifiers.unwrap_or_default())));
+
+        match info {
+            Some((version, modifiers)) => Ok(Self::new(path.as_ref(), version, &modifiers)),
+            None => bail!("Failed to parse '{root}' for version information"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_globs() {
+        let ignore = IgnoreList::from_globs(["# comment", "", "php-faq-*", "php-8.?.0/", "*.bak"])
+            .expect("Can't parse globs");
+
+        assert!(ignore.is_ignored("php-faq-8.1.0-notes"));
+        assert!(ignore.is_ignored("php-8.2.0"));
+        assert!(ignore.is_ignored("php-8.3.1.bak"));
+        assert!(!ignore.is_ignored("php-8.3.1"));
+        assert!(!ignore.is_ignored("php-8.10.0"));
+    }
+
+    #[test]
+    fn parse_build_root_names() {
+        let (version, modifiers) =
+            BuildRoot::parse_path_info("php-8.3.1-debug").expect("Can't parse path");
+        assert_eq!(version, Version::from_major_minor_patch(8, 3, 1));
+        assert_eq!(modifiers, "debug");
+
+        assert!(BuildRoot::parse_path_info("notphp-8.3.1").is_err());
+    }
+
+    #[test]
+    fn parse_snapshot_root_names() {
+        let snapshot = Version::new(8, 5, Some(0), Some(downloads::VersionModifier::Dev));
+
+        let (version, modifiers) =
+            BuildRoot::parse_path_info("php-8.5.0-dev").expect("Can't parse snapshot path");
+        assert_eq!(version, snapshot);
+        assert_eq!(modifiers, "");
+
+        let (version, modifiers) = BuildRoot::parse_path_info("php-8.5.0-dev-debug")
+            .expect("Can't parse snapshot path with modifiers");
+        assert_eq!(version, snapshot);
+        assert_eq!(modifiers, "debug");
+
+        let (version, modifiers) =
+            BuildRoot::parse_path_info("php-8.5.0-devel").expect("Can't parse path");
+        assert_eq!(version, Version::from_major_minor_patch(8, 5, 0));
+        assert_eq!(modifiers, "devel");
+    }
+
+    #[test]
+    fn rebuild_keeps_user_files_out_of_the_manifest() {
+        let dir = tempfile::tempdir().expect("Can't create build root");
+        let root = BuildRoot::new(dir.path(), Version::from_major_minor_patch(8, 3, 1), "");
+        fs::write(dir.path().join("configure"), "").expect("Can't write file");
+        root.save_manifest().expect("Can't save manifest");
+
+        fs::write(dir.path().join("build.sh"), "").expect("Can't write file");
+        let unlisted = root.unlisted_files().expect("Can't read manifest");
+
+        // What a rebuild writes is part of the tree from then on
+        fs::write(dir.path().join("Makefile"), "").expect("Can't write file");
+        root.update_manifest(&unlisted)
+            .expect("Can't update manifest");
+
+        let unlisted = root.unlisted_files().expect("Can't read manifest");
+        assert_eq!(unlisted, HashSet::from([PathBuf::from("build.sh")]));
+    }
+
+    #[test]
+    fn verify_notices_changed_files() {
+        let dir = tempfile::tempdir().expect("Can't create build root");
+        let root = BuildRoot::new(dir.path(), Version::from_major_minor_patch(8, 3, 1), "");
+        fs::write(dir.path().join("main.c"), "int main;").expect("Can't write file");
+        root.save_manifest().expect("Can't save manifest");
+        assert_eq!(root.verify().0, Verdict::Ok);
+
+        fs::write(dir.path().join("main.c"), "int main();").expect("Can't write file");
+        let (verdict, detail) = root.verify();
+        assert_eq!(verdict, Verdict::Failed);
+        assert!(detail.contains("changed"), "{detail}");
+    }
+
+    fn tarball_with(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(1_700_000_000);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, *data)
+                .expect("Can't append to tarball");
+        }
+        builder.into_inner().expect("Can't finish tarball")
+    }
+
+    #[test]
+    fn resume_skips_finished_files() {
+        let staging = tempfile::tempdir().expect("Can't create staging directory");
+        let data = tarball_with(&[("php-8.3.1/a.c", b"a"), ("php-8.3.1/b.c", b"b")]);
+
+        Tarball::unpack(&mut Archive::new(&data[..]), staging.path(), false).expect("Can't unpack");
+
+        // An interrupted write leaves the file with the wrong mtime
+        let partial = staging.path().join("php-8.3.1/b.c");
+        fs::write(&partial, "").expect("Can't truncate file");
+        filetime::set_file_mtime(&partial, FileTime::now()).expect("Can't set mtime");
+
+        let skipped = Tarball::unpack(&mut Archive::new(&data[..]), staging.path(), true)
+            .expect("Can't resume");
+        assert_eq!(skipped, 1);
+        assert_eq!(fs::read(&partial).expect("Can't read file"), b"b");
+    }
+
+    #[test]
+    fn parallel_unpack_keeps_the_last_entry() {
+        let staging = tempfile::tempdir().expect("Can't create staging directory");
+        let versions: Vec<_> = (0..32).map(|n| format!("{n}")).collect();
+        let mut files: Vec<(&str, &[u8])> = versions
+            .iter()
+            .map(|v| ("php-8.3.1/main.c", v.as_bytes()))
+            .collect();
+        files.push(("php-8.3.1/other.c", b"other"));
+        let data = tarball_with(&files);
+
+        Tarball::unpack_parallel(&mut Archive::new(&data[..]), staging.path(), false, true)
+            .expect("Can't unpack");
+
+        let main = staging.path().join("php-8.3.1/main.c");
+        assert_eq!(fs::read(main).expect("Can't read file"), b"31");
+    }
+
+    #[test]
+    fn parallel_unpack_creates_nothing_outside_staging() {
+        let staging = tempfile::tempdir().expect("Can't create staging directory");
+        let outside = tempfile::tempdir().expect("Can't create outside directory");
+
+        let mut builder = tar::Builder::new(vec![]);
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        link.set_mode(0o777);
+        builder
+            .append_link(&mut link, "php-8.3.1/escape", outside.path())
+            .expect("Can't append symlink");
+        let mut file = tar::Header::new_gnu();
+        file.set_size(4);
+        file.set_mode(0o644);
+        file.set_cksum();
+        builder
+            .append_data(&mut file, "php-8.3.1/escape/sub/evil.c", &b"evil"[..])
+            .expect("Can't append file");
+        let data = builder.into_inner().expect("Can't finish tarball");
+
+        assert!(Tarball::unpack_parallel(
+            &mut Archive::new(&data[..]),
+            staging.path(),
+            false,
+            true
+        )
+        .is_err());
+        assert!(!outside.path().join("sub").exists());
+    }
+
+    #[test]
+    fn staging_in_use_is_left_alone() {
+        let root = tempfile::tempdir().expect("Can't create staging root");
+        let staging = root.path().join("php-8.3.1.tar.bz2");
+        fs::create_dir(&staging).expect("Can't create staging directory");
+
+        let lock = StagingLock::acquire(&staging)
+            .expect("Can't lock")
+            .expect("Lock already held");
+        assert!(StagingLock::acquire(&staging)
+            .expect("Can't lock")
+            .is_none());
+        assert!(!remove_staging_dir(&staging).expect("Can't remove"));
+        assert!(staging.is_dir());
+
+        drop(lock);
+        assert!(remove_staging_dir(&staging).expect("Can't remove"));
+        assert!(!staging.exists());
     }
 }
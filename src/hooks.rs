@@ -1,31 +1,118 @@
-use crate::config::Config;
+use crate::{
+    config::Config,
+    messages::{msg, Msg},
+    platform::Platform,
+    progress,
+    template::Template,
+};
 use anyhow::{anyhow, Result};
-use indicatif::ProgressBar;
+use clap::ValueEnum;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     fmt,
     io::{BufRead, BufReader, Write},
-    os::unix::fs::PermissionsExt,
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt},
+        process::CommandExt,
+    },
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
 };
 use tempfile::NamedTempFile;
 
+// Anything one of these is in is the top of a project, hook search stops there
+const PROJECT_MARKERS: [&str; 3] = [".git", ".php-version", ".phpdownloader-version"];
+
+// The pid of the hook currently running, if any, so `--max-time` can stop it.  There's room for
+// one because hooks never overlap: batch operations fetch concurrently but extract and run hooks
+// one root at a time.
+static RUNNING: AtomicU32 = AtomicU32::new(0);
+
+// With --json, every hook reported so far, for the operation's result document
+static REPORTED: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+
 #[derive(Debug)]
 pub struct ScriptResult {
     pub status: i32,
     pub output: Vec<String>,
+    /// How long the hook ran for, `None` if there was no hook to run.
+    pub duration: Option<Duration>,
+    /// Where the hook ran.
+    pub dir: PathBuf,
+}
+
+#[derive(Debug)]
+enum HookSource {
+    Script(PathBuf),
+    Command(String),
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
 pub enum Hook {
     PostExtract,
     Configure,
     Make,
+    Install,
+    Reload,
+}
+
+/// Which hooks an operation should run, from `--no-hooks`, `--skip-hook` and `--only-hook`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HookSet(u8);
+
+impl HookSet {
+    pub const ALL: Self = Self(0b1_1111);
+
+    const fn bit(hook: Hook) -> u8 {
+        1 << hook as u8
+    }
+
+    pub fn new(no_hooks: bool, only: &[Hook], skip: &[Hook]) -> Self {
+        let mut set = if no_hooks {
+            Self(0)
+        } else if only.is_empty() {
+            Self::ALL
+        } else {
+            Self(only.iter().fold(0, |acc, &h| acc | Self::bit(h)))
+        };
+
+        for &hook in skip {
+            set.0 &= !Self::bit(hook);
+        }
+
+        set
+    }
+
+    pub const fn contains(self, hook: Hook) -> bool {
+        self.0 & Self::bit(hook) != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Serialize for Hook {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hook {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::from_str(&s, false).map_err(de::Error::custom)
+    }
 }
 
 impl fmt::Display for Hook {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.as_str(),)
+        write!(f, "{}", self.as_str())
     }
 }
 
@@ -34,6 +121,8 @@ impl ScriptResult {
         Self {
             status: 0,
             output: vec![],
+            duration: None,
+            dir: PathBuf::new(),
         }
     }
 
@@ -41,12 +130,12 @@ impl ScriptResult {
         self.output.push(line.to_string());
     }
 
-    fn set_status(&mut self, status: i32) {
+    const fn set_status(&mut self, status: i32) {
         self.status = status;
     }
 
     pub fn save(&self) -> Result<PathBuf> {
-        let mut tmp = NamedTempFile::new()?;
+        let mut tmp = NamedTempFile::new_in(Config::tmp_dir())?;
 
         for line in &self.output {
             writeln!(tmp, "{line}")?;
@@ -57,6 +146,58 @@ impl ScriptResult {
 
         Ok(path)
     }
+
+    /// Report a hook that ran as a JSON line on stderr, alongside the `--json` download progress,
+    /// and keep it for the result `print_reported` writes at the end of the operation, so CI can
+    /// pick up the log of a failed hook.
+    pub fn report(&self, hook: Hook, log: Option<&Path>) {
+        const CONTEXT: usize = 5;
+
+        let Some(duration) = self.duration else {
+            return;
+        };
+
+        let tail = self.output.len().saturating_sub(CONTEXT);
+
+        let record = serde_json::json!({
+            "hook": hook.as_str(),
+            "path": self.dir,
+            "status": self.status,
+            "duration_ms": u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+            "log": log,
+            "first_lines": &self.output[..CONTEXT.min(self.output.len())],
+            "last_lines": &self.output[tail.max(CONTEXT.min(self.output.len()))..],
+        });
+
+        let mut event = serde_json::json!({ "event": "hook" });
+        if let (Some(event), Some(record)) = (event.as_object_mut(), record.as_object()) {
+            event.extend(record.clone());
+        }
+        eprintln!("{event}");
+
+        REPORTED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(record);
+    }
+}
+
+/// Print the JSON result of `operation` with every hook reported while it ran on stdout.
+/// Operations that ran no hooks print nothing, so commands with their own JSON output keep it.
+pub fn print_reported(operation: &str, error: Option<&anyhow::Error>) {
+    let hooks = std::mem::take(&mut *REPORTED.lock().unwrap_or_else(PoisonError::into_inner));
+    if hooks.is_empty() {
+        return;
+    }
+
+    let result = serde_json::json!({
+        "operation": operation,
+        "ok": error.is_none(),
+        "error": error.map(|e| format!("{e:#}")),
+        "hooks": hooks,
+    });
+
+    println!("{result:#}");
 }
 
 impl Hook {
@@ -64,47 +205,187 @@ impl Hook {
         match self {
             Self::Configure => "configure",
             Self::Make => "make",
+            Self::Install => "install",
+            Self::Reload => "reload",
             Self::PostExtract => "post-extract",
         }
     }
 
-    fn get(hook: Self) -> Result<Option<PathBuf>> {
-        let mut path: PathBuf = Config::hooks_path()?;
-        path.push(&hook.to_string());
+    /// Kill the hook that's currently running, if there is one.
+    pub fn kill_running() {
+        let pid = RUNNING.swap(0, Ordering::SeqCst);
+        if let Ok(pid) = libc::pid_t::try_from(pid) {
+            if pid > 0 {
+                // SAFETY: kill has no memory safety requirements.  Hooks run in their own process
+                // group so this takes out anything they started too.
+                unsafe { libc::kill(-pid, libc::SIGKILL) };
+            }
+        }
+    }
+
+    fn is_executable(path: &Path) -> bool {
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    }
+
+    /// Whether only we could have put `path` there: we own it and neither group nor others can
+    /// write to it.
+    fn is_trusted(path: &Path) -> bool {
+        // SAFETY: getuid has no preconditions and can't fail
+        let uid = unsafe { libc::getuid() };
+        path.metadata()
+            .is_ok_and(|m| m.uid() == uid && m.mode() & 0o022 == 0)
+    }
 
-        let mode = path.metadata()?.permissions().mode();
+    // Project hook directories from `working_dir` up, stopping below `home` (whose hooks are the
+    // global ones) or at the top of the project.  Directories someone else could have planted
+    // scripts in are skipped, along with everything above them.
+    fn project_dirs(working_dir: &Path, home: Option<&Path>) -> Vec<PathBuf> {
+        let mut dirs = vec![];
 
-        if mode & 0o111 != 0 && path.exists() {
-            Ok(Some(path))
-        } else {
-            Ok(None)
+        for dir in working_dir.ancestors() {
+            if home == Some(dir) {
+                break;
+            }
+
+            let cfg = dir.join(Config::APP_CFG_PATH);
+            let hooks = cfg.join(Config::APP_HOOKS_PATH);
+            if hooks.is_dir() {
+                if Self::is_trusted(&cfg) && Self::is_trusted(&hooks) {
+                    dirs.push(hooks);
+                } else {
+                    progress::note(msg!(Msg::HooksUntrusted, path = format!("{hooks:?}")));
+                }
+            }
+
+            if PROJECT_MARKERS.iter().any(|m| dir.join(m).exists()) {
+                break;
+            }
         }
+
+        dirs
     }
 
-    fn get_cmd(path: &Path, working_dir: &Path, args: &[&str]) -> Command {
-        let mut cmd = Command::new("bash");
+    /// Directories searched for hook scripts, highest precedence first.  A project can ship its
+    /// own hooks in `.phpdownloader/hooks` above the build root, up to the top of the project
+    /// (a directory with `.git` or a version file) or the home directory.  The closest one wins,
+    /// and the global `~/.phpdownloader/hooks` is used last.
+    pub fn search_path(working_dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut dirs = Self::project_dirs(working_dir, Config::home_dir().as_deref());
+
+        let global = Config::hooks_path()?;
+        if !dirs.contains(&global) {
+            dirs.push(global);
+        }
+
+        Ok(dirs)
+    }
+
+    /// Whether there is anything to run for `hook`, a script or a command, from `working_dir`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the hook search path can't be determined.
+    pub fn is_configured(hook: Self, working_dir: &Path) -> Result<bool> {
+        Ok(Self::get(hook, working_dir)?.is_some())
+    }
+
+    // Hook scripts win over a command configured in config.json, which wins over the default
+    fn get(hook: Self, working_dir: &Path) -> Result<Option<HookSource>> {
+        let script = Self::search_path(working_dir)?
+            .into_iter()
+            .map(|dir| dir.join(hook.as_str()))
+            .find(|path| Self::is_executable(path) && Self::is_trusted(path));
+
+        if let Some(path) = script {
+            return Ok(Some(HookSource::Script(path)));
+        }
+
+        let cmd = Config::settings()
+            .hooks
+            .get(hook.as_str())
+            .cloned()
+            .or_else(|| hook.default_command());
+
+        Ok(cmd.map(HookSource::Command))
+    }
+
+    // What to run when neither a script nor a configured command exists
+    fn default_command(self) -> Option<String> {
+        match self {
+            Self::Install => Some(format!("{} install", Platform::make())),
+            _ => None,
+        }
+    }
+
+    // Scripts get the positional `args` followed by any configured hook-args, configured commands
+    // only get the hook-args since they can reference `{build_root}` themselves.  A configured
+    // command is shell by design and goes in as is, but the values expanded into it and everything
+    // else are single arguments and get quoted for `sh -c`.  `{configure_args}` is the exception,
+    // it's meant to expand to several arguments so each of those is quoted instead.
+    fn get_cmdline(hook: Self, src: &HookSource, args: &[&str], vars: &Template) -> Result<String> {
+        let mut parts = match src {
+            HookSource::Script(path) => {
+                let mut parts = vec![quote(&path.to_string_lossy())];
+                parts.extend(args.iter().map(|arg| quote(arg)));
+                parts
+            }
+            HookSource::Command(cmd) => vec![vars.expand_with(cmd, |name, value| match name {
+                "configure_args" => Platform::detect()
+                    .configure_args
+                    .iter()
+                    .map(|arg| quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                _ => quote(value),
+            })?],
+        };
+
+        if let Some(extra) = Config::settings().hook_args.get(hook.as_str()) {
+            for arg in extra {
+                parts.push(quote(&vars.expand(arg)?));
+            }
+        }
+
+        Ok(parts.join(" "))
+    }
+
+    fn get_cmd(cmdline: &str, working_dir: &Path, cflags: Option<&str>) -> Command {
+        let mut cmd = Command::new(Platform::shell());
 
         cmd.arg("-c")
             .current_dir(working_dir)
-            .arg(format!("{} {} 2>&1", path.display(), args.join(" ")))
+            .arg(format!("{cmdline} 2>&1"))
+            .envs(Platform::detect().hook_env(cflags))
+            .process_group(0)
             .stdout(Stdio::piped());
 
         cmd
     }
 
-    pub fn exec<P: AsRef<Path>>(hook: Self, working_dir: P, args: &[&str]) -> Result<ScriptResult> {
+    pub fn exec<P: AsRef<Path>>(
+        hook: Self,
+        working_dir: P,
+        args: &[&str],
+        vars: &Template,
+        cflags: Option<&str>,
+    ) -> Result<ScriptResult> {
         let mut res = ScriptResult::new();
 
-        let Some(path) = Self::get(hook)? else {
+        let Some(src) = Self::get(hook, working_dir.as_ref())? else {
             return Ok(res);
         };
 
-        let pb = ProgressBar::new_spinner();
+        let cmdline = Self::get_cmdline(hook, &src, args, vars)?;
+
+        let pb = progress::spinner();
         pb.set_message(format!("Running {hook} hook"));
 
-        let mut cmd = Self::get_cmd(&path, working_dir.as_ref(), args);
+        let mut cmd = Self::get_cmd(&cmdline, working_dir.as_ref(), cflags);
+        let started = Instant::now();
 
         let mut child = cmd.spawn()?;
+        RUNNING.store(child.id(), Ordering::SeqCst);
         let stdout = child
             .stdout
             .take()
@@ -118,10 +399,102 @@ impl Hook {
             pb.tick();
         }
 
-        let status = child.wait()?;
+        let status = child.wait();
+        RUNNING.store(0, Ordering::SeqCst);
+        let status = status?;
         pb.finish_and_clear();
         res.set_status(status.code().unwrap_or(0));
+        res.duration = Some(started.elapsed());
+        res.dir = working_dir.as_ref().to_path_buf();
 
         Ok(res)
     }
 }
+
+// Quote `arg` for `sh`, leaving the common harmless ones alone so logged command lines stay
+// readable
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_arguments() {
+        assert_eq!(quote("--with-openssl=/opt/ssl"), "--with-openssl=/opt/ssl");
+        assert_eq!(
+            quote("/tmp/my hooks/configure"),
+            "'/tmp/my hooks/configure'"
+        );
+        assert_eq!(quote("it's; rm -rf ~"), r"'it'\''s; rm -rf ~'");
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn command_values_are_quoted() {
+        let vars = Template::default()
+            .set("build_root", "/tmp/my php/php-8.3.1")
+            .set("prefix", "/opt/php; rm -rf ~");
+        let src = HookSource::Command("cd {build_root} && make PREFIX={prefix}".into());
+
+        assert_eq!(
+            Hook::get_cmdline(Hook::Make, &src, &[], &vars).expect("Can't build command line"),
+            r"cd '/tmp/my php/php-8.3.1' && make PREFIX='/opt/php; rm -rf ~'"
+        );
+    }
+
+    #[test]
+    fn hook_selection() {
+        let only = HookSet::new(false, &[Hook::Make], &[]);
+        assert!(only.contains(Hook::Make));
+        assert!(!only.contains(Hook::Configure));
+
+        let skip = HookSet::new(false, &[], &[Hook::Configure, Hook::Make]);
+        assert!(skip.contains(Hook::PostExtract));
+        assert!(!skip.contains(Hook::Make));
+
+        assert!(HookSet::new(true, &[], &[]).is_empty());
+        assert!(HookSet::new(false, &[Hook::Make], &[Hook::Make]).is_empty());
+    }
+
+    #[test]
+    fn project_hooks_stay_in_the_project() {
+        let tmp = tempfile::tempdir().expect("Can't create project");
+        let hooks = |dir: &Path| {
+            let hooks = dir.join(Config::APP_CFG_PATH).join(Config::APP_HOOKS_PATH);
+            std::fs::create_dir_all(&hooks).expect("Can't create hooks dir");
+            hooks
+        };
+
+        let outside = hooks(tmp.path());
+        let project = tmp.path().join("project");
+        let shared = hooks(&project.join("shared"));
+        let root = project.join("shared/php-8.3.4");
+        std::fs::create_dir_all(project.join(".git")).expect("Can't create project marker");
+        std::fs::create_dir_all(&root).expect("Can't create build root");
+        let own = hooks(&project);
+
+        // Above the project marker is out of bounds
+        assert_eq!(Hook::project_dirs(&root, None), vec![shared.clone(), own]);
+
+        // So is anything above home
+        assert_eq!(
+            Hook::project_dirs(&root, Some(&project)),
+            vec![shared.clone()]
+        );
+
+        // And hooks others could write to aren't run at all
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777))
+            .expect("Can't make hooks dir world writable");
+        assert!(!Hook::project_dirs(&root, None).contains(&shared));
+        assert!(!Hook::project_dirs(&root, None).contains(&outside));
+    }
+}
@@ -3,375 +3,3561 @@
 #![allow(clippy::non_ascii_literal)]
 #![allow(clippy::must_use_candidate)]
 
+mod activation;
+mod audit;
+mod auth;
+mod cache;
+mod compat;
 mod config;
+mod conflict;
+mod constraint;
 pub mod downloads;
 mod extract;
 mod hooks;
+mod messages;
+mod migrate;
+mod mirror;
+mod plan;
+mod platform;
+mod progress;
+mod prompt;
+mod queue;
+#[cfg(feature = "serve")]
+mod serve;
+mod signature;
+mod snapshot;
+mod state;
+mod template;
+mod transport;
+mod version_file;
 mod view;
 
 use crate::{
+    activation::Shell,
+    audit::Confirmation,
     config::Config,
-    downloads::{DownloadList, Extension, Version},
-    extract::{BuildRoot, Tarball},
-    hooks::{Hook, ScriptResult},
-    view::Viewer,
+    conflict::{OnConflict, Resolution},
+    constraint::{VersionAlias, VersionArg, VersionReq},
+    downloads::{DownloadInfo, DownloadList, Eol, Extension, Version},
+    extract::{BuildRoot, MtimeMode, SourceInfo, Tarball, UnpackOptions, Verdict, BACKUP_SUFFIX},
+    hooks::{Hook, HookSet, ScriptResult},
+    messages::{msg, Msg},
+    plan::{PlannedUpgrade, UpgradePlan},
+    platform::Platform,
+    snapshot::Snapshot,
+    template::Template,
+    view::{RootInfo, ToHumanSize, VerifyResult, VersionStatus, Viewer},
 };
-use anyhow::{bail, Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::{
+    error::{ContextKind, ContextValue, ErrorKind},
+    parser::ValueSource,
+    ArgAction, ArgMatches, CommandFactory, Parser, Subcommand, ValueEnum,
+};
+use colored::Colorize;
+use futures::{stream, StreamExt};
 use std::{
-    fmt,
+    collections::{hash_map::Entry, HashMap},
+    ffi::OsString,
+    fmt, fs,
     path::{Path, PathBuf},
+    process::Command,
     str,
+    time::Duration,
 };
+use tempfile::TempDir;
+
+// `upgrade --check` exits with this when there's something to upgrade
+const UPGRADES_AVAILABLE: i32 = 10;
 
 const NEW_MAJOR: u8 = 8;
 const NEW_MINOR: u8 = 2;
 
 #[derive(Parser, Debug)]
+#[command(infer_subcommands = true)]
+#[allow(clippy::struct_excessive_bools)]
 struct Options {
-    #[arg(short, long, default_value = "bz2")]
-    extension: Extension,
+    #[arg(short, long)]
+    extension: Option<Extension>,
 
     #[arg(short, long)]
     json: bool,
 
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
     #[arg(short, long)]
     force: bool,
 
+    #[arg(short, long)]
+    yes: bool,
+
+    #[arg(long)]
+    no_verify: bool,
+
+    #[arg(long)]
+    verify_signatures: bool,
+
+    #[arg(long)]
+    insecure: bool,
+
+    #[arg(long)]
+    allow_eol: bool,
+
+    #[arg(long, value_enum, value_name = "POLICY")]
+    on_conflict: Option<OnConflict>,
+
     #[arg(short, long)]
     no_hooks: bool,
 
+    #[arg(long, value_name = "HOOK", conflicts_with = "no_hooks")]
+    skip_hook: Vec<Hook>,
+
+    #[arg(long, value_name = "HOOK", conflicts_with = "no_hooks")]
+    only_hook: Vec<Hook>,
+
+    #[arg(long)]
+    ephemeral_cache: bool,
+
+    #[arg(long, value_name = "SECONDS", default_value_t = Config::DEFAULT_CACHE_TTL.as_secs())]
+    cache_ttl: u64,
+
+    #[arg(long, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+
+    #[arg(long, overrides_with = "follow_symlinks")]
+    no_follow_symlinks: bool,
+
+    #[arg(long)]
+    preserve_xattrs: bool,
+
+    #[arg(long, value_enum, default_value_t)]
+    mtime: MtimeMode,
+
+    #[arg(long)]
+    compat_patches: bool,
+
+    #[arg(long)]
+    fast_decompress: bool,
+
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    #[arg(long, value_name = "SECONDS")]
+    connect_timeout: Option<u64>,
+
+    #[arg(long, value_name = "SECONDS")]
+    max_time: Option<u64>,
+
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=i64::from(Config::MAX_SEGMENTS)))]
+    segments: Option<u8>,
+
+    #[arg(long, value_name = "SECONDS", default_value_t = Config::DEFAULT_STALL_TIMEOUT.as_secs())]
+    stall_timeout: u64,
+
+    #[arg(long, hide = true, env = "PHPDOWNLOADER_BASE_URL")]
+    base_url: Option<String>,
+
+    #[arg(long, value_name = "URL")]
+    mirror: Vec<String>,
+
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    #[arg(long, value_name = "FILE")]
+    ca_bundle: Option<PathBuf>,
+
+    #[arg(long)]
+    no_system_roots: bool,
+
+    #[arg(long)]
+    danger_skip_tls_verify: bool,
+
+    #[arg(long, value_name = "AGENT")]
+    user_agent: Option<String>,
+
+    #[arg(long)]
+    offline: bool,
+
+    #[arg(long, value_name = "DIR", value_parser = is_writable_dir)]
+    tmpdir: Option<PathBuf>,
+
+    #[arg(long, hide = true, conflicts_with = "list_versions_remote")]
+    list_versions_local: bool,
+
+    #[arg(long, hide = true, value_enum, num_args = 0..=1, default_missing_value = "active")]
+    list_versions_remote: Option<RemoteVersions>,
+
     #[clap(subcommand)]
-    operation: Operation,
+    operation: Option<Operation>,
+}
+
+/// Which remote versions `--list-versions-remote` prints.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum RemoteVersions {
+    /// The newest release of each supported series
+    Active,
+    /// Every release of each supported series
+    All,
+}
+
+/// An application path `paths` can print.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AppPath {
+    /// The application directory, `~/.phpdownloader`
+    Root,
+    /// The settings file
+    Config,
+    /// Downloaded tarballs
+    Registry,
+    /// Hook scripts
+    Hooks,
+    /// Compatibility patches
+    Patches,
+    /// Cached listings and API responses
+    Cache,
+    /// Where `get` extracts build trees
+    Workspace,
+    /// Message catalogs
+    Locale,
+    /// Queued downloads
+    Queue,
+    /// The audit log of destructive actions
+    AuditLog,
+    /// The release manager keyring
+    Keyring,
+    /// Interrupted extractions waiting to be resumed
+    Staging,
+    /// Where hook logs and other temporary files go
+    Logs,
+    /// The install prefix, when it doesn't depend on the version
+    Installs,
+}
+
+impl AppPath {
+    fn resolve(self) -> Result<Option<PathBuf>> {
+        Ok(Some(match self {
+            Self::Root => Config::app_root()?,
+            Self::Config => Config::settings_path()?,
+            Self::Registry => Config::registry_path()?,
+            Self::Hooks => Config::hooks_path()?,
+            Self::Patches => Config::patches_path()?,
+            Self::Cache => Config::cache_path()?,
+            Self::Workspace => Config::workspace_path()?,
+            Self::Locale => Config::locale_path()?,
+            Self::Queue => Config::queue_path()?,
+            Self::AuditLog => Config::audit_path()?,
+            Self::Keyring => Config::keyring_path()?,
+            Self::Staging => Config::staging_path()?,
+            Self::Logs => Config::tmp_dir(),
+            Self::Installs => {
+                let prefix = Config::settings()
+                    .prefix
+                    .as_deref()
+                    .unwrap_or(Template::DEFAULT_PREFIX);
+
+                // A prefix like /opt/php/{version} has no single answer
+                match Template::default().expand(prefix) {
+                    Ok(prefix) => PathBuf::from(prefix),
+                    Err(_) => return Ok(None),
+                }
+            }
+        }))
+    }
 }
 
 #[derive(Parser, Debug, Clone)]
 enum Operation {
+    Active,
+    #[command(hide = true)]
+    BenchExtract {
+        version: Version,
+    },
     Cached {
-        version: Option<Version>,
+        version: Option<VersionArg>,
+
+        #[arg(long)]
+        check_updates: bool,
+
+        #[arg(long)]
+        problems_only: bool,
     },
+    Doctor {
+        version: Option<VersionArg>,
+    },
+    #[command(visible_alias = "dl")]
     Download {
-        version: Version,
-        output_path: Option<PathBuf>,
+        #[arg(value_name = "VERSION... [OUTPUT_PATH]", value_parser = download_arg)]
+        args: Vec<DownloadArg>,
+
+        #[arg(long)]
+        pre: bool,
+
+        #[arg(long, conflicts_with = "pre")]
+        no_pre: bool,
+
+        #[arg(long)]
+        all_active: bool,
+
+        #[arg(long)]
+        from_file: bool,
+
+        #[arg(long, value_name = "N", default_value_t = 4)]
+        parallel: usize,
+
+        #[arg(long, value_name = "DIR", value_parser = is_writable_dir)]
+        extract: Option<PathBuf>,
+    },
+    Env {
+        version: VersionArg,
+
+        #[arg(long, value_enum)]
+        shell: Option<Shell>,
     },
+    #[command(visible_alias = "x")]
     Extract {
-        version: Version,
+        #[arg(value_name = "VERSION... OUTPUT_PATH [OUTPUT_FILE]", required = true)]
+        args: Vec<String>,
 
-        #[clap(value_parser = is_writable_dir)]
-        output_path: PathBuf,
+        #[arg(long)]
+        pre: bool,
+
+        #[arg(long, conflicts_with = "pre")]
+        no_pre: bool,
 
-        output_file: Option<PathBuf>,
+        #[arg(long)]
+        rm_tarball: bool,
+
+        #[arg(long, conflicts_with = "rm_tarball")]
+        keep_tarball: bool,
+
+        #[arg(long)]
+        reuse: bool,
+
+        #[arg(long)]
+        from_file: bool,
+
+        #[arg(long, value_name = "N", default_value_t = 4)]
+        parallel: usize,
+    },
+    Gc {
+        #[clap(value_parser = is_dir)]
+        path: Option<PathBuf>,
+
+        #[arg(long, default_value_t = 1)]
+        depth: usize,
+
+        #[arg(long, value_name = "N")]
+        keep: Option<usize>,
+
+        #[arg(long, value_name = "DAYS")]
+        older_than: Option<u64>,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Get {
+        version: VersionArg,
+
+        #[arg(long)]
+        pre: bool,
+
+        #[arg(long, conflicts_with = "pre")]
+        no_pre: bool,
+
+        #[arg(long)]
+        install: bool,
+
+        #[clap(value_parser = is_writable_dir)]
+        output_path: Option<PathBuf>,
     },
     Latest {
-        version: Option<Version>,
+        version: Option<VersionArg>,
+
+        #[arg(long)]
+        pre: bool,
+
+        #[arg(long, conflicts_with = "pre")]
+        no_pre: bool,
+
+        #[arg(short, long)]
+        quiet: bool,
     },
+    #[command(visible_alias = "ls")]
     List {
+        version: Option<VersionArg>,
+
+        #[arg(long)]
+        include_qa: bool,
+    },
+    #[command(hide = true)]
+    Messages,
+    Migrate {
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Mirror {
+        #[command(subcommand)]
+        action: MirrorAction,
+    },
+    Paths {
+        #[arg(value_enum)]
+        name: Option<AppPath>,
+    },
+    Pin {
+        #[clap(value_parser = is_dir)]
+        path: PathBuf,
+    },
+    Purge {
+        #[arg(long)]
+        installs: bool,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Rebuild {
+        #[clap(value_parser = is_dir)]
+        path: PathBuf,
+    },
+    Refetch {
+        version: Version,
+    },
+    Refresh {
+        /// The series to forget, e.g. 8.3.  The cache is kept per series, so unlike most
+        /// commands this takes no constraints or aliases.
+        #[arg(required_unless_present = "all")]
         version: Option<Version>,
+
+        #[arg(long, conflicts_with = "version")]
+        all: bool,
     },
-    Upgrade {
+    Resolve {
+        version: VersionArg,
+
+        #[arg(long)]
+        pre: bool,
+
+        #[arg(long, conflicts_with = "pre")]
+        no_pre: bool,
+    },
+    ResumeAll,
+    Roots {
+        #[clap(value_parser = is_dir)]
+        path: Option<PathBuf>,
+
+        #[arg(long)]
+        version: Option<VersionArg>,
+
+        #[arg(long)]
+        outdated: bool,
+
+        #[arg(long, default_value_t = 1)]
+        depth: usize,
+    },
+    #[cfg(feature = "serve")]
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: std::net::SocketAddr,
+    },
+    State {
+        #[command(subcommand)]
+        action: StateAction,
+    },
+    Unpin {
+        #[clap(value_parser = is_dir)]
         path: PathBuf,
     },
+    #[command(visible_alias = "up")]
+    Upgrade {
+        #[clap(value_parser = is_writable_dir, required_unless_present = "apply")]
+        path: Option<PathBuf>,
+
+        #[arg(long, default_value_t = 1)]
+        depth: usize,
+
+        #[arg(long, conflicts_with = "parent")]
+        single: bool,
+
+        #[arg(long)]
+        parent: bool,
+
+        #[arg(long)]
+        check: bool,
+
+        #[arg(long, conflicts_with = "check")]
+        plan: bool,
+
+        #[arg(long, value_name = "PLAN", conflicts_with_all = ["check", "plan", "path"])]
+        apply: Option<PathBuf>,
+    },
+    Verify {
+        #[arg(required_unless_present = "all")]
+        version: Option<VersionArg>,
+
+        #[arg(long, conflicts_with = "version")]
+        all: bool,
+
+        #[clap(long, value_parser = is_dir)]
+        path: Option<PathBuf>,
+
+        #[arg(long, default_value_t = 1)]
+        depth: usize,
+
+        #[arg(long)]
+        check_upstream: bool,
+    },
     Version,
 }
 
+/// Something `download` can fetch, a release or a snapshot of a php-src branch.
+#[derive(Debug, Clone)]
+enum DownloadTarget {
+    Release(Version),
+    Constraint(VersionReq),
+    Alias(VersionAlias),
+    Snapshot(Snapshot),
+}
+
+impl From<VersionArg> for DownloadTarget {
+    fn from(arg: VersionArg) -> Self {
+        match arg {
+            VersionArg::Version(version) => Self::Release(version),
+            VersionArg::Req(req) => Self::Constraint(req),
+            VersionArg::Alias(alias) => Self::Alias(alias),
+        }
+    }
+}
+
+impl fmt::Display for DownloadTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Release(version) => write!(f, "{version}"),
+            Self::Constraint(req) => write!(f, "{req}"),
+            Self::Alias(alias) => write!(f, "{alias}"),
+            Self::Snapshot(snapshot) => write!(f, "{snapshot}"),
+        }
+    }
+}
+
+/// A `download` argument, the versions come first optionally followed by where to put them.
+#[derive(Debug, Clone)]
+enum DownloadArg {
+    Target(DownloadTarget),
+    OutputPath(PathBuf),
+}
+
+fn download_arg(s: &str) -> std::result::Result<DownloadArg, String> {
+    // As with constraints below, a directory called `master` is the output path
+    if let Ok(snapshot) = s.parse() {
+        if !Path::new(s).is_dir() {
+            return Ok(DownloadArg::Target(DownloadTarget::Snapshot(snapshot)));
+        }
+    }
+
+    if let Ok(version) = s.parse() {
+        return Ok(DownloadArg::Target(DownloadTarget::Release(version)));
+    }
+
+    if let Ok(alias) = s.parse() {
+        return Ok(DownloadArg::Target(DownloadTarget::Alias(alias)));
+    }
+
+    // A constraint like `8` could also be a directory, the directory wins
+    match s.parse() {
+        Ok(req) if !Path::new(s).is_dir() => {
+            Ok(DownloadArg::Target(DownloadTarget::Constraint(req)))
+        }
+        _ => is_writable_dir(s).map(DownloadArg::OutputPath),
+    }
+}
+
+/// Split `download` arguments into the targets and the output path, if there is one.  Without
+/// any versions (or with `from_file`) the version comes from a `.php-version` file, unless
+/// `all_active` supplies them.
+fn download_targets(
+    args: Vec<DownloadArg>,
+    all_active: bool,
+    from_file: bool,
+    follow_symlinks: bool,
+) -> Result<(Vec<DownloadTarget>, Option<PathBuf>)> {
+    let mut versions = vec![];
+    let mut output_path = None;
+
+    for arg in args {
+        match (arg, &output_path) {
+            (DownloadArg::Target(t), None) => versions.push(t),
+            (DownloadArg::OutputPath(path), None) => output_path = Some(path),
+            (arg, _) => bail!("Unexpected {arg:?}, give the versions followed by one output path"),
+        }
+    }
+
+    if from_file || (versions.is_empty() && !all_active) {
+        versions.insert(0, version_file::version()?.into());
+    }
+
+    if let Some(path) = &output_path {
+        check_writable_dir(path, follow_symlinks).map_err(|e| anyhow!(e))?;
+    }
+
+    Ok((versions, output_path))
+}
+
+/// Split `extract` arguments into the versions, the output path and the output file name, which
+/// only makes sense for a single version.  Without any versions (or with `from_file`) the version
+/// comes from a `.php-version` file.  A constraint that's also a directory is taken to be the
+/// output path, as with `download`.
+fn extract_args(
+    args: Vec<String>,
+    from_file: bool,
+    follow_symlinks: bool,
+) -> Result<(Vec<VersionArg>, PathBuf, Option<PathBuf>)> {
+    let mut args = args.into_iter().peekable();
+    let mut versions = vec![];
+
+    while let Some(Ok(version)) = args.peek().map(|arg| arg.parse::<VersionArg>()) {
+        if matches!(version, VersionArg::Req(_))
+            && args.peek().is_some_and(|arg| Path::new(arg).is_dir())
+        {
+            break;
+        }
+        args.next();
+        versions.push(version);
+    }
+
+    let output_path = args.next().context("No output path given")?;
+    let output_path = PathBuf::from(output_path);
+    check_writable_dir(&output_path, follow_symlinks).map_err(|e| anyhow!(e))?;
+    let output_file = args.next().map(PathBuf::from);
+
+    if let Some(arg) = args.next() {
+        bail!("Unexpected {arg:?}, give the versions, an output path and an output file name");
+    }
+
+    if from_file || versions.is_empty() {
+        versions.insert(0, version_file::version()?);
+    }
+
+    if versions.len() > 1 && output_file.is_some() {
+        bail!("An output file name can only be given when extracting a single version");
+    }
+
+    Ok((versions, output_path, output_file))
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum MirrorAction {
+    /// Lay the registry out as a static mirror other machines can use with `--base-url`
+    Export { dir: PathBuf },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum StateAction {
+    /// Save settings, hooks, patches and the download queue to a tarball
+    Export {
+        file: PathBuf,
+
+        /// Include the downloaded tarballs
+        #[arg(long)]
+        with_tarballs: bool,
+
+        /// Keep inline mirror passwords and tokens in config.json, which are left out otherwise
+        #[arg(long)]
+        include_secrets: bool,
+    },
+    /// Replace this machine's settings, hooks and patches with an exported tarball.  Tarballs in
+    /// it are hashed again and left out if they don't match their checksums
+    Import { file: PathBuf },
+}
+
+/// How `upgrade` interprets the path it's given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpgradeScope {
+    /// A build root if the path is one, otherwise the build roots under it.
+    Auto,
+    /// The path must be a build root.
+    Single,
+    /// The build roots under the path, or next to it if it's a build root itself.
+    Parent,
+}
+
+/// Flags shared by everything that extracts a build tree.
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::struct_excessive_bools)]
+struct ExtractOptions {
+    force: bool,
+    hooks: HookSet,
+    follow_symlinks: bool,
+    on_conflict: Option<OnConflict>,
+    pre: bool,
+    rm_tarball: bool,
+    compat_patches: bool,
+    unpack: UnpackOptions,
+}
+
 impl Operation {
     const fn as_str(&self) -> &'static str {
         match self {
+            Self::Active => "active",
+            Self::BenchExtract { .. } => "bench-extract",
             Self::Cached { .. } => "cached",
+            Self::Doctor { .. } => "doctor",
             Self::Download { .. } => "download",
+            Self::Env { .. } => "env",
             Self::Extract { .. } => "extract",
+            Self::Gc { .. } => "gc",
+            Self::Get { .. } => "get",
             Self::Latest { .. } => "latest",
             Self::List { .. } => "list",
+            Self::Messages => "messages",
+            Self::Migrate { .. } => "migrate",
+            Self::Mirror { .. } => "mirror",
+            Self::Paths { .. } => "paths",
+            Self::Pin { .. } => "pin",
+            Self::Purge { .. } => "purge",
+            Self::Rebuild { .. } => "rebuild",
+            Self::Refetch { .. } => "refetch",
+            Self::Refresh { .. } => "refresh",
+            Self::Resolve { .. } => "resolve",
+            Self::ResumeAll => "resume-all",
+            Self::Roots { .. } => "roots",
+            #[cfg(feature = "serve")]
+            Self::Serve { .. } => "serve",
+            Self::State { .. } => "state",
+            Self::Unpin { .. } => "unpin",
             Self::Upgrade { .. } => "upgrade",
+            Self::Verify { .. } => "verify",
             Self::Version => "version",
         }
     }
+
+    /// Whether this operation will try to write tarballs into the registry.
+    fn writes_registry(&self) -> bool {
+        match self {
+            Self::Download { args, .. } => {
+                !args.iter().any(|a| matches!(a, DownloadArg::OutputPath(_)))
+            }
+            Self::Extract { .. } | Self::Get { .. } | Self::ResumeAll | Self::Upgrade { .. } => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+fn validate_hook(hook: Hook, res: &ScriptResult) -> Result<()> {
+    // With --json every hook that ran gets its log saved so it can be reported
+    let log = if res.status != 0 || (Config::json_progress() && res.duration.is_some()) {
+        Some(res.save()?)
+    } else {
+        None
+    };
+
+    if Config::json_progress() {
+        res.report(hook, log.as_deref());
+    }
+
+    if let Some(path) = log.filter(|_| res.status != 0) {
+        eprintln!(
+            "{}",
+            msg!(Msg::HookFailed, hook = hook, path = format!("{path:?}"))
+        );
+        bail!(msg!(Msg::HookError));
+    }
+
+    Ok(())
+}
+
+// Older series don't have every tarball format, let the user know if we had to switch.
+fn series_extension(version: Version, extension: Extension) -> Extension {
+    // GitHub only offers snapshots gzipped
+    if version.is_snapshot() {
+        return Extension::GZ;
+    }
+
+    let actual = extension.for_series(version.major, version.minor);
+
+    if actual != extension {
+        eprintln!(
+            "Note:  .tar.{extension} isn't available for PHP {}.{}, using .tar.{actual}",
+            version.major, version.minor
+        );
+    }
+
+    actual
+}
+
+/// Run blocking filesystem or process work on tokio's blocking pool so it doesn't stall the
+/// runtime.  If the caller is cancelled the work still runs to completion, so anything it leaves
+/// behind is either cleaned up by the work itself or resumable (see `Tarball::extract`).
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("Background task failed")?
+}
+
+// Custom directory names don't carry the version, but the recorded source tarball does
+fn reusable_root(path: &Path, version: Version) -> Option<BuildRoot> {
+    let path = path.canonicalize().ok()?;
+
+    if let Ok(root) = BuildRoot::from_path(&path) {
+        return (root.version == version).then_some(root);
+    }
+
+    let root = BuildRoot::new(&path, version, "");
+    let prefix = format!("php-{version}.tar.");
+    root.source()
+        .is_some_and(|s| s.tarball.starts_with(&prefix))
+        .then_some(root)
+}
+
+// Object files built against musl don't link on glibc hosts and vice versa, so a tree that
+// recorded another libc has to be extracted again rather than built on
+#[allow(clippy::unnecessary_debug_formatting)]
+fn check_libc(root: &BuildRoot) -> Result<()> {
+    let host = Platform::libc();
+
+    match root.source().and_then(|s| s.libc) {
+        Some(libc) if libc != host => bail!(msg!(
+            Msg::LibcMismatch,
+            path = format!("{:?}", root.src),
+            libc = libc,
+            host = host
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Unpack `tarball` under `dst_path` and apply any compatibility fixes, returning the extracted
+/// path and the fixes applied.
+async fn unpack_tarball(
+    version: Version,
+    tarball: &Tarball,
+    dst_path: &Path,
+    dst_file: Option<&Path>,
+    opts: ExtractOptions,
+) -> Result<(String, Vec<String>)> {
+    let needed = tarball.estimated_size()?;
+    eprintln!(
+        "Extraction will use ~{} in {}",
+        needed.to_human_size(),
+        dst_path.display()
+    );
+
+    match extract::available_space(dst_path) {
+        Ok(free) if free < needed && !opts.force => bail!(
+            "Only {} free in {}, use --force to extract anyway",
+            free.to_human_size(),
+            dst_path.display()
+        ),
+        Err(e) => eprintln!("Warning:  {e:#}"),
+        _ => {}
+    }
+
+    let resume = match tarball.partial_extraction()? {
+        Some(path) => prompt::confirm(&msg!(Msg::ResumePrompt, path = format!("{path:?}")))?,
+        None => false,
+    };
+
+    // Extract the arghive and capture full destination path
+    let extracted_path = {
+        let (tarball, dst_path, dst_file) = (
+            tarball.clone(),
+            dst_path.to_path_buf(),
+            dst_file.map(Path::to_path_buf),
+        );
+        let unpack = UnpackOptions {
+            resume,
+            ..opts.unpack
+        };
+
+        blocking(move || tarball.extract(&dst_path, dst_file.as_deref(), unpack))
+            .await?
+            .canonicalize()?
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    let patches = if opts.compat_patches {
+        let path = PathBuf::from(&extracted_path);
+        let with_cflags = opts.hooks.contains(Hook::Configure) || opts.hooks.contains(Hook::Make);
+        let patches = blocking(move || compat::apply_patches(version, &path, with_cflags)).await?;
+        if !patches.is_empty() {
+            eprintln!("{}", msg!(Msg::AppliedCompat, patches = patches.join(", ")));
+        }
+        patches
+    } else {
+        vec![]
+    };
+
+    Ok((extracted_path, patches))
+}
+
+/// Get `version`'s tarball into the registry, queueing it for `resume-all` if the download fails.
+async fn fetch_tarball(version: Version, extension: Extension) -> Result<Tarball> {
+    match Tarball::get_or_download(version, extension).await {
+        Ok(tarball) => {
+            queue::remove(version, extension);
+            Ok(tarball)
+        }
+        Err(e) => {
+            // Snapshots are only ever downloaded explicitly
+            if !version.is_snapshot() {
+                queue::push(version, extension, None, &e);
+            }
+            Err(e)
+        }
+    }
 }
 
-impl fmt::Display for Operation {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.as_str())
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn op_extract(
+    mut version: Version,
+    extension: Extension,
+    dst_path: &Path,
+    dst_file: Option<&Path>,
+    modifiers: &str,
+    opts: ExtractOptions,
+) -> Result<BuildRoot> {
+    let dst_path = Tarball::resolve_dst_root(dst_path, opts.follow_symlinks)?;
+    let extension = series_extension(version, extension);
+
+    // If we only have major.minor just resolve patch if we can
+    let downloads = DownloadList::new(version.major, version.minor, extension).with_pre(opts.pre);
+    version.resolve_latest(&downloads).await?;
+
+    // Settle any conflict first, there's nothing to download for a tree we reuse
+    let existing = Tarball::check_dst_path(version, &dst_path, dst_file)?;
+    let resolution = existing
+        .map(|path| conflict::resolve_interactive(&path, opts.on_conflict, OnConflict::Abort))
+        .transpose()?;
+
+    // A suffixed path is absolute, so it replaces the root when joined onto it
+    let (dst_file, reused) = match resolution {
+        Some(Resolution::Reuse(path)) => {
+            let root = reusable_root(&path, version).context(format!(
+                "{path:?} exists but isn't a PHP {version} build root"
+            ))?;
+            check_libc(&root)?;
+            eprintln!("{}", msg!(Msg::Reusing, path = format!("{:?}", root.src)));
+            (None, Some(root))
+        }
+        Some(Resolution::Write(path)) => (Some(path), None),
+        None => (dst_file.map(Path::to_path_buf), None),
+    };
+
+    // A reused tree keeps whatever compatibility fixes it was extracted with
+    let (extracted_path, patches, tarball) = if let Some(root) = &reused {
+        (
+            root.src.to_string_lossy().into_owned(),
+            root.source().map(|s| s.patches).unwrap_or_default(),
+            None,
+        )
+    } else {
+        let tarball = fetch_tarball(version, extension).await?;
+        let (path, patches) =
+            unpack_tarball(version, &tarball, &dst_path, dst_file.as_deref(), opts).await?;
+        (path, patches, Some(tarball))
+    };
+
+    if !opts.hooks.is_empty() {
+        for warning in compat::check(version) {
+            eprintln!("Warning:  {warning}");
+        }
+
+        let path = extracted_path.clone();
+        let cflags = compat::cflags(&patches);
+        let hooks = opts.hooks;
+
+        blocking(move || {
+            let vars = Template::for_build(version, Path::new(&path))?;
+
+            for hook in [Hook::PostExtract, Hook::Configure, Hook::Make]
+                .into_iter()
+                .filter(|&h| hooks.contains(h))
+            {
+                let res = Hook::exec(hook, &*path, &[&path], &vars, cflags.as_deref())?;
+                validate_hook(hook, &res)?;
+            }
+
+            Ok(())
+        })
+        .await?;
+    }
+
+    // Its manifest already tells the user's files apart from the release's, rewriting it would
+    // make them look like part of the release
+    let Some(tarball) = tarball else {
+        return reused.context("Reused build root went missing");
+    };
+
+    // The directory name may come from a template we can't parse, but we know the version, and
+    // the modifiers unless they're only in the name
+    let modifiers = match BuildRoot::from_path(&extracted_path) {
+        Ok(named) if modifiers.is_empty() => named.modifiers,
+        _ => modifiers.to_string(),
+    };
+    let root = BuildRoot::new(&extracted_path, version, &modifiers);
+
+    let (loc, files) = {
+        let (root, tarball) = (root.clone(), tarball.clone());
+
+        blocking(move || {
+            let source = SourceInfo {
+                version: Some(root.version),
+                modifiers: Some(root.modifiers.clone()),
+                libc: Some(Platform::libc().to_string()),
+                patches,
+                ..tarball.source_info()?
+            };
+            root.save_source(&source)?;
+            root.save_manifest()
+        })
+        .await?
+    };
+    eprintln!(
+        "{}",
+        msg!(Msg::SavedManifest, path = format!("{loc:?}"), files = files)
+    );
+
+    if opts.rm_tarball {
+        let path = tarball.remove()?;
+        audit::record("remove-tarball", &path, Confirmation::Implicit);
+        eprintln!("{}", msg!(Msg::RemovedTarball, path = format!("{path:?}")));
+    }
+
+    Ok(root)
+}
+
+#[allow(clippy::literal_string_with_formatting_args)]
+async fn op_get(
+    version: Version,
+    extension: Extension,
+    dst_path: &Path,
+    install: bool,
+    opts: ExtractOptions,
+) -> Result<()> {
+    let root = op_extract(version, extension, dst_path, None, "", opts).await?;
+    let path = root.src.clone();
+    let vars = Template::for_build(root.version, &path)?;
+
+    let installed = install && opts.hooks.contains(Hook::Install);
+
+    let php = if installed {
+        let cflags = root.source().and_then(|s| compat::cflags(&s.patches));
+        let installed = vars.expand("{prefix}/bin/php")?;
+
+        let dir = path.clone();
+        blocking(move || {
+            let res = Hook::exec(
+                Hook::Install,
+                &dir,
+                &[&dir.to_string_lossy()],
+                &vars,
+                cflags.as_deref(),
+            )?;
+            validate_hook(Hook::Install, &res)
+        })
+        .await?;
+
+        PathBuf::from(installed)
+    } else {
+        path.join("sapi/cli/php")
+    };
+
+    if php.exists() {
+        eprintln!(
+            "{}",
+            msg!(
+                Msg::PhpInstalled,
+                version = root.version,
+                path = php.display()
+            )
+        );
+    } else {
+        eprintln!(
+            "{}",
+            msg!(
+                Msg::PhpSources,
+                version = root.version,
+                path = path.display()
+            )
+        );
+    }
+
+    if installed && php.exists() {
+        let prefix = php.parent().and_then(Path::parent).unwrap_or(&php);
+        match activation::write(root.version, prefix) {
+            Ok(()) => eprintln!("{}", msg!(Msg::PhpActivate, version = root.version)),
+            Err(e) => eprintln!("Warning:  {e:#}"),
+        }
+    }
+
+    if installed {
+        reload_services(root.version, &path, php, opts.hooks).await?;
+    }
+
+    Ok(())
+}
+
+// The newest install matching `version`, which should have had any alias resolved.  A prefix
+// like /opt/php/{version} can't be worked back from a series or constraint, so we try the
+// versions we've built or downloaded.
+fn installed_prefix(version: &VersionArg) -> Result<(Version, PathBuf)> {
+    let template = Config::settings()
+        .prefix
+        .as_deref()
+        .unwrap_or(Template::DEFAULT_PREFIX);
+
+    let mut candidates: Vec<_> = Tarball::list(&Config::registry_path()?)?
+        .tarballs
+        .into_iter()
+        .map(|t| t.version)
+        .chain(
+            BuildRoot::from_parent_path(Config::workspace_path()?, 1)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|root| root.version),
+        )
+        .filter(|&v| version.matches(v))
+        .collect();
+
+    candidates.sort_unstable_by(|a, b| b.cmp(a));
+    candidates.dedup();
+    if let VersionArg::Version(version) = version {
+        candidates.push(*version);
+    }
+
+    // Different versions may share a prefix, so ask what's actually installed there
+    let mut tried = vec![];
+
+    for candidate in candidates {
+        let prefix = PathBuf::from(Template::for_version(candidate).expand(template)?);
+        if tried.contains(&prefix) {
+            continue;
+        }
+
+        if let Some(installed) =
+            php_version(&prefix.join("bin/php")).filter(|&v| version.matches(v))
+        {
+            return Ok((installed, prefix));
+        }
+        tried.push(prefix);
+    }
+
+    bail!(msg!(Msg::NotInstalled, version = version))
+}
+
+// What `php` says it is, or the first thing it said instead if it doesn't run
+fn report_version(php: &Path) -> std::result::Result<Version, String> {
+    let output = Command::new(php)
+        .args(["-r", "echo PHP_VERSION;"])
+        .output()
+        .map_err(|e| e.to_string())?;
+
+    let reported = String::from_utf8_lossy(&output.stdout);
+    let reported = reported.trim();
+
+    match reported.parse() {
+        Ok(version) if output.status.success() => Ok(version),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(stderr.lines().next().unwrap_or(reported).to_string())
+        }
+    }
+}
+
+// What `php` says it is, `None` if it doesn't run
+fn php_version(php: &Path) -> Option<Version> {
+    report_version(php).ok()
+}
+
+fn op_env(version: &VersionArg, shell: Option<Shell>) -> Result<()> {
+    let shell = shell.unwrap_or_else(Shell::detect);
+    let (version, prefix) = installed_prefix(version)?;
+
+    // Installs from before we wrote these still get a script
+    let script =
+        fs::read_to_string(shell.path(&prefix)).unwrap_or_else(|_| shell.script(version, &prefix));
+
+    print!("{script}");
+
+    Ok(())
+}
+
+// Run `php` the way a service would and make sure it's the version we just built
+fn smoke_test(php: &Path, version: Version) -> Result<()> {
+    let output = match report_version(php) {
+        Ok(reported) if reported == version => return Ok(()),
+        Ok(reported) => reported.to_string(),
+        Err(output) => output,
+    };
+
+    bail!(msg!(
+        Msg::SmokeTestFailed,
+        path = php.display(),
+        version = version,
+        output = output
+    ))
+}
+
+/// Run the reload hook, e.g. `systemctl reload php-fpm@{series}`, once a freshly installed `php`
+/// passes its smoke test.  Nothing is smoke tested without a reload hook to run.
+async fn reload_services(
+    version: Version,
+    build_root: &Path,
+    php: PathBuf,
+    hooks: HookSet,
+) -> Result<()> {
+    if !hooks.contains(Hook::Reload) {
+        return Ok(());
+    }
+
+    let dir = build_root.to_path_buf();
+
+    blocking(move || {
+        if !Hook::is_configured(Hook::Reload, &dir)? {
+            return Ok(());
+        }
+
+        smoke_test(&php, version)?;
+
+        let php = php.to_string_lossy();
+        let vars = Template::for_build(version, &dir)?.set("php", &*php);
+        let res = Hook::exec(Hook::Reload, &dir, &[&php], &vars, None)?;
+        validate_hook(Hook::Reload, &res)
+    })
+    .await
+}
+
+// Find the newest upstream version of each series.  Each series is only looked up once, and the
+// listing cache takes care of repeated runs.
+async fn latest_versions<I>(series: I) -> HashMap<(u8, u8, Extension), Option<Version>>
+where
+    I: IntoIterator<Item = (u8, u8, Extension)>,
+{
+    let mut latest = HashMap::new();
+
+    for key @ (major, minor, extension) in series {
+        if let Entry::Vacant(entry) = latest.entry(key) {
+            let version = match DownloadList::new(major, minor, extension).latest().await {
+                Ok(dl) => dl.map(|dl| dl.version),
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        msg!(
+                            Msg::UpdateCheckFailed,
+                            series = format!("{major}.{minor}"),
+                            error = e
+                        )
+                    );
+                    None
+                }
+            };
+
+            entry.insert(version);
+        }
+    }
+
+    latest
+}
+
+async fn op_roots(
+    path: &Path,
+    version: Option<VersionArg>,
+    outdated: bool,
+    depth: usize,
+    extension: Extension,
+    viewer: &(dyn Viewer + Send),
+) -> Result<()> {
+    let mut roots: Vec<_> = BuildRoot::discover(path, depth)?
+        .into_iter()
+        .filter(|root| VersionArg::optional_matches(version.as_ref(), root.version))
+        .collect();
+
+    roots.sort_unstable();
+
+    let latest = latest_versions(
+        roots
+            .iter()
+            .map(|r| (r.version.major, r.version.minor, extension)),
+    )
+    .await;
+
+    let roots: Vec<_> = roots
+        .into_iter()
+        .map(|root| {
+            let newest = latest
+                .get(&(root.version.major, root.version.minor, extension))
+                .copied()
+                .flatten();
+
+            root_info(root, newest)
+        })
+        .filter(|info| !outdated || info.latest.is_some_and(|l| l > info.version))
+        .collect();
+
+    viewer.display_roots(&roots);
+
+    Ok(())
+}
+
+fn root_info(root: BuildRoot, latest: Option<Version>) -> RootInfo {
+    RootInfo {
+        size: root.size(),
+        source: root.source(),
+        pinned: root.is_pinned(),
+        path: root.src,
+        version: root.version,
+        modifiers: root.modifiers,
+        latest,
+    }
+}
+
+fn eol_warning(eol: Eol) -> String {
+    let series = format!("{}.{}", eol.major, eol.minor);
+    eol.since.map_or_else(
+        || msg!(Msg::EolWarning, series = series),
+        |date| msg!(Msg::EolWarningSince, series = series, date = date),
+    )
+}
+
+/// Warn prominently about a series that no longer gets security fixes, or refuse it outright
+/// with `deny-eol` unless `--allow-eol` was given.  Not knowing is only worth a warning.
+async fn check_eol(version: Version) -> Result<()> {
+    if version.is_snapshot() {
+        return Ok(());
+    }
+
+    match DownloadList::eol(version.major, version.minor).await {
+        Ok(Some(_)) if Config::deny_eol() => bail!(msg!(
+            Msg::EolRefused,
+            series = format!("{}.{}", version.major, version.minor)
+        )),
+        Ok(Some(eol)) => progress::note(eol_warning(eol).red().bold()),
+        Ok(None) => {}
+        Err(e) => progress::note(msg!(
+            Msg::EolCheckFailed,
+            series = format!("{}.{}", version.major, version.minor),
+            error = e
+        )),
+    }
+
+    Ok(())
+}
+
+/// Whether each series is end of life, `None` where we couldn't find out.  Decided by
+/// `DownloadList::eol`, so a series that isn't out yet doesn't count.
+async fn eol_series<I>(series: I) -> HashMap<(u8, u8), Option<bool>>
+where
+    I: IntoIterator<Item = (u8, u8)>,
+{
+    let mut eol = HashMap::new();
+
+    for key @ (major, minor) in series {
+        if let Entry::Vacant(entry) = eol.entry(key) {
+            entry.insert(match DownloadList::eol(major, minor).await {
+                Ok(state) => Some(state.is_some()),
+                Err(e) => {
+                    eprintln!(
+                        "{}",
+                        msg!(
+                            Msg::EolCheckFailed,
+                            series = format!("{major}.{minor}"),
+                            error = e
+                        )
+                    );
+                    None
+                }
+            });
+        }
+    }
+
+    eol
+}
+
+/// Where `version` stands given the newest release in its series and whether the series is end
+/// of life, if we know them.
+fn version_status(version: Version, latest: Option<Version>, eol: Option<bool>) -> VersionStatus {
+    if version.is_snapshot() {
+        return VersionStatus::Unknown;
+    }
+
+    if eol == Some(true) {
+        return VersionStatus::Eol;
+    }
+
+    match latest {
+        Some(latest) if latest > version => VersionStatus::Outdated,
+        Some(_) => VersionStatus::Current,
+        None => VersionStatus::Unknown,
+    }
+}
+
+/// List the registry.  With `check_updates` each tarball is compared against php.net, and
+/// `problems_only` narrows that down to the outdated and end of life ones.
+async fn op_cached(
+    version: Option<VersionArg>,
+    check_updates: bool,
+    problems_only: bool,
+    viewer: &(dyn Viewer + Send),
+) -> Result<()> {
+    let mut tarballs: Vec<_> = Tarball::list(&Config::registry_path()?)?
+        .report()
+        .into_iter()
+        .filter(|fi| VersionArg::optional_matches(version.as_ref(), fi.version))
+        .collect();
+
+    tarballs.sort_by_key(|t| t.version);
+
+    if !check_updates && !problems_only {
+        viewer.display(&tarballs);
+        return Ok(());
+    }
+
+    let eol = eol_series(
+        tarballs
+            .iter()
+            .filter(|t| !t.version.is_snapshot())
+            .map(|t| (t.version.major, t.version.minor)),
+    )
+    .await;
+
+    let latest = latest_versions(
+        tarballs
+            .iter()
+            .filter(|t| !t.version.is_snapshot())
+            .map(|t| (t.version.major, t.version.minor, t.extension)),
+    )
+    .await;
+
+    let data: Vec<_> = tarballs
+        .into_iter()
+        .map(|t| {
+            let newest = latest
+                .get(&(t.version.major, t.version.minor, t.extension))
+                .copied()
+                .flatten();
+            let series = (t.version.major, t.version.minor);
+            let status = version_status(t.version, newest, eol.get(&series).copied().flatten());
+            (t, newest, status)
+        })
+        .filter(|(.., status)| !problems_only || status.is_problem())
+        .collect();
+
+    viewer.display_updates(&data);
+
+    Ok(())
+}
+
+// Plumbing for completion scripts, bare versions with no decoration
+fn op_list_versions_local() -> Result<()> {
+    let mut versions: Vec<_> = Tarball::list(&Config::registry_path()?)?
+        .tarballs
+        .into_iter()
+        .map(|t| t.version)
+        .collect();
+
+    versions.sort_unstable();
+    versions.dedup();
+
+    for version in versions {
+        println!("{version}");
+    }
+
+    Ok(())
+}
+
+async fn op_list_versions_remote(which: RemoteVersions, extension: Extension) -> Result<()> {
+    let releases = DownloadList::active_releases().await?;
+
+    for release in releases {
+        match which {
+            RemoteVersions::Active => {
+                if let Some(version) = release.latest {
+                    println!("{version}");
+                }
+            }
+            RemoteVersions::All => {
+                let list = DownloadList::new(release.major, release.minor, extension)
+                    .list()
+                    .await?;
+                for info in list {
+                    println!("{}", info.version);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn op_latest(
+    version: Option<VersionArg>,
+    extension: Extension,
+    pre: bool,
+    quiet: bool,
+    viewer: &(dyn Viewer + Send),
+) -> Result<()> {
+    let urls = match version {
+        Some(VersionArg::Req(req)) => req.latest(extension, pre).await?,
+        version => {
+            let versions = match version {
+                Some(arg) => {
+                    let v = arg.resolve(extension, pre).await?;
+                    vec![(v.major, v.minor)]
+                }
+                None => DownloadList::active_series().await.unwrap_or_else(|e| {
+                    eprintln!("{}", msg!(Msg::ActiveFailed, error = e));
+                    vec![(7, 4), (8, 0), (8, 1), (8, 2), (8, 3)]
+                }),
+            };
+
+            let mut urls = vec![];
+
+            for (major, minor) in versions {
+                let latest = DownloadList::new(major, minor, extension)
+                    .with_pre(pre)
+                    .latest()
+                    .await?;
+                if let Some(latest) = latest {
+                    urls.push(latest);
+                }
+            }
+
+            urls
+        }
+    };
+
+    if quiet {
+        for url in &urls {
+            println!("{}", url.version);
+        }
+    } else {
+        viewer.display(&urls);
+    }
+
+    Ok(())
+}
+
+async fn op_list(
+    version: Option<VersionArg>,
+    extension: Extension,
+    include_qa: bool,
+    viewer: &(dyn Viewer + Send),
+) -> Result<()> {
+    let urls = match version {
+        Some(VersionArg::Req(req)) => req.list(extension, include_qa).await?,
+        version => {
+            let version = match version {
+                Some(arg) => arg.resolve(extension, false).await?,
+                None => Version::from_major_minor(NEW_MAJOR, NEW_MINOR),
+            };
+            DownloadList::new(version.major, version.minor, extension)
+                .with_qa(include_qa)
+                .list()
+                .await?
+        }
+    };
+
+    viewer.display(&urls);
+
+    let mut series: Vec<_> = urls
+        .iter()
+        .map(|dl| (dl.version.major, dl.version.minor))
+        .collect();
+    series.dedup();
+
+    for (major, minor) in series {
+        if let Ok(Some(eol)) = DownloadList::eol(major, minor).await {
+            eprintln!("{}", eol_warning(eol).red().bold());
+        }
+    }
+
+    Ok(())
+}
+
+async fn op_resolve(mut version: Version, extension: Extension, pre: bool) -> Result<()> {
+    let downloads = DownloadList::new(version.major, version.minor, extension).with_pre(pre);
+    version.resolve_latest(&downloads).await?;
+
+    println!("{version}");
+
+    Ok(())
+}
+
+/// The newest release of every series php.net supports, for `download --all-active`.
+async fn active_targets() -> Result<Vec<DownloadTarget>> {
+    Ok(DownloadList::active_releases()
+        .await?
+        .into_iter()
+        .map(|release| {
+            let version = release
+                .latest
+                .unwrap_or_else(|| Version::from_major_minor(release.major, release.minor));
+            DownloadTarget::Release(version)
+        })
+        .collect())
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn op_download_snapshot(
+    snapshot: &Snapshot,
+    path: &Path,
+    on_conflict: Option<OnConflict>,
+) -> Result<Version> {
+    let (dl, commit) = snapshot.download_info().await?;
+    let version = dl.version;
+    eprintln!(
+        "{}",
+        msg!(
+            Msg::SnapshotVersion,
+            snapshot = snapshot,
+            version = version,
+            commit = snapshot::short(&commit)
+        )
+    );
+
+    let dst = path.join(version.get_file_name(dl.extension));
+
+    // The branch moves on, so unlike a release an existing snapshot is replaced by default
+    match conflict::resolve(&dst, on_conflict, OnConflict::Overwrite)? {
+        Resolution::Reuse(dst) => eprintln!("{version}\t{dst:?}"),
+        Resolution::Write(dst) => {
+            // GitHub neither signs nor publishes checksums for these
+            progress::note(msg!(Msg::SnapshotUnverified, snapshot = snapshot));
+            dl.download_to_file(&dst).await?;
+            snapshot::save_commit(&dst, &commit)?;
+        }
+    }
+
+    Ok(version)
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn op_download_one(
+    target: &DownloadTarget,
+    path: &Path,
+    extension: Extension,
+    on_conflict: Option<OnConflict>,
+    pre: bool,
+    queue: bool,
+) -> Result<Version> {
+    let mut version = match target {
+        DownloadTarget::Release(version) => *version,
+        DownloadTarget::Constraint(req) => req.resolve(extension, pre).await?,
+        DownloadTarget::Alias(alias) => alias.resolve(extension, pre).await?,
+        DownloadTarget::Snapshot(snapshot) => {
+            return op_download_snapshot(snapshot, path, on_conflict).await
+        }
+    };
+
+    let extension = series_extension(version, extension);
+    let downloads = DownloadList::new(version.major, version.minor, extension).with_pre(pre);
+
+    // Resolve to the actual major.minor.patch (if needed)
+    version.resolve_latest(&downloads).await?;
+    check_eol(version).await?;
+
+    let dst = path.join(version.get_file_name(extension));
+
+    // An existing tarball is normally just a cache hit
+    match conflict::resolve(&dst, on_conflict, OnConflict::Reuse)? {
+        Resolution::Reuse(dst) => eprintln!("{version}\t{dst:?}"),
+        Resolution::Write(dst) => {
+            let res = async {
+                downloads
+                    .get(version)
+                    .await?
+                    .context(format!("Unable to get download URL for PHP {version}"))?
+                    .download_to_file(&dst)
+                    .await
+            };
+
+            // Failures in a batch are queued for `resume-all`
+            match res.await {
+                Ok(_) if queue => queue::remove(version, extension),
+                Ok(_) => {}
+                Err(e) => {
+                    if queue {
+                        let registry = Config::registry_path()?;
+                        let dir = (path != registry).then_some(path);
+                        queue::push(version, extension, dir, &e);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    Ok(version)
+}
+
+/// Download `versions` into `path`, up to `parallel` at a time, returning the resolved versions
+/// in the order they were given, then extract each into `extract`'s directory if given.  A single
+/// failure is returned as is, in a batch every failure is reported and queued for `resume-all`,
+/// and the rest carry on.
+async fn op_download(
+    versions: &[DownloadTarget],
+    path: &Path,
+    extension: Extension,
+    on_conflict: Option<OnConflict>,
+    pre: bool,
+    parallel: usize,
+    extract: Option<(&Path, ExtractOptions)>,
+) -> Result<Vec<Version>> {
+    let batch = versions.len() > 1;
+    let mut results: Vec<_> = stream::iter(versions)
+        .map(|target| op_download_one(target, path, extension, on_conflict, pre, batch))
+        .buffered(parallel.max(1))
+        .collect()
+        .await;
+
+    if let Some((dir, opts)) = extract {
+        for res in &mut results {
+            if let Ok(version) = *res {
+                // The download already noted any switch to the series' format
+                let extension = extension.for_series(version.major, version.minor);
+                if let Err(e) = op_extract(version, extension, dir, None, "", opts).await {
+                    *res = Err(e);
+                }
+            }
+        }
+    }
+
+    if !batch {
+        return results.into_iter().collect();
+    }
+
+    let done = summarize(versions.iter().zip(results));
+
+    if done.len() < versions.len() {
+        let failed = if extract.is_some() {
+            Msg::ExtractsFailed
+        } else {
+            Msg::DownloadsFailed
+        };
+        bail!(msg!(
+            failed,
+            count = versions.len() - done.len(),
+            total = versions.len()
+        ));
+    }
+
+    Ok(done)
+}
+
+/// Print how each item of a batch went, returning what succeeded.
+fn summarize<D: fmt::Display, T: fmt::Display>(
+    results: impl Iterator<Item = (D, Result<T>)>,
+) -> Vec<T> {
+    eprintln!("{}", msg!(Msg::BatchSummary));
+
+    let mut succeeded = vec![];
+    for (item, res) in results {
+        match res {
+            Ok(value) => {
+                eprintln!(
+                    "{}",
+                    msg!(Msg::BatchSucceeded, item = item, result = &value)
+                );
+                succeeded.push(value);
+            }
+            Err(e) => eprintln!(
+                "{}",
+                msg!(Msg::BatchFailed, item = item, error = format!("{e:#}"))
+            ),
+        }
+    }
+
+    succeeded
+}
+
+/// Extract several versions into `path`.  Their tarballs are resolved and fetched up to
+/// `parallel` at a time, the extractions and their hooks run one after another.
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn op_extract_many(
+    versions: &[VersionArg],
+    extension: Extension,
+    path: &Path,
+    parallel: usize,
+    opts: ExtractOptions,
+) -> Result<()> {
+    let fetched: Vec<_> = stream::iter(versions)
+        .map(|arg| async move {
+            let mut version = arg.resolve(extension, opts.pre).await?;
+            let extension = series_extension(version, extension);
+            let downloads =
+                DownloadList::new(version.major, version.minor, extension).with_pre(opts.pre);
+            version.resolve_latest(&downloads).await?;
+            check_eol(version).await?;
+            fetch_tarball(version, extension).await?;
+            Ok((version, extension))
+        })
+        .buffered(parallel.max(1))
+        .collect()
+        .await;
+
+    let mut results = vec![];
+    for res in fetched {
+        results.push(match res {
+            Ok((version, extension)) => op_extract(version, extension, path, None, "", opts)
+                .await
+                .map(|root| format!("{:?}", root.src)),
+            Err(e) => Err(e),
+        });
+    }
+
+    let extracted = summarize(versions.iter().zip(results));
+
+    if extracted.len() < versions.len() {
+        bail!(msg!(
+            Msg::ExtractsFailed,
+            count = versions.len() - extracted.len(),
+            total = versions.len()
+        ));
+    }
+
+    Ok(())
+}
+
+async fn op_upgrade_root(
+    root: &BuildRoot,
+    extension: Extension,
+    opts: ExtractOptions,
+) -> Result<Option<BuildRoot>> {
+    if root.is_pinned() {
+        eprintln!("{}", msg!(Msg::PinnedSkip, version = root.version));
+        return Ok(None);
+    }
+
+    check_writable_dir(&root.parent(), opts.follow_symlinks).map_err(|e| anyhow!(e))?;
+
+    let latest = DownloadList::new(root.version.major, root.version.minor, extension)
+        .with_pre(opts.pre)
+        .latest()
+        .await?
+        .context("Can't find latest version")?;
+
+    if latest.version > root.version {
+        eprintln!("    {} -> {}", root.version, latest.version);
+    } else {
+        eprintln!("{}", msg!(Msg::UpToDate, version = root.version));
+        return Ok(None);
+    }
+
+    upgrade_root_to(root, latest.version, latest.extension, opts)
+        .await
+        .map(Some)
+}
+
+/// Extract `version` next to `root` and carry its scripts over.
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn upgrade_root_to(
+    root: &BuildRoot,
+    version: Version,
+    extension: Extension,
+    opts: ExtractOptions,
+) -> Result<BuildRoot> {
+    // Fetch the tarball before touching anything so a failed download is just queued
+    fetch_tarball(version, extension).await?;
+
+    // A `dir-name` template could give the new version the old root's name
+    let name = PathBuf::from(root.version_path_name(version)?);
+    if root.parent().join(&name) == root.src {
+        bail!(
+            "Upgrading {:?} to PHP {version} would extract over it, check the dir-name setting",
+            root.src
+        );
+    }
+
+    let res = op_extract(
+        version,
+        extension,
+        &root.parent(),
+        Some(&name),
+        &root.modifiers,
+        opts,
+    )
+    .await?;
+
+    let mut extracted_path = res.src.clone();
+
+    let backup_path = root
+        .src
+        .file_name()
+        .context("Build root has no file name")?
+        .to_string_lossy();
+
+    extracted_path.push(format!("{}{BACKUP_SUFFIX}", &*backup_path));
+
+    eprintln!("{}", msg!(Msg::BackingUpScripts));
+    match root.save_scripts(&extracted_path) {
+        Ok(_) => {
+            if let Err(e) = res.record_backup(&extracted_path, root) {
+                eprintln!(
+                    "{}",
+                    msg!(Msg::BackupRecordFailed, error = format!("{e:#}"))
+                );
+            }
+        }
+        Err(e) => eprintln!("{}", msg!(Msg::BackupFailed, error = format!("{e:?}"))),
+    }
+
+    Ok(res)
+}
+
+// The hooks an upgrade of `root` would run, in the order it runs them
+fn planned_hooks(root: &BuildRoot, hooks: HookSet) -> Result<Vec<Hook>> {
+    let mut planned = vec![];
+
+    for hook in [Hook::PostExtract, Hook::Configure, Hook::Make] {
+        if hooks.contains(hook) && Hook::is_configured(hook, &root.src)? {
+            planned.push(hook);
+        }
+    }
+
+    Ok(planned)
+}
+
+/// Print what `upgrade` would do as JSON, to be reviewed and carried out with `--apply`.
+async fn op_upgrade_plan(
+    path: &Path,
+    scope: UpgradeScope,
+    depth: usize,
+    extension: Extension,
+    opts: ExtractOptions,
+) -> Result<()> {
+    let mut roots = upgrade_roots(path, scope, depth)?;
+    roots.sort_unstable();
+
+    let mut upgrades = vec![];
+
+    for root in roots.into_iter().filter(|root| !root.is_pinned()) {
+        let Some(latest) = DownloadList::new(root.version.major, root.version.minor, extension)
+            .with_pre(opts.pre)
+            .latest()
+            .await?
+            .filter(|latest| latest.version > root.version)
+        else {
+            continue;
+        };
+
+        let cached = Tarball::new(latest.version, latest.extension).is_ok();
+
+        upgrades.push(PlannedUpgrade {
+            extract_to: root.parent().join(root.version_path_name(latest.version)?),
+            hooks: planned_hooks(&root, opts.hooks)?,
+            will_remove: vec![root.src.clone()],
+            path: root.src,
+            current: root.version,
+            target: latest.version,
+            extension: latest.extension,
+            download_size: if cached { 0 } else { latest.size },
+        });
+    }
+
+    let plan = UpgradePlan {
+        created: Utc::now().to_rfc3339(),
+        upgrades,
+    };
+
+    println!("{}", serde_json::to_string_pretty(&plan)?);
+
+    Ok(())
+}
+
+/// Carry out a plan from `upgrade --plan`.  Reviewing it was the confirmation, so the old roots
+/// it lists are removed without asking.  Roots that changed since are skipped.
+async fn op_upgrade_apply(plan: &Path, opts: ExtractOptions) -> Result<()> {
+    let plan = plan::load(plan)?;
+    let mut failed = 0;
+
+    for (n, planned) in plan.upgrades.iter().enumerate() {
+        eprintln!(
+            "{}",
+            msg!(
+                Msg::Upgrading,
+                n = 1 + n,
+                path = format!("{:?}", planned.path)
+            )
+        );
+
+        if let Err(e) = apply_planned(planned, opts).await {
+            eprintln!("    Warning: {e:?}");
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        bail!(msg!(
+            Msg::PlanFailed,
+            count = failed,
+            total = plan.upgrades.len()
+        ));
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn apply_planned(planned: &PlannedUpgrade, opts: ExtractOptions) -> Result<()> {
+    if !planned.path.is_dir() {
+        bail!("{:?} no longer exists", planned.path);
+    }
+
+    let root = BuildRoot::from_path(&planned.path)?;
+    if root.version != planned.current || root.is_pinned() {
+        bail!(msg!(
+            Msg::PlanStale,
+            path = format!("{:?}", root.src),
+            version = root.version,
+            planned = planned.current
+        ));
+    }
+
+    if let Some(path) = planned.will_remove.iter().find(|&path| *path != root.src) {
+        bail!("The plan can only remove the root it upgrades, not {path:?}");
+    }
+
+    eprintln!("    {} -> {}", planned.current, planned.target);
+    let opts = ExtractOptions {
+        hooks: planned.hook_set(),
+        ..opts
+    };
+    upgrade_root_to(&root, planned.target, planned.extension, opts).await?;
+
+    if !planned.will_remove.is_empty() {
+        eprint!("{}", msg!(Msg::Removing, path = format!("{:?}", root.src)));
+        let path = root.src.clone();
+        blocking(move || root.remove()).await?;
+        audit::record("remove-root", &path, Confirmation::Implicit);
+        eprintln!("{}", msg!(Msg::Done));
+    }
+
+    Ok(())
+}
+
+fn op_doctor(version: Option<Version>) -> Result<()> {
+    println!(
+        "{} {} on {}/{}",
+        env!("CARGO_BIN_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+
+    println!("{:<12}{}", "libc", Platform::libc());
+
+    let mut missing = 0;
+
+    for tool in [Platform::shell(), Platform::make()]
+        .into_iter()
+        .chain(platform::BUILD_TOOLS)
+    {
+        if let Some(path) = Platform::which(tool) {
+            println!("{tool:<12}{}", path.display().to_string().green());
+        } else {
+            println!("{tool:<12}{}", "missing".red());
+            missing += 1;
+        }
+    }
+
+    let registry = Config::registry_path()?;
+    let status = if Config::is_writable(&registry) {
+        "writable".green()
+    } else {
+        "not writable".red()
+    };
+    println!("{:<12}{} ({status})", "registry", registry.display());
+
+    if missing > 0 {
+        if let Some(hint) = Platform::install_hint() {
+            println!("\nInstall missing tools with: {hint}");
+        }
+    }
+
+    check_php_path(version);
+
+    if let Some(version) = version {
+        for warning in compat::check(version) {
+            println!("{} {warning}", "Warning:".yellow());
+        }
+    }
+
+    Ok(())
+}
+
+/// Where `get --install` puts `php` for `version`, or for every version when the prefix doesn't
+/// depend on it.
+fn managed_bin(version: Option<Version>) -> Option<PathBuf> {
+    let prefix = Config::settings()
+        .prefix
+        .as_deref()
+        .unwrap_or(Template::DEFAULT_PREFIX);
+
+    let vars = version.map_or_else(Template::default, Template::for_version);
+    vars.expand(prefix).ok().map(|p| Path::new(&p).join("bin"))
+}
+
+// Explain why `php -v` might not be the PHP we installed, the usual cause being another php
+// earlier in PATH.
+fn check_php_path(version: Option<Version>) {
+    let found = Platform::which_all("php");
+
+    for (n, php) in found.iter().enumerate() {
+        let label = if n == 0 { "php" } else { "" };
+        println!("{label:<12}{}", php.display());
+    }
+
+    let Some(bin) = managed_bin(version) else {
+        return;
+    };
+    let managed = bin.join("php");
+    if !managed.is_file() {
+        return;
+    }
+
+    let same = |a: &Path| {
+        a.canonicalize()
+            .ok()
+            .is_some_and(|a| managed.canonicalize().is_ok_and(|m| m == a))
+    };
+
+    let fix = format!("export PATH=\"{}:$PATH\"", bin.display());
+
+    match found.first() {
+        Some(first) if same(first) => {}
+        Some(first) if found.iter().any(|p| same(p)) => {
+            println!(
+                "{} {} comes before {} in PATH, so `php` runs it instead",
+                "Warning:".yellow(),
+                first.display(),
+                managed.display()
+            );
+            println!(
+                "Put {} first, e.g. in your shell profile: {fix}, then run `hash -r`",
+                bin.display()
+            );
+        }
+        _ => {
+            println!(
+                "{} {} isn't in PATH, so `php` won't run {}",
+                "Warning:".yellow(),
+                bin.display(),
+                managed.display()
+            );
+            println!("Add it, e.g. in your shell profile: {fix}");
+        }
+    }
+}
+
+async fn op_active(viewer: &(dyn Viewer + Send)) -> Result<()> {
+    let releases = DownloadList::active_releases().await?;
+    viewer.display_active(&releases);
+    Ok(())
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+fn op_migrate(dry_run: bool) -> Result<()> {
+    let path = Config::settings_path()?;
+    let Some(mut json) = Config::read_settings_json()? else {
+        eprintln!("{}", msg!(Msg::NoSettings, path = format!("{path:?}")));
+        return Ok(());
+    };
+
+    let Some(changes) = migrate::migrate(&mut json) else {
+        eprintln!("{}", msg!(Msg::MigrateUpToDate, path = format!("{path:?}")));
+        return Ok(());
+    };
+
+    for change in &changes {
+        eprintln!("    {change}");
+    }
+    eprintln!(
+        "    Set {} to {}",
+        migrate::VERSION_KEY,
+        migrate::CONFIG_VERSION
+    );
+
+    if dry_run {
+        eprintln!("{}", msg!(Msg::MigrateDryRun, path = format!("{path:?}")));
+        return Ok(());
+    }
+
+    let backup = path.with_extension("json.bak");
+    fs::copy(&path, &backup).context(format!("Unable to back up {path:?}"))?;
+    let data = serde_json::to_string_pretty(&json)? + "\n";
+    cache::write_atomic(&path, data.as_bytes())?;
+
+    eprintln!(
+        "{}",
+        msg!(
+            Msg::Migrated,
+            path = format!("{path:?}"),
+            backup = format!("{backup:?}")
+        )
+    );
+
+    Ok(())
+}
+
+fn op_mirror_export(dir: &Path) -> Result<()> {
+    let count = mirror::export(dir)?;
+    eprintln!(
+        "{}",
+        msg!(Msg::Exported, count = count, path = format!("{dir:?}"))
+    );
+    Ok(())
+}
+
+async fn op_resume_all() -> Result<()> {
+    let queue = queue::load()?;
+
+    if queue.is_empty() {
+        eprintln!("{}", msg!(Msg::NothingQueued));
+        return Ok(());
+    }
+
+    let mut failed = vec![];
+
+    for mut entry in queue {
+        eprintln!(
+            "{}",
+            msg!(
+                Msg::Retrying,
+                version = entry.version,
+                extension = entry.extension
+            )
+        );
+        let res = match &entry.path {
+            Some(path) => {
+                let target = DownloadTarget::Release(entry.version);
+                op_download_one(&target, path, entry.extension, None, true, false)
+                    .await
+                    .map(|_| ())
+            }
+            None => Tarball::get_or_download(entry.version, entry.extension)
+                .await
+                .map(|_| ()),
+        };
+        if let Err(e) = res {
+            eprintln!("    Warning: {e:#}");
+            entry.error = format!("{e:#}");
+            failed.push(entry);
+        }
+    }
+
+    queue::save(&failed)?;
+
+    if !failed.is_empty() {
+        bail!(msg!(Msg::StillFailing, count = failed.len()));
+    }
+
+    eprintln!("{}", msg!(Msg::QueueEmpty));
+
+    Ok(())
+}
+
+async fn op_bench_extract(version: Version, extension: Extension) -> Result<()> {
+    let tarball = Tarball::new(version, series_extension(version, extension))?;
+
+    let mut modes = vec![("built-in", false)];
+    if tarball.has_fast_decoder() {
+        modes.push(("fast", true));
+    }
+
+    for (name, fast) in modes {
+        let t = tarball.clone();
+        let (bytes, elapsed) = blocking(move || t.bench_decode(fast)).await?;
+        let rate = u128::from(bytes) * 1000 / elapsed.as_millis().max(1);
+
+        println!(
+            "{name:<10}{} in {:.2}s ({}/s)",
+            bytes.to_human_size(),
+            elapsed.as_secs_f64(),
+            u64::try_from(rate).unwrap_or(u64::MAX).to_human_size()
+        );
+    }
+
+    Ok(())
+}
+
+async fn op_verify(
+    version: Option<VersionArg>,
+    path: &Path,
+    depth: usize,
+    check_upstream: bool,
+    viewer: &(dyn Viewer + Send),
+) -> Result<()> {
+    let mut tarballs: Vec<_> = Tarball::list(&Config::registry_path()?)?
+        .report()
+        .into_iter()
+        .filter(|t| VersionArg::optional_matches(version.as_ref(), t.version))
+        .collect();
+    tarballs.sort_by_key(|t| t.version);
+
+    let mut changed = HashMap::new();
+    if check_upstream {
+        for info in tarballs.iter().filter(|t| !t.version.is_snapshot()) {
+            match upstream_change(info).await {
+                Ok(Some(detail)) => {
+                    let warning = msg!(
+                        Msg::UpstreamChanged,
+                        version = info.version,
+                        extension = info.extension,
+                        detail = detail
+                    );
+                    eprintln!("{}", warning.red().bold());
+                    changed.insert(PathBuf::from(&info.location), detail);
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!(
+                    "{}",
+                    msg!(
+                        Msg::UpstreamCheckFailed,
+                        version = info.version,
+                        extension = info.extension,
+                        error = format!("{e:#}")
+                    )
+                ),
+            }
+        }
+    }
+
+    let mut roots: Vec<_> = BuildRoot::discover(path, depth)?
+        .into_iter()
+        .filter(|root| VersionArg::optional_matches(version.as_ref(), root.version))
+        .collect();
+    roots.sort_unstable();
+
+    // Hashing every tarball is slow, keep it off the runtime
+    let results = blocking(move || {
+        let tarballs = tarballs.into_iter().map(|info| {
+            let (verdict, detail) = changed.remove(Path::new(&info.location)).map_or_else(
+                || Tarball::from(&info).verify(),
+                |detail| (Verdict::Failed, format!("changed upstream, {detail}")),
+            );
+            VerifyResult {
+                kind: "tarball",
+                version: info.version,
+                path: PathBuf::from(info.location),
+                verdict,
+                detail,
+            }
+        });
+
+        let roots = roots.into_iter().map(|root| {
+            let (verdict, detail) = root.verify();
+            VerifyResult {
+                kind: "root",
+                version: root.version,
+                path: root.src,
+                verdict,
+                detail,
+            }
+        });
+
+        Ok(tarballs.chain(roots).collect::<Vec<_>>())
+    })
+    .await?;
+
+    viewer.display_verify(&results);
+
+    let failed = results
+        .iter()
+        .filter(|r| r.verdict == Verdict::Failed)
+        .count();
+    if failed > 0 {
+        bail!(msg!(Msg::VerifyFailed, count = failed));
+    }
+
+    Ok(())
+}
+
+// What differs between a registry tarball and what php.net serves for its version now, as with a
+// re-tagged RC.  Only what upstream publishes can be compared, so `None` means no sign of a change.
+async fn upstream_change(info: &DownloadInfo) -> Result<Option<String>> {
+    let local = Tarball::from(info).source_info()?;
+
+    if let Some(upstream) = info.official_checksum().await? {
+        let ours = local.checksum.trim_start_matches("sha256:");
+        return Ok(
+            (upstream != ours).then(|| format!("its SHA-256 is now {upstream}, ours is {ours}"))
+        );
+    }
+
+    let remote = DownloadList::new(info.version.major, info.version.minor, info.extension)
+        .get(info.version)
+        .await?;
+
+    Ok(remote
+        .filter(|remote| remote.size > 0 && remote.size != local.size)
+        .map(|remote| format!("it is now {} bytes, ours is {}", remote.size, local.size)))
+}
+
+/// Download a registry tarball again over the copy we have, for when upstream replaced it.
+async fn op_refetch(version: Version) -> Result<()> {
+    if version.is_snapshot() {
+        bail!(msg!(Msg::SnapshotMissing, version = version));
+    }
+
+    let tarballs: Vec<_> = Tarball::list(&Config::registry_path()?)?
+        .report()
+        .into_iter()
+        .filter(|t| t.version == version && t.version.patch.is_some())
+        .collect();
+
+    if tarballs.is_empty() {
+        bail!("PHP {version} isn't in the registry, use `download {version}` to fetch it");
+    }
+
+    let mut batch = prompt::Batch::default();
+    for info in tarballs {
+        let dl = DownloadList::new(version.major, version.minor, info.extension)
+            .get(version)
+            .await?
+            .context(format!("Unable to get download URL for PHP {version}"))?;
+
+        let prompt = msg!(
+            Msg::RefetchPrompt,
+            version = version,
+            extension = info.extension
+        );
+        if !batch.confirm(&prompt)? {
+            if batch.quit() {
+                break;
+            }
+            continue;
+        }
+
+        let dst = PathBuf::from(&info.location);
+        let digest = dl.download_to_file(&dst).await?;
+        audit::record("refetch", &dst, Confirmation::prompted());
+
+        eprintln!(
+            "{}",
+            msg!(
+                Msg::Refetched,
+                version = version,
+                extension = info.extension,
+                digest = digest
+            )
+        );
+    }
+
+    Ok(())
+}
+
+/// Prune the scripts backups `upgrade` leaves in build roots.  A backup goes if it's from more than
+/// `keep` upgrades ago or older than `older_than` days, with neither we only report.
+fn op_gc(
+    path: &Path,
+    depth: usize,
+    keep: Option<usize>,
+    older_than: Option<u64>,
+    dry_run: bool,
+) -> Result<()> {
+    let mut roots = BuildRoot::discover(path, depth)?;
+    roots.sort_unstable();
+
+    let cutoff = older_than.map(|days| {
+        Utc::now().timestamp() - i64::try_from(days.saturating_mul(86_400)).unwrap_or(i64::MAX)
+    });
+
+    let (mut total, mut freed, mut removed) = (0u64, 0u64, 0usize);
+
+    for root in &roots {
+        let backups = root.backups();
+        if backups.is_empty() {
+            continue;
+        }
+
+        println!("{}", root.src.display().to_string().bold());
+
+        let mut pruned: Vec<PathBuf> = vec![];
+
+        for backup in &backups {
+            // Nested backups go along with the one they're in
+            if pruned.iter().any(|p| backup.path.starts_with(p)) {
+                continue;
+            }
+
+            let size = extract::dir_size(&root.src.join(&backup.path));
+            if backup.generation() == 1 {
+                total += size;
+            }
+
+            let prune = keep.is_some_and(|keep| backup.generation() > keep)
+                || cutoff.is_some_and(|cutoff| backup.created < cutoff);
+
+            let created = DateTime::from_timestamp(backup.created, 0)
+                .map_or_else(|| String::from("-"), |d| d.format("%Y-%m-%d").to_string());
+
+            println!(
+                "  {}\t{:>10}\t{created}\t{}",
+                if prune { "prune".red() } else { "keep".green() },
+                size.to_human_size(),
+                backup.path.display(),
+            );
+
+            if prune {
+                if !dry_run {
+                    root.remove_backup(backup)?;
+                    audit::record(
+                        "remove-backup",
+                        &root.src.join(&backup.path),
+                        Confirmation::Implicit,
+                    );
+                }
+                pruned.push(backup.path.clone());
+                freed += size;
+                removed += 1;
+            }
+        }
+    }
+
+    if keep.is_none() && cutoff.is_none() {
+        eprintln!("{}", msg!(Msg::GcUsage, size = total.to_human_size()));
+    } else if dry_run {
+        eprintln!(
+            "{}",
+            msg!(Msg::GcDryRun, count = removed, size = freed.to_human_size())
+        );
+    } else {
+        eprintln!(
+            "{}",
+            msg!(
+                Msg::GcRemoved,
+                count = removed,
+                size = freed.to_human_size()
+            )
+        );
+    }
+
+    Ok(())
+}
+
+fn op_refresh(version: Option<Version>) -> Result<()> {
+    let removed = cache::clear(version.map(|v| (v.major, v.minor)))?;
+
+    match version {
+        Some(v) => eprintln!(
+            "{}",
+            msg!(
+                Msg::RemovedCachedSeries,
+                count = removed,
+                series = format!("{}.{}", v.major, v.minor)
+            )
+        ),
+        None => eprintln!("{}", msg!(Msg::RemovedCached, count = removed)),
+    }
+
+    Ok(())
+}
+
+/// Run the configure and make hooks again in an existing build root, e.g. after changing the
+/// configure arguments.  Compatibility CFLAGS recorded at extraction are applied again.
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn op_rebuild(path: &Path, hooks: HookSet, compat_patches: bool) -> Result<()> {
+    let root = BuildRoot::from_path(path.canonicalize()?)
+        .context(format!("{path:?} is not a build root"))?;
+    check_libc(&root)?;
+
+    let selected: Vec<_> = [Hook::Configure, Hook::Make]
+        .into_iter()
+        .filter(|&h| hooks.contains(h))
+        .collect();
+
+    if selected.is_empty() {
+        bail!("Nothing to rebuild, configure and make are both skipped");
+    }
+
+    for warning in compat::check(root.version) {
+        eprintln!("Warning:  {warning}");
     }
-}
 
-fn validate_hook(hook: Hook, res: &ScriptResult) -> Result<()> {
-    if res.status != 0 {
-        let path = res.save()?;
-        eprintln!("Warning:  Could not execute {hook} script.  Script output logged to {path:?}");
-        bail!("Failed to execute hook");
+    eprintln!(
+        "{}",
+        msg!(
+            Msg::Rebuilding,
+            version = root.version,
+            path = format!("{:?}", root.src)
+        )
+    );
+
+    // Trees extracted with --no-hooks never recorded the CFLAGS fixes, so work them out again
+    let mut patches = root.source().map(|s| s.patches).unwrap_or_default();
+    if compat_patches {
+        for fix in compat::cflag_fixes(root.version) {
+            if !patches.contains(&fix) {
+                patches.push(fix);
+            }
+        }
     }
+    let cflags = compat::cflags(&patches);
+
+    let (loc, files) = blocking(move || {
+        // Only what the build writes counts as part of the tree, files the user added don't
+        let unlisted = root.unlisted_files().unwrap_or_default();
+        let path = root.src.to_string_lossy().into_owned();
+        let vars = Template::for_build(root.version, &root.src)?;
+
+        for hook in selected {
+            let res = Hook::exec(hook, &root.src, &[&path], &vars, cflags.as_deref())?;
+            validate_hook(hook, &res)?;
+        }
+
+        // The build changes the tree, keep `verify` in step with it
+        root.update_manifest(&unlisted)
+    })
+    .await?;
+
+    eprintln!(
+        "{}",
+        msg!(Msg::SavedManifest, path = format!("{loc:?}"), files = files)
+    );
 
     Ok(())
 }
 
-async fn op_extract(
-    mut version: Version,
-    extension: Extension,
-    dst_path: &Path,
-    dst_file: Option<&Path>,
-    no_hooks: bool,
-) -> Result<PathBuf> {
-    // If we only have major.minor just resolve patch if we can
-    let downloads = DownloadList::new(version.major, version.minor, extension);
-    version.resolve_latest(&downloads).await?;
+#[allow(clippy::unnecessary_debug_formatting)]
+fn op_pin(path: &Path, pin: bool) -> Result<()> {
+    let root = BuildRoot::from_path(path.canonicalize()?)
+        .context(format!("{path:?} is not a build root"))?;
 
-    let tarball = Tarball::get_or_download(version, extension).await?;
+    if pin {
+        root.pin()?;
+        eprintln!(
+            "{}",
+            msg!(
+                Msg::Pinned,
+                path = format!("{:?}", root.src),
+                version = root.version
+            )
+        );
+    } else {
+        root.unpin()?;
+        eprintln!("{}", msg!(Msg::Unpinned, path = format!("{:?}", root.src)));
+    }
+
+    Ok(())
+}
 
-    if let Some(path) = tarball.check_dst_path(dst_path, dst_file)? {
-        return Err(anyhow::anyhow!("Path {path:?} already exists"));
+fn op_paths(name: Option<AppPath>, viewer: &(dyn Viewer + Send)) -> Result<()> {
+    if let Some(name) = name {
+        let path = name
+            .resolve()?
+            .context(format!("The {name:?} path depends on the PHP version"))?;
+        println!("{}", path.display());
+        return Ok(());
     }
 
-    // Extract the arghive and capture full destination path
-    let extracted_path = tarball
-        .extract(dst_path, dst_file)?
-        .canonicalize()?
-        .to_string_lossy()
-        .into_owned();
-
-    if !no_hooks {
-        for hook in [Hook::PostExtract, Hook::Configure, Hook::Make] {
-            let res = Hook::exec(hook, &*extracted_path, &[&extracted_path])?;
-            validate_hook(hook, &res)?;
+    let mut paths = vec![];
+    for name in AppPath::value_variants() {
+        if let Some(path) = name.resolve()? {
+            let value = name.to_possible_value().context("Unnamed path")?;
+            paths.push((value.get_name().to_string(), path));
         }
     }
 
-    let root = BuildRoot::from_path(&extracted_path)?;
-    let (loc, files) = root.save_manifest()?;
-    eprintln!("Saved manifest {loc:?} with {files} files.");
+    let paths: Vec<_> = paths.iter().map(|(n, p)| (n.as_str(), p.clone())).collect();
+    viewer.display_paths(&paths);
 
-    Ok(extracted_path.into())
+    Ok(())
 }
 
-fn op_cached(version: Option<Version>, viewer: &(dyn Viewer + Send)) -> Result<()> {
-    let mut tarballs: Vec<_> = Tarball::list(&Config::registry_path()?)?
-        .into_iter()
-        .filter(|fi| fi.version.optional_matches(version))
-        .collect();
-
-    tarballs.sort_by(|a, b| a.version.cmp(&b.version));
+/// What `purge` would remove, with sizes.  Only data we can rebuild or download again, and our
+/// own leftovers in the temporary directory.
+fn purge_targets(installs: bool) -> Result<Vec<(&'static str, PathBuf, u64)>> {
+    let mut paths = vec![
+        ("registry", Config::registry_path()?),
+        ("cache", Config::cache_path()?),
+        ("queue", Config::queue_path()?),
+        ("audit-log", Config::audit_path()?),
+    ];
+
+    if installs {
+        paths.push(("workspace", Config::workspace_path()?));
+    }
 
-    viewer.display(&tarballs);
+    // Interrupted extractions nothing is working in right now
+    paths.extend(
+        extract::idle_staging_dirs()?
+            .into_iter()
+            .map(|path| ("staging", path)),
+    );
 
-    Ok(())
+    Ok(paths
+        .into_iter()
+        .filter(|(_, path)| path.symlink_metadata().is_ok())
+        .map(|(name, path)| {
+            let size = extract::dir_size(&path);
+            (name, path, size)
+        })
+        .filter(|(_, _, size)| *size > 0)
+        .collect())
 }
 
-async fn op_latest(
-    version: Option<Version>,
-    extension: Extension,
-    viewer: &(dyn Viewer + Send),
-) -> Result<()> {
-    let versions = version.map_or_else(
-        || vec![(7, 4), (8, 0), (8, 1), (8, 2), (8, 3)],
-        |v| vec![(v.major, v.minor)],
+#[allow(clippy::unnecessary_debug_formatting)]
+fn op_purge(installs: bool, dry_run: bool) -> Result<()> {
+    let targets = purge_targets(installs)?;
+    if targets.is_empty() {
+        eprintln!("{}", msg!(Msg::PurgeEmpty));
+        return Ok(());
+    }
+
+    for (name, path, size) in &targets {
+        println!("{name}\t{:>10}\t{}", size.to_human_size(), path.display());
+    }
+
+    let total: u64 = targets.iter().map(|(_, _, size)| size).sum();
+    let prompt = msg!(
+        Msg::PurgePrompt,
+        count = targets.len(),
+        size = total.to_human_size()
     );
 
-    let mut urls = vec![];
+    if dry_run || !prompt::confirm(&prompt)? {
+        return Ok(());
+    }
+
+    // Recording a purge after the audit log is gone would only start a new one, so it goes last
+    // and is recorded before it's removed
+    let (log, rest): (Vec<_>, Vec<_>) = targets.iter().partition(|(name, ..)| *name == "audit-log");
 
-    for (major, minor) in versions {
-        let latest = DownloadList::new(major, minor, extension).latest().await?;
-        if let Some(latest) = latest {
-            urls.push(latest);
+    for (name, path, _) in rest.into_iter().chain(log) {
+        if *name == "audit-log" {
+            audit::record("purge", path, Confirmation::prompted());
+        }
+
+        // An extraction may have started on it since we listed it
+        if *name == "staging" {
+            if extract::remove_staging_dir(path)? {
+                audit::record("purge", path, Confirmation::prompted());
+            } else {
+                eprintln!("Warning:  Skipping {path:?}, an extraction is using it");
+            }
+            continue;
+        }
+
+        if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+        .context(format!("Unable to remove {path:?}"))?;
+
+        if *name != "audit-log" {
+            audit::record("purge", path, Confirmation::prompted());
         }
     }
 
-    viewer.display(&urls);
+    eprintln!(
+        "{}",
+        msg!(
+            Msg::Purged,
+            count = targets.len(),
+            size = total.to_human_size()
+        )
+    );
 
     Ok(())
 }
 
-async fn op_list(
-    version: Option<Version>,
+#[allow(clippy::unnecessary_debug_formatting)]
+fn upgrade_roots(path: &Path, scope: UpgradeScope, depth: usize) -> Result<Vec<BuildRoot>> {
+    let path = path.canonicalize()?;
+    let single = BuildRoot::from_path(&path).ok();
+
+    match (scope, single) {
+        (UpgradeScope::Single, None) => bail!("{path:?} is not a build root"),
+        (UpgradeScope::Single, Some(root)) => Ok(vec![root]),
+        (UpgradeScope::Auto, Some(root)) => {
+            eprintln!(
+                "Detected a single build root {:?} (PHP {}), use --parent to upgrade its siblings",
+                root.src, root.version
+            );
+            Ok(vec![root])
+        }
+        (UpgradeScope::Parent, Some(_)) => {
+            let parent = path.parent().context("Build root has no parent")?;
+            let roots = BuildRoot::from_parent_path(parent, depth)?;
+            eprintln!(
+                "{path:?} is a build root, using the {} build root(s) in {parent:?}",
+                roots.len()
+            );
+            Ok(roots)
+        }
+        (UpgradeScope::Parent | UpgradeScope::Auto, None) => {
+            let roots = BuildRoot::from_parent_path(&path, depth)?;
+            eprintln!(
+                "{}",
+                msg!(
+                    Msg::DetectedRoots,
+                    count = roots.len(),
+                    path = format!("{path:?}")
+                )
+            );
+            Ok(roots)
+        }
+    }
+}
+
+/// List the roots `upgrade` would replace without touching anything, returning whether there
+/// are any.  Pinned roots are left out as `upgrade` skips them too.
+async fn op_upgrade_check(
+    path: &Path,
+    scope: UpgradeScope,
+    depth: usize,
     extension: Extension,
+    pre: bool,
     viewer: &(dyn Viewer + Send),
-) -> Result<()> {
-    let version = version.unwrap_or_else(|| Version::from_major_minor(NEW_MAJOR, NEW_MINOR));
-    let urls = DownloadList::new(version.major, version.minor, extension)
-        .list()
-        .await?;
+) -> Result<bool> {
+    let mut roots = upgrade_roots(path, scope, depth)?;
+    roots.sort_unstable();
 
-    viewer.display(&urls);
+    let total = roots.len();
+    let mut outdated = vec![];
 
-    Ok(())
+    for root in roots.into_iter().filter(|root| !root.is_pinned()) {
+        let latest = DownloadList::new(root.version.major, root.version.minor, extension)
+            .with_pre(pre)
+            .latest()
+            .await?
+            .map(|dl| dl.version)
+            .filter(|&latest| latest > root.version);
+
+        if latest.is_some() {
+            outdated.push(root_info(root, latest));
+        }
+    }
+
+    if outdated.is_empty() {
+        eprintln!("{}", msg!(Msg::RootsCurrent, count = total));
+    } else {
+        viewer.display_roots(&outdated);
+    }
+
+    Ok(!outdated.is_empty())
 }
 
-async fn op_download(
-    mut version: Version,
+async fn op_upgrade(
     path: &Path,
+    scope: UpgradeScope,
+    depth: usize,
     extension: Extension,
-    overwrite: bool,
+    opts: ExtractOptions,
 ) -> Result<()> {
-    let downloads = DownloadList::new(version.major, version.minor, extension);
-
-    // Resolve to the actual major.minor.patch (if needed)
-    version.resolve_latest(&downloads).await?;
+    let mut roots = upgrade_roots(path, scope, depth)?;
 
-    let mut dst = PathBuf::from(path);
-    dst.push(version.get_file_name(extension));
-
-    if !overwrite && dst.exists() {
-        eprintln!("{version}\t{dst:?}");
-    } else {
-        let dl = downloads
-            .get(version)
-            .await?
-            .context(format!("Unable to get download URL for PHP {version}"))?;
+    roots.sort_unstable();
 
-        dl.download_to_file(&dst).await?;
+    if roots.is_empty() {
+        eprintln!("{}", msg!(Msg::NoRoots, path = format!("{path:?}")));
+        return Ok(());
     }
 
-    Ok(())
-}
+    let mut upgrades = vec![];
 
-async fn op_upgrade_root(
-    root: &BuildRoot,
-    extension: Extension,
-    no_hooks: bool,
-) -> Result<Option<BuildRoot>> {
-    let latest = DownloadList::new(root.version.major, root.version.minor, extension)
-        .latest()
-        .await?
-        .context("Can't find latest version")?;
+    for (n, root) in roots.into_iter().enumerate() {
+        eprintln!(
+            "{}",
+            msg!(Msg::Upgrading, n = 1 + n, path = format!("{:?}", root.src))
+        );
+        match op_upgrade_root(&root, extension, opts).await {
+            Ok(Some(res)) => upgrades.push((root, res)),
+            Err(e) => eprintln!("    Warning: {e:?}"),
+            _ => {}
+        }
+    }
 
-    if latest.version > root.version {
-        eprintln!("    {} -> {}", root.version, latest.version);
-    } else {
+    for (n, (old, new)) in upgrades.iter().enumerate() {
         eprintln!(
-            "    Version {} is already the latest version, skipping.",
-            root.version
+            "{}",
+            msg!(
+                Msg::UpgradePlanned,
+                n = n + 1,
+                from = format!("{:?}", old.src),
+                to = format!("{:?}", new.src)
+            )
         );
-        return Ok(None);
     }
 
-    let mut extracted_path = op_extract(
-        latest.version,
-        extension,
-        &root.parent(),
-        Some(&PathBuf::from(root.version_path_name(latest.version))),
-        no_hooks,
-    )
-    .await?;
+    let mut batch = prompt::Batch::default();
+    for (root, _) in upgrades {
+        let prompt = msg!(Msg::RemoveOldPrompt, path = format!("{:?}", root.src));
+        if !batch.confirm(&prompt)? {
+            if batch.quit() {
+                break;
+            }
+            continue;
+        }
 
-    let res = BuildRoot::from_path(&extracted_path)?;
+        eprint!("{}", msg!(Msg::Removing, path = format!("{:?}", root.src)));
+        let path = root.src.clone();
+        blocking(move || root.remove()).await?;
+        audit::record("remove-root", &path, Confirmation::prompted());
+        eprintln!("{}", msg!(Msg::Done));
+    }
 
-    let backup_path = root
-        .src
-        .file_name()
-        .expect("No file name?")
-        .to_string_lossy();
+    Ok(())
+}
 
-    extracted_path.push(format!("{}-backup-scripts", &*backup_path));
+// Hooks run on blocking threads, and a tokio timeout can't cancel those, so it would just stop
+// waiting while the hook carried on.  Instead a thread kills whatever hook is running and exits
+// once the time is up.
+fn start_watchdog(max_time: Duration, operation: String, json: bool) {
+    std::thread::spawn(move || {
+        std::thread::sleep(max_time);
+        Hook::kill_running();
+        let error = anyhow!("Gave up after --max-time {}s", max_time.as_secs());
+        if json {
+            hooks::print_reported(&operation, Some(&error));
+        }
+        eprintln!("Error: {error}");
+        std::process::exit(124);
+    });
+}
+
+fn ephemeral_registry(forced: bool, operation: Option<&Operation>) -> Result<Option<TempDir>> {
+    if !forced {
+        if !operation.is_some_and(Operation::writes_registry) || Config::registry_writable() {
+            return Ok(None);
+        }
 
-    eprintln!("Backing up scripts from old build tree...");
-    if let Err(e) = root.save_scripts(&extracted_path) {
-        eprintln!("Warning:  Unable to backup new scripts ({e:?})");
+        eprintln!("{}", msg!(Msg::EphemeralRegistry));
     }
 
-    Ok(Some(res))
+    Config::use_ephemeral_registry().map(Some)
+}
+
+// The readonly bit doesn't account for group/other permissions or ACLs, so we actually try to
+// create a file in the directory.  A symlink to one only counts if we may follow it, as with
+// `Tarball::resolve_dst_root`.
+#[allow(clippy::unnecessary_debug_formatting)]
+fn check_writable_dir(path: &Path, follow_symlinks: bool) -> std::result::Result<(), String> {
+    if path.is_symlink() && !follow_symlinks {
+        Err(format!(
+            "{path:?} is a symlink (use --follow-symlinks to use its target)"
+        ))
+    } else if path.is_symlink() && !path.exists() {
+        Err(format!("{path:?} is a dangling symlink!"))
+    } else if !path.is_dir() {
+        Err(format!("{path:?} is not a directory!"))
+    } else if !Config::is_writable(path) {
+        Err(format!("The directory {path:?} is not writable"))
+    } else {
+        Ok(())
+    }
 }
 
-fn user_confirm(msg: &str) -> Result<bool> {
-    eprint!("{msg}? (yes/no)");
+fn is_dir(s: &str) -> std::result::Result<PathBuf, String> {
+    let path = PathBuf::from(s);
 
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    if path.is_dir() {
+        Ok(path)
+    } else {
+        Err(format!("{s:?} is not a directory!"))
+    }
+}
 
-    Ok(input.chars().next().map_or(false, |c| c == 'y' || c == 'Y'))
+// Clap checks these before it knows whether we may follow symlinks, see `check_dir_args`
+fn is_writable_dir(s: &str) -> std::result::Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+    check_writable_dir(&path, true)?;
+    Ok(path)
 }
 
-async fn op_upgrade(path: &Path, extension: Extension, no_hooks: bool) -> Result<()> {
-    let mut roots = match BuildRoot::from_path(path) {
-        Ok(root) => vec![root],
-        _ => BuildRoot::from_parent_path(path)?,
+/// Check the directories clap accepted again, now that we know whether symlinks may be followed.
+fn check_dir_args(opt: &Options) -> Result<()> {
+    if !opt.no_follow_symlinks {
+        return Ok(());
+    }
+
+    let dir = match &opt.operation {
+        Some(Operation::Download { extract, .. }) => extract.as_ref(),
+        Some(Operation::Get { output_path, .. }) => output_path.as_ref(),
+        Some(Operation::Upgrade { path, .. }) => path.as_ref(),
+        _ => None,
     };
 
-    roots.sort_unstable();
+    for path in opt.tmpdir.iter().chain(dir) {
+        check_writable_dir(path, false).map_err(|e| anyhow!(e))?;
+    }
 
-    if roots.is_empty() {
-        eprintln!("Faiiled to determine build root(s) from path {path:?}");
-        return Ok(());
+    Ok(())
+}
+
+// If clap rejected a subcommand that is actually a version (e.g. `php-downloader 8.3.2`) return
+// where to insert the configured default action.
+fn bare_version_position(args: &[OsString], err: &clap::Error) -> Option<usize> {
+    if err.kind() != ErrorKind::InvalidSubcommand {
+        return None;
     }
 
-    let mut upgrades = vec![];
+    let Some(ContextValue::String(value)) = err.get(ContextKind::InvalidSubcommand) else {
+        return None;
+    };
 
-    for (n, root) in roots.into_iter().enumerate() {
-        eprintln!("[{}] Upgrading {:?}", 1 + n, root.src);
-        match op_upgrade_root(&root, extension, no_hooks).await {
-            Ok(Some(res)) => upgrades.push((root, res)),
-            Err(e) => eprintln!("    Warning: {e:?}"),
-            _ => {}
-        }
+    value.parse::<Version>().ok()?;
+
+    args.iter()
+        .skip(1)
+        .position(|arg| arg.to_str() == Some(value))
+        .map(|pos| pos + 1)
+}
+
+/// Turn a configured default into command line arguments, or `None` if the value makes no sense
+/// for a flag.
+fn default_args(long: &str, value: &serde_json::Value) -> Option<Vec<OsString>> {
+    match value {
+        serde_json::Value::Bool(true) => Some(vec![format!("--{long}").into()]),
+        serde_json::Value::Bool(false) => Some(vec![]),
+        serde_json::Value::String(s) => Some(vec![format!("--{long}={s}").into()]),
+        serde_json::Value::Number(n) => Some(vec![format!("--{long}={n}").into()]),
+        _ => None,
     }
+}
 
-    for (n, (old, new)) in upgrades.iter().enumerate() {
-        eprintln!("[{}] {:?} -> {:?}", n + 1, &old.src, &new.src);
+/// Add the configured `defaults` for the chosen subcommand to `args`, skipping any flag the user
+/// already gave on the command line.
+fn with_defaults(mut args: Vec<OsString>) -> Vec<OsString> {
+    let settings = Config::settings();
+    if settings.defaults.is_empty() {
+        return args;
     }
 
-    if !upgrades.is_empty() && user_confirm("Remove old path(s)")? {
-        for (root, _) in upgrades {
-            eprint!("Removing {:?}...", &root.src);
-            root.remove()?;
-            eprintln!("done!");
+    let cmd = Options::command();
+    let Ok(matches) = cmd.clone().try_get_matches_from(&args) else {
+        return args;
+    };
+    let Some((name, sub_matches)) = matches.subcommand() else {
+        return args;
+    };
+    let Some(defaults) = settings.defaults.get(name) else {
+        return args;
+    };
+    let Some(sub_cmd) = cmd.find_subcommand(name) else {
+        return args;
+    };
+
+    let mut global = vec![];
+    let mut local = vec![];
+
+    let mut keys: Vec<_> = defaults.keys().collect();
+    keys.sort_unstable();
+
+    for key in keys {
+        let find = |c: &clap::Command| {
+            c.get_arguments()
+                .find(|a| a.get_long() == Some(key))
+                .map(|a| a.get_id().clone())
+        };
+
+        let (id, m, dst): (_, &ArgMatches, _) = if let Some(id) = find(sub_cmd) {
+            (id, sub_matches, &mut local)
+        } else if let Some(id) = find(&cmd) {
+            (id, &matches, &mut global)
+        } else {
+            eprintln!("Warning:  Ignoring unknown default '{key}' for {name}");
+            continue;
+        };
+
+        if m.value_source(id.as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        match default_args(key, &defaults[key]) {
+            Some(extra) => dst.extend(extra),
+            None => eprintln!("Warning:  Ignoring invalid default '{key}' for {name}"),
         }
     }
 
-    Ok(())
+    // Global flags go before the subcommand, subcommand flags before any `--`
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    args.splice(end..end, local);
+    args.splice(1..1, global);
+
+    args
 }
 
-fn is_writable_dir(s: &str) -> std::result::Result<PathBuf, String> {
-    let path = Path::new(s);
+fn parse_options() -> Options {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
 
-    if !path.is_dir() {
-        Err(format!("{s:?} is not a directory!"))
-    } else if std::fs::metadata(path)
-        .map_err(|e| e.to_string())?
-        .permissions()
-        .readonly()
-    {
-        Err(format!("The directory {s:?} is not writable"))
-    } else {
-        Ok(PathBuf::from(path))
+    if let Err(e) = Options::try_parse_from(&args) {
+        let Some(pos) = bare_version_position(&args, &e) else {
+            e.exit();
+        };
+
+        let action = Config::settings()
+            .default_action
+            .as_deref()
+            .unwrap_or(Config::DEFAULT_ACTION);
+
+        args.insert(pos, action.into());
     }
+
+    Options::parse_from(with_defaults(args))
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let opt: Options = Options::parse();
+    let opt = parse_options();
+    let json = opt.json;
+    let operation = opt
+        .operation
+        .as_ref()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+
+    let res = run(opt).await;
+
+    // CI wants the hook logs most when the operation failed
+    if json {
+        hooks::print_reported(&operation, res.as_ref().err());
+    }
 
+    res
+}
+
+#[allow(clippy::too_many_lines)]
+async fn run(opt: Options) -> Result<()> {
     let viewer = view::get_viewer(opt.json);
+    if let Some(dir) = opt
+        .tmpdir
+        .as_ref()
+        .or_else(|| Config::settings().tmpdir.as_ref())
+    {
+        Config::set_tmpdir(dir);
+    }
+    let _ephemeral = ephemeral_registry(opt.ephemeral_cache, opt.operation.as_ref())?;
+    if let Some(url) = &opt.base_url {
+        Config::set_base_url(&transport::mirror_url(url)?);
+    }
+    Config::set_cache_ttl(Duration::from_secs(opt.cache_ttl));
+    Config::set_json_progress(opt.json);
+    Config::set_verbosity(opt.verbose);
+    Config::set_offline(opt.offline);
+    Config::set_assume_yes(opt.yes);
+    Config::set_no_verify(opt.no_verify);
+    Config::set_verify_signatures(opt.verify_signatures || Config::settings().verify_signatures);
+    Config::set_insecure(opt.insecure);
+    if let Some(secs) = opt.timeout.or_else(|| Config::settings().timeout) {
+        Config::set_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = opt
+        .connect_timeout
+        .or_else(|| Config::settings().connect_timeout)
+    {
+        Config::set_connect_timeout(Duration::from_secs(secs));
+    }
+    Config::set_deny_eol(Config::settings().deny_eol && !opt.allow_eol);
+    Config::set_stall_timeout(Duration::from_secs(opt.stall_timeout));
+    if let Some(segments) = opt.segments.or_else(|| Config::settings().segments) {
+        Config::set_segments(segments);
+    }
+    if let Some(secs) = opt.max_time {
+        let operation = opt
+            .operation
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        start_watchdog(Duration::from_secs(secs), operation, opt.json);
+    }
+    let settings = Config::settings();
+    Config::set_mirrors(
+        opt.mirror
+            .iter()
+            .chain(&settings.mirrors)
+            .map(|m| transport::mirror_url(m))
+            .collect::<Result<_>>()?,
+    );
+    if let Some(agent) = opt.user_agent.as_ref().or(settings.user_agent.as_ref()) {
+        Config::set_user_agent(agent);
+    }
+    if let Some(url) = opt.proxy.as_ref().or(settings.proxy.as_ref()) {
+        transport::proxy(url)?;
+        Config::set_proxy(url);
+    }
+    if let Some(path) = opt.ca_bundle.as_ref().or(settings.ca_bundle.as_ref()) {
+        transport::ca_certificates(path)?;
+        Config::set_ca_bundle(path);
+    }
+    let no_system_roots = opt.no_system_roots || settings.no_system_roots;
+    if no_system_roots && Config::ca_bundle().is_none() {
+        bail!("--no-system-roots needs a --ca-bundle to trust instead");
+    }
+    Config::set_no_system_roots(no_system_roots);
+    if opt.danger_skip_tls_verify || settings.danger_skip_tls_verify {
+        eprintln!("Warning:  TLS certificates aren't being verified (--danger-skip-tls-verify)");
+        Config::set_skip_tls_verify(true);
+    }
+    downloads::init_http_client()?;
+    check_dir_args(&opt)?;
+    let extension = opt.extension.or(settings.extension).unwrap_or_default();
+    let extract_opts = ExtractOptions {
+        force: opt.force,
+        hooks: HookSet::new(opt.no_hooks, &opt.only_hook, &opt.skip_hook),
+        follow_symlinks: !opt.no_follow_symlinks,
+        on_conflict: opt.on_conflict,
+        pre: settings.pre,
+        rm_tarball: settings.rm_tarball,
+        compat_patches: opt.compat_patches,
+        unpack: UnpackOptions {
+            preserve_xattrs: opt.preserve_xattrs,
+            mtime: opt.mtime,
+            resume: false,
+            fast_decompress: opt.fast_decompress,
+        },
+    };
+
+    if opt.list_versions_local {
+        return op_list_versions_local();
+    }
+
+    if let Some(which) = opt.list_versions_remote {
+        return op_list_versions_remote(which, extension).await;
+    }
+
+    let Some(operation) = opt.operation else {
+        Options::command()
+            .error(ErrorKind::MissingSubcommand, "A subcommand is required")
+            .exit();
+    };
 
-    match opt.operation {
-        Operation::Cached { version } => {
-            op_cached(version, &*viewer)?;
+    match operation {
+        Operation::Active => {
+            op_active(&*viewer).await?;
+        }
+        Operation::Cached {
+            version,
+            check_updates,
+            problems_only,
+        } => {
+            let version = match version {
+                Some(arg) => Some(arg.concrete(extension, settings.pre).await?),
+                None => None,
+            };
+            op_cached(version, check_updates, problems_only, &*viewer).await?;
         }
         Operation::Extract {
+            args,
+            pre,
+            no_pre,
+            rm_tarball,
+            keep_tarball,
+            reuse,
+            from_file,
+            parallel,
+        } => {
+            let (versions, output_path, output_file) =
+                extract_args(args, from_file, extract_opts.follow_symlinks)?;
+            let pre = pre || (settings.pre && !no_pre);
+            let opts = ExtractOptions {
+                pre,
+                rm_tarball: rm_tarball || (settings.rm_tarball && !keep_tarball),
+                on_conflict: if reuse {
+                    Some(OnConflict::Reuse)
+                } else {
+                    extract_opts.on_conflict
+                },
+                ..extract_opts
+            };
+
+            if let [version] = &versions[..] {
+                let version = version.resolve(extension, pre).await?;
+                check_eol(version).await?;
+                op_extract(
+                    version,
+                    extension,
+                    &output_path,
+                    output_file.as_deref(),
+                    "",
+                    opts,
+                )
+                .await?;
+            } else {
+                op_extract_many(&versions, extension, &output_path, parallel, opts).await?;
+            }
+        }
+        Operation::Get {
             version,
+            pre,
+            no_pre,
+            install,
             output_path,
-            output_file,
         } => {
-            op_extract(
+            let path = output_path.map_or_else(Config::workspace_path, Ok)?;
+            let pre = pre || (settings.pre && !no_pre);
+            let version = version.resolve(extension, pre).await?;
+            check_eol(version).await?;
+            op_get(
                 version,
-                opt.extension,
-                &output_path,
-                output_file.as_deref(),
-                opt.no_hooks,
+                extension,
+                &path,
+                install,
+                ExtractOptions {
+                    pre,
+                    ..extract_opts
+                },
             )
             .await?;
         }
-        Operation::Latest { version } => {
-            op_latest(version, opt.extension, &*viewer).await?;
+        Operation::Latest {
+            version,
+            pre,
+            no_pre,
+            quiet,
+        } => {
+            let pre = pre || (settings.pre && !no_pre);
+            op_latest(version, extension, pre, quiet, &*viewer).await?;
         }
-        Operation::List { version } => {
-            op_list(version, opt.extension, &*viewer).await?;
+        Operation::List {
+            version,
+            include_qa,
+        } => {
+            op_list(version, extension, include_qa, &*viewer).await?;
         }
-        Operation::Download {
+        Operation::Roots {
+            path,
             version,
-            output_path,
+            outdated,
+            depth,
+        } => {
+            let path = path.map_or_else(Config::workspace_path, Ok)?;
+            let version = match version {
+                Some(arg) => Some(arg.concrete(extension, settings.pre).await?),
+                None => None,
+            };
+            op_roots(&path, version, outdated, depth, extension, &*viewer).await?;
+        }
+        Operation::Messages => {
+            print!("{}", Msg::catalog_json()?);
+        }
+        Operation::Paths { name } => {
+            op_paths(name, &*viewer)?;
+        }
+        Operation::Migrate { dry_run } => {
+            op_migrate(dry_run)?;
+        }
+        Operation::Mirror {
+            action: MirrorAction::Export { dir },
+        } => {
+            op_mirror_export(&dir)?;
+        }
+        Operation::State {
+            action:
+                StateAction::Export {
+                    file,
+                    with_tarballs,
+                    include_secrets,
+                },
+        } => {
+            let exported = state::export(&file, with_tarballs, include_secrets)?;
+            if exported.secrets_removed > 0 {
+                eprintln!(
+                    "{}",
+                    msg!(Msg::StateSecretsRemoved, count = exported.secrets_removed)
+                );
+            }
+            eprintln!(
+                "{}",
+                msg!(
+                    Msg::StateExported,
+                    items = exported.items.join(", "),
+                    path = file.display()
+                )
+            );
+        }
+        Operation::State {
+            action: StateAction::Import { file },
+        } => {
+            let prompt = msg!(Msg::StateImportPrompt, path = file.display());
+            if prompt::confirm(&prompt)? {
+                let items = state::import(&file)?;
+                eprintln!(
+                    "{}",
+                    msg!(
+                        Msg::StateImported,
+                        items = items.join(", "),
+                        path = file.display()
+                    )
+                );
+            }
+        }
+        Operation::BenchExtract { version } => {
+            op_bench_extract(version, extension).await?;
+        }
+        Operation::ResumeAll => {
+            op_resume_all().await?;
+        }
+        Operation::Refresh { version, .. } => {
+            op_refresh(version)?;
+        }
+        Operation::Resolve {
+            version,
+            pre,
+            no_pre,
+        } => {
+            let pre = pre || (settings.pre && !no_pre);
+            op_resolve(version.resolve(extension, pre).await?, extension, pre).await?;
+        }
+        Operation::Download {
+            args,
+            pre,
+            no_pre,
+            all_active,
+            parallel,
+            extract,
+            from_file,
         } => {
+            let (mut versions, output_path) =
+                download_targets(args, all_active, from_file, extract_opts.follow_symlinks)?;
+            if all_active {
+                let active = active_targets().await?;
+                let given = versions.clone();
+                versions.extend(active.into_iter().filter(|active| {
+                    !given.iter().any(|given| match (given, active) {
+                        (DownloadTarget::Release(a), DownloadTarget::Release(b)) => a.matches(*b),
+                        (DownloadTarget::Constraint(req), DownloadTarget::Release(b)) => {
+                            req.matches(*b)
+                        }
+                        _ => false,
+                    })
+                }));
+            }
+            if extract.is_some() && output_path.is_some() {
+                bail!("--extract extracts from the registry, it can't be used with an output path");
+            }
+            // The registry only knows tarballs by their release names
+            if output_path.is_none() && opt.on_conflict == Some(OnConflict::Suffix) {
+                bail!("--on-conflict suffix can't be used for downloads into the registry");
+            }
+
+            let pre = pre || (settings.pre && !no_pre);
             let path = output_path.unwrap_or(Config::registry_path()?);
-            op_download(version, &path, opt.extension, opt.force).await?;
+            op_download(
+                &versions,
+                &path,
+                extension,
+                opt.on_conflict
+                    .or_else(|| opt.force.then_some(OnConflict::Overwrite)),
+                pre,
+                parallel,
+                extract.as_deref().map(|dir| {
+                    (
+                        dir,
+                        ExtractOptions {
+                            pre,
+                            ..extract_opts
+                        },
+                    )
+                }),
+            )
+            .await?;
+        }
+        #[cfg(feature = "serve")]
+        Operation::Serve { bind } => {
+            serve::run(bind).await?;
+        }
+        Operation::Rebuild { path } => {
+            op_rebuild(&path, extract_opts.hooks, extract_opts.compat_patches).await?;
+        }
+        Operation::Pin { path } => {
+            op_pin(&path, true)?;
+        }
+        Operation::Purge { installs, dry_run } => {
+            op_purge(installs, dry_run)?;
+        }
+        Operation::Unpin { path } => {
+            op_pin(&path, false)?;
+        }
+        Operation::Upgrade {
+            path,
+            depth,
+            single,
+            parent,
+            check,
+            plan,
+            apply,
+        } => {
+            let scope = if single {
+                UpgradeScope::Single
+            } else if parent {
+                UpgradeScope::Parent
+            } else {
+                UpgradeScope::Auto
+            };
+
+            if let Some(plan) = apply {
+                op_upgrade_apply(&plan, extract_opts).await?;
+            } else {
+                let path = path.context("No path given")?;
+                if plan {
+                    op_upgrade_plan(&path, scope, depth, extension, extract_opts).await?;
+                } else if !check {
+                    op_upgrade(&path, scope, depth, extension, extract_opts).await?;
+                } else if op_upgrade_check(
+                    &path,
+                    scope,
+                    depth,
+                    extension,
+                    extract_opts.pre,
+                    &*viewer,
+                )
+                .await?
+                {
+                    std::process::exit(UPGRADES_AVAILABLE);
+                }
+            }
+        }
+        Operation::Verify {
+            version,
+            path,
+            depth,
+            check_upstream,
+            ..
+        } => {
+            let path = path.map_or_else(Config::workspace_path, Ok)?;
+            let version = match version {
+                Some(arg) => Some(arg.concrete(extension, settings.pre).await?),
+                None => None,
+            };
+            op_verify(version, &path, depth, check_upstream, &*viewer).await?;
+        }
+        Operation::Refetch { version } => {
+            op_refetch(version).await?;
+        }
+        Operation::Gc {
+            path,
+            depth,
+            keep,
+            older_than,
+            dry_run,
+        } => {
+            let path = path.map_or_else(Config::workspace_path, Ok)?;
+            op_gc(&path, depth, keep, older_than, dry_run)?;
+        }
+        Operation::Doctor { version } => {
+            let version = match version {
+                Some(arg) => Some(arg.resolve(extension, settings.pre).await?),
+                None => None,
+            };
+            op_doctor(version)?;
         }
-        Operation::Upgrade { path } => {
-            op_upgrade(&path, opt.extension, opt.no_hooks).await?;
+        Operation::Env { version, shell } => {
+            op_env(&version.concrete(extension, settings.pre).await?, shell)?;
         }
         Operation::Version => {
             println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
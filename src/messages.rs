@@ -0,0 +1,220 @@
+use crate::{config::Config, template::Template};
+use anyhow::{Context, Result};
+use std::{collections::HashMap, fs, sync::OnceLock};
+
+macro_rules! catalog {
+    ($($variant:ident => $key:literal: $text:literal,)*) => {
+        /// Every user-facing message we print, keyed so packagers can replace the English text.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Msg {
+            $($variant,)*
+        }
+
+        impl Msg {
+            pub const ALL: &'static [Self] = &[$(Self::$variant,)*];
+
+            /// The key used for this message in locale files and `messages` in config.json.
+            pub const fn key(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $key,)*
+                }
+            }
+
+            /// The built in English text, with `{name}` placeholders.
+            pub const fn english(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $text,)*
+                }
+            }
+        }
+    };
+}
+
+catalog! {
+    ActiveFailed => "active-failed": "Warning:  Unable to get the active releases ({error})",
+    AppliedCompat => "applied-compat": "Applied compatibility fixes: {patches}",
+    BatchFailed => "batch-failed": "    {item}\tfailed ({error})",
+    BatchSucceeded => "batch-succeeded": "    {item}\t{result}",
+    BatchSummary => "batch-summary": "Summary:",
+    BackingUpScripts => "backing-up-scripts": "Backing up scripts from old build tree...",
+    BackupFailed => "backup-failed": "Warning:  Unable to backup new scripts ({error})",
+    BackupRecordFailed => "backup-record-failed": "Warning:  Unable to record scripts backup ({error})",
+    ChecksumMismatch => "checksum-mismatch": "SHA-256 mismatch for {url}, expected {expected} but got {actual} (use --no-verify to keep it anyway)",
+    ChecksumMissing => "checksum-missing": "Warning:  php.net publishes no SHA-256 for PHP {version}, not verified",
+    ChecksumUnavailable => "checksum-unavailable": "Warning:  Unable to verify PHP {version} against php.net ({error})",
+    ConflictPrompt => "conflict-prompt": "{path} already exists, abort, overwrite, suffix or reuse?",
+    ConfirmAssumed => "confirm-assumed": "{prompt}? yes (--yes)",
+    ConfirmPrompt => "confirm-prompt": "{prompt}?",
+    DetectedRoots => "detected-roots": "Detected {count} build root(s) in {path}",
+    Done => "done": "done!",
+    DownloadsFailed => "downloads-failed": "{count} of {total} download(s) failed",
+    DownloadRetry => "download-retry": "Warning:  {error}, retrying (attempt {attempt} of {attempts})",
+    Downloading => "downloading": "Unable to find {version} locally, downloading.",
+    EolCheckFailed => "eol-check-failed": "Warning:  Unable to check whether PHP {series} is end of life ({error})",
+    EolRefused => "eol-refused": "PHP {series} is end of life and no longer receives security fixes, use --allow-eol to use it anyway",
+    EolWarning => "eol-warning": "Warning:  PHP {series} is end of life and no longer receives security fixes",
+    EolWarningSince => "eol-warning-since": "Warning:  PHP {series} reached end of life on {date} and no longer receives security fixes",
+    EphemeralRegistry => "ephemeral-registry": "Warning:  Registry is not writable, caching tarballs in a temporary directory.",
+    Exported => "exported": "Exported {count} tarball(s) to {path}",
+    ExtractsFailed => "extracts-failed": "{count} of {total} extraction(s) failed",
+    FilesExtracted => "files-extracted": "Files extracted to '{path}'",
+    GcDryRun => "gc-dry-run": "Dry run, would remove {count} backup(s) freeing {size}",
+    GcRemoved => "gc-removed": "Removed {count} backup(s), freed {size}",
+    GcUsage => "gc-usage": "Backups use {size}, prune them with --keep or --older-than",
+    HooksUntrusted => "hooks-untrusted": "Warning:  Ignoring hooks in {path}, it isn't yours or others can write to it",
+    HookFailed => "hook-failed": "Warning:  Could not execute {hook} script.  Script output logged to {path}",
+    HookError => "hook-error": "Failed to execute hook",
+    ImportChecksumMismatch => "import-checksum-mismatch": "Warning:  Not importing {path}, it doesn't match its recorded checksum",
+    LibcMismatch => "libc-mismatch": "{path} was built against {libc} but this host uses {host}, extract it again instead",
+    MirrorFailed => "mirror-failed": "Warning:  {url} failed ({error}), trying the next mirror",
+    Migrated => "migrated": "Migrated {path} (backup in {backup})",
+    MigrateDryRun => "migrate-dry-run": "Dry run, {path} was not modified",
+    MigrateUpToDate => "migrate-up-to-date": "{path} is up to date",
+    NoMatchingRelease => "no-matching-release": "No PHP release matches {constraint}",
+    NoVersionFile => "no-version-file": "No version given and no .php-version file in {path} or above",
+    NoRoots => "no-roots": "Failed to determine build root(s) from path {path}",
+    NoSettings => "no-settings": "No settings file at {path}, nothing to migrate",
+    NothingQueued => "nothing-queued": "Nothing queued",
+    NotInRegistry => "not-in-registry": "PHP {version} ({extension}) isn't in the registry and --offline was given",
+    NotInstalled => "not-installed": "PHP {version} isn't installed, install it with `get --install {version}`",
+    OfflineData => "offline-data": "Warning:  Unable to reach php.net ({error}), answering from the cache and registry (offline data, may be stale)",
+    OfflineRefused => "offline-refused": "Not fetching {url}, --offline was given",
+    PathExists => "path-exists": "Path {path} already exists, use --on-conflict to choose what to do",
+    Pinned => "pinned": "Pinned {path} to {version}",
+    PinnedSkip => "pinned-skip": "    Version {version} is pinned, skipping.",
+    PlanFailed => "plan-failed": "{count} of {total} planned upgrade(s) failed",
+    PlanStale => "plan-stale": "{path} is PHP {version} now, not {planned} as planned, skipping",
+    PhpActivate => "php-activate": "Use it in this shell with: eval \"$(php-downloader env {version})\"",
+    PhpInstalled => "php-installed": "PHP {version} is at {path}",
+    PhpSources => "php-sources": "PHP {version} sources are at {path}",
+    PromptInvalid => "prompt-invalid": "Please answer {choices}",
+    PurgeEmpty => "purge-empty": "Nothing to purge",
+    PurgePrompt => "purge-prompt": "Remove {count} path(s) using {size}",
+    Purged => "purged": "Removed {count} path(s), freed {size}",
+    QaUnavailable => "qa-unavailable": "Warning:  Unable to list QA releases ({error})",
+    Queued => "queued": "    Queued PHP {version} ({extension}), retry with `resume-all`",
+    QueueEmpty => "queue-empty": "Queue is empty",
+    QueueFailed => "queue-failed": "Warning:  Unable to queue PHP {version} ({error})",
+    Rebuilding => "rebuilding": "Rebuilding PHP {version} in {path}",
+    Refetched => "refetched": "Replaced PHP {version} ({extension}) with the upstream tarball, SHA-256 {digest}",
+    RefetchPrompt => "refetch-prompt": "Replace the registry's PHP {version} ({extension}) with what php.net serves now",
+    RemovedCached => "removed-cached": "Removed {count} cached file(s)",
+    RemovedCachedSeries => "removed-cached-series": "Removed {count} cached listing(s) for {series}",
+    RemovedTarball => "removed-tarball": "Removed tarball {path}",
+    RemoveOldPrompt => "remove-old-prompt": "Remove the old build root {path}",
+    Removing => "removing": "Removing {path}...",
+    ResumePrompt => "resume-prompt": "Resume the interrupted extraction in {path}",
+    Reusing => "reusing": "Reusing the existing build tree {path}",
+    Retrying => "retrying": "Retrying PHP {version} ({extension})",
+    RootsCurrent => "roots-current": "All {count} build root(s) are up to date",
+    SavedManifest => "saved-manifest": "Saved manifest {path} with {files} files.",
+    SegmentsIgnored => "segments-ignored": "Warning:  {url} ignored the range requests, downloading it in one stream",
+    SignatureIgnored => "signature-ignored": "Warning:  {error}, continuing because of --insecure",
+    SignatureRefused => "signature-refused": "Refusing an unverified tarball, use --insecure to accept it anyway",
+    SmokeTestFailed => "smoke-test-failed": "{path} doesn't run as PHP {version} ({output}), not running the reload hook",
+    SnapshotMissing => "snapshot-missing": "PHP {version} is a php-src snapshot, fetch it with `download master` or `download branch:NAME`",
+    SnapshotNoCommit => "snapshot-no-commit": "The PHP {version} snapshot in the registry has no recorded commit, fetch it again with `download master` or `download branch:NAME`",
+    SnapshotUnverified => "snapshot-unverified": "Warning:  php-src {snapshot} snapshots aren't signed and have no published checksum, only TLS vouches for them",
+    SnapshotUsing => "snapshot-using": "Using the PHP {version} snapshot of commit {commit}",
+    SnapshotVersion => "snapshot-version": "php-src {snapshot} is PHP {version} at commit {commit}",
+    SkippedPrevious => "skipped-previous": "Skipped {count} files extracted by a previous run",
+    StateExported => "state-exported": "Exported {items} to {path}",
+    StateSecretsRemoved => "state-secrets-removed": "Left {count} inline mirror credentials out of config.json, use --include-secrets to export them",
+    StateImported => "state-imported": "Imported {items} from {path}",
+    StateImportPrompt => "state-import-prompt": "Replace this machine's settings, hooks and patches with those in {path}",
+    StillFailing => "still-failing": "{count} download(s) still failing, left in the queue",
+    UpToDate => "up-to-date": "    Version {version} is already the latest version, skipping.",
+    UpdateCheckFailed => "update-check-failed": "Warning:  Unable to check for updates to {series} ({error})",
+    Unpinned => "unpinned": "Unpinned {path}",
+    UpstreamChanged => "upstream-changed": "Warning:  PHP {version} ({extension}) changed upstream since it was downloaded, {detail}.  Get the new tarball with `refetch {version}`",
+    UpstreamCheckFailed => "upstream-check-failed": "Warning:  Unable to compare PHP {version} ({extension}) with upstream ({error})",
+    Upgrading => "upgrading": "[{n}] Upgrading {path}",
+    UpgradePlanned => "upgrade-planned": "[{n}] {from} -> {to}",
+    VersionFromFile => "version-from-file": "Using PHP {version} from {path}",
+    VerifyFailed => "verify-failed": "{count} artifact(s) failed verification",
+}
+
+static OVERRIDES: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+#[allow(clippy::unnecessary_debug_formatting)]
+fn load_locale(locale: &str) -> Result<HashMap<String, String>> {
+    let path = Config::locale_path()?.join(format!("{locale}.json"));
+    let data = fs::read(&path).context(format!("Unable to read {path:?}"))?;
+    serde_json::from_slice(&data).context(format!("Unable to parse {path:?}"))
+}
+
+// Inline `messages` win over the locale file so a single string can be tweaked on top of it
+fn overrides() -> &'static HashMap<String, String> {
+    OVERRIDES.get_or_init(|| {
+        let settings = Config::settings();
+
+        let mut messages = settings
+            .locale
+            .as_deref()
+            .map_or_else(HashMap::new, |locale| {
+                load_locale(locale).unwrap_or_else(|e| {
+                    eprintln!("Warning:  {e:#}, using English messages");
+                    HashMap::new()
+                })
+            });
+
+        messages.extend(settings.messages.clone());
+        messages
+    })
+}
+
+impl Msg {
+    /// The text for this message with `vars` filled in.  An override that doesn't expand (e.g.
+    /// it uses a variable this message doesn't have) falls back to English.
+    pub fn text(self, vars: &Template) -> String {
+        overrides()
+            .get(self.key())
+            .and_then(|s| vars.expand(s).ok())
+            .or_else(|| vars.expand(self.english()).ok())
+            .unwrap_or_else(|| self.english().to_string())
+    }
+
+    /// The English catalog as a locale file, a starting point for translations.
+    pub fn catalog_json() -> Result<String> {
+        let catalog: serde_json::Map<_, _> = Self::ALL
+            .iter()
+            .map(|m| (m.key().to_string(), m.english().into()))
+            .collect();
+
+        Ok(serde_json::to_string_pretty(&catalog)? + "\n")
+    }
+}
+
+/// Look up a catalog message, e.g. `msg!(Msg::Pinned, path = format!("{src:?}"), version = v)`.
+macro_rules! msg {
+    ($msg:expr $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::messages::Msg::text(
+            $msg,
+            &$crate::template::Template::default()$(.set(stringify!($name), $value.to_string()))*,
+        )
+    };
+}
+
+pub(crate) use msg;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_unique() {
+        let mut keys: Vec<_> = Msg::ALL.iter().map(|m| m.key()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        assert_eq!(keys.len(), Msg::ALL.len());
+    }
+
+    #[test]
+    fn expands_english_defaults() {
+        assert_eq!(
+            msg!(Msg::Pinned, path = "/tmp/php-8.3.1", version = "8.3.1"),
+            "Pinned /tmp/php-8.3.1 to 8.3.1"
+        );
+        assert_eq!(msg!(Msg::NothingQueued), "Nothing queued");
+    }
+}
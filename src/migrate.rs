@@ -0,0 +1,75 @@
+use serde_json::{Map, Value};
+
+/// The settings layout this release writes.
+pub const CONFIG_VERSION: u64 = 1;
+
+/// The settings key holding the layout version.
+pub const VERSION_KEY: &str = "config-version";
+
+struct Migration {
+    /// The config version this migration upgrades to
+    version: u64,
+    apply: fn(&mut Map<String, Value>) -> Vec<String>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    apply: kebab_case_keys,
+}];
+
+// Settings are kebab-case, but snake_case keys used to be silently ignored
+fn kebab_case_keys(map: &mut Map<String, Value>) -> Vec<String> {
+    let keys: Vec<_> = map.keys().filter(|k| k.contains('_')).cloned().collect();
+    let mut changes = vec![];
+
+    for key in keys {
+        let new_key = key.replace('_', "-");
+        if map.contains_key(&new_key) {
+            changes.push(format!("Dropped '{key}', '{new_key}' is already set"));
+            map.remove(&key);
+        } else if let Some(value) = map.remove(&key) {
+            changes.push(format!("Renamed '{key}' to '{new_key}'"));
+            map.insert(new_key, value);
+        }
+    }
+
+    changes
+}
+
+/// Bring `settings` up to `CONFIG_VERSION`, returning a description of each change made, or
+/// `None` if it was already current.
+pub fn migrate(settings: &mut Value) -> Option<Vec<String>> {
+    let map = settings.as_object_mut()?;
+
+    let from = map.get(VERSION_KEY).and_then(Value::as_u64).unwrap_or(0);
+    if from >= CONFIG_VERSION {
+        return None;
+    }
+
+    let changes = MIGRATIONS
+        .iter()
+        .filter(|m| m.version > from)
+        .flat_map(|m| (m.apply)(map))
+        .collect();
+
+    map.insert(VERSION_KEY.to_string(), CONFIG_VERSION.into());
+
+    Some(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_legacy_keys() {
+        let mut settings = serde_json::json!({"rm_tarball": true, "pre": true});
+
+        let changes = migrate(&mut settings).expect("Should migrate");
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(settings["rm-tarball"], true);
+        assert_eq!(settings[VERSION_KEY], CONFIG_VERSION);
+        assert!(migrate(&mut settings).is_none());
+    }
+}
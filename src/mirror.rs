@@ -0,0 +1,141 @@
+use crate::{
+    cache,
+    config::Config,
+    downloads::{DownloadInfo, Version},
+    extract::Tarball,
+    signature,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// One tarball in an exported mirror's `index.json`.
+#[derive(Debug, Serialize)]
+struct IndexEntry {
+    version: Version,
+    path: String,
+    size: u64,
+    checksum: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Index {
+    generated: i64,
+    tarballs: Vec<IndexEntry>,
+}
+
+/// The `index.json` listing every tarball with its size and checksum.
+pub fn index_json(tarballs: &[DownloadInfo]) -> Result<String> {
+    let entries = tarballs
+        .iter()
+        .map(|info| {
+            let source = Tarball::from(info).source_info()?;
+            Ok(IndexEntry {
+                version: info.version,
+                path: info.version.mirror_path(info.extension),
+                size: source.size,
+                checksum: source.checksum,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    let index = Index {
+        generated: Utc::now().timestamp(),
+        tarballs: entries,
+    };
+
+    Ok(serde_json::to_string_pretty(&index)? + "\n")
+}
+
+// Tarballs never change once they're in the registry, so a hard link is as good as a copy
+#[allow(clippy::unnecessary_debug_formatting)]
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    let _ = fs::remove_file(dst);
+
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst).context(format!("Unable to copy {src:?} to {dst:?}"))?;
+    }
+
+    Ok(())
+}
+
+fn release_date(info: &DownloadInfo) -> Option<String> {
+    let modified = fs::metadata(&info.location).ok()?.modified().ok()?;
+    Some(
+        DateTime::<Utc>::from(modified)
+            .format("%d %b %Y")
+            .to_string(),
+    )
+}
+
+/// Everything in the registry, in the order mirrors list it.
+pub fn tarballs() -> Result<Vec<DownloadInfo>> {
+    let mut tarballs = Tarball::list(&Config::registry_path()?)?.report();
+    tarballs.sort_by_key(|t| (t.version, t.extension.to_string()));
+    Ok(tarballs)
+}
+
+/// The same shape php.net's `/releases/active/` returns, with the newest cached stable release
+/// standing in for each series.
+pub fn active_json(tarballs: &[DownloadInfo]) -> Value {
+    let mut majors: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+
+    for info in tarballs.iter().filter(|t| t.version.rc.is_none()) {
+        let Version { major, minor, .. } = info.version;
+
+        majors.entry(major.to_string()).or_default().insert(
+            format!("{major}.{minor}"),
+            json!({
+                "version": info.version.to_string(),
+                "date": release_date(info),
+                "tags": [],
+            }),
+        );
+    }
+
+    json!(majors)
+}
+
+/// Lay the registry out under `dir` the way php.net does, so it can be served as a static
+/// `--base-url` mirror.  Alongside the tarballs we write `releases/active/index.html` for the
+/// releases API and an `index.json` listing every file.  Returns how many tarballs were exported.
+///
+/// # Errors
+///
+/// Fails if the registry can't be read or anything under `dir` can't be written.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn export(dir: &Path) -> Result<usize> {
+    let tarballs = tarballs()?;
+
+    for info in &tarballs {
+        let dst = dir.join(info.version.mirror_path(info.extension));
+
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).context(format!("Unable to create directory {parent:?}"))?;
+        }
+
+        link_or_copy(Path::new(&info.location), &dst)?;
+
+        let signature = signature::signature_path(Path::new(&info.location));
+        if signature.exists() {
+            link_or_copy(&signature, &signature::signature_path(&dst))?;
+        }
+    }
+
+    let active: PathBuf = dir.join("releases/active");
+    fs::create_dir_all(&active).context(format!("Unable to create directory {active:?}"))?;
+    cache::write_atomic(
+        &active.join("index.html"),
+        serde_json::to_string(&active_json(&tarballs))?.as_bytes(),
+    )?;
+
+    cache::write_atomic(&dir.join("index.json"), index_json(&tarballs)?.as_bytes())?;
+
+    Ok(tarballs.len())
+}
@@ -0,0 +1,49 @@
+use crate::{
+    downloads::{Extension, Version},
+    hooks::{Hook, HookSet},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path, path::PathBuf};
+
+/// What `upgrade` would do to one build root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PlannedUpgrade {
+    pub path: PathBuf,
+    pub current: Version,
+    pub target: Version,
+    pub extension: Extension,
+    /// Bytes to fetch, 0 when the tarball is already in the registry
+    pub download_size: u64,
+    pub extract_to: PathBuf,
+    pub hooks: Vec<Hook>,
+    /// Removed once the upgrade succeeds, without asking
+    pub will_remove: Vec<PathBuf>,
+}
+
+/// The output of `upgrade --plan`, reviewed and then carried out by `upgrade --apply`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpgradePlan {
+    pub created: String,
+    pub upgrades: Vec<PlannedUpgrade>,
+}
+
+impl PlannedUpgrade {
+    /// The hooks to run, as planned.
+    pub fn hook_set(&self) -> HookSet {
+        HookSet::new(self.hooks.is_empty(), &self.hooks, &[])
+    }
+}
+
+/// Read a plan written by `upgrade --plan`.
+///
+/// # Errors
+///
+/// Fails if the file can't be read or isn't a plan.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn load(path: &Path) -> Result<UpgradePlan> {
+    let data = fs::read(path).context(format!("Unable to read {path:?}"))?;
+    serde_json::from_slice(&data).context(format!("Unable to parse upgrade plan {path:?}"))
+}
@@ -0,0 +1,190 @@
+use crate::config::Config;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// Tools a PHP source build needs beyond the shell and make.
+pub const BUILD_TOOLS: [&str; 5] = ["cc", "autoconf", "bison", "re2c", "pkg-config"];
+
+/// Build adjustments PHP needs on particular hosts.  Configure arguments are left to the configure
+/// hook to use, as `{configure_args}` in a configured command or `$PHPDOWNLOADER_CONFIGURE_ARGS`
+/// in a script, and the environment is set for every hook unless the user already set it.
+#[derive(Debug, Default)]
+pub struct Platform {
+    pub configure_args: Vec<String>,
+    pub env: Vec<(&'static str, String)>,
+}
+
+impl Platform {
+    pub fn detect() -> Self {
+        if Config::settings().no_platform_defaults {
+            return Self::default();
+        }
+
+        Self::for_host(env::consts::OS, env::consts::ARCH)
+    }
+
+    fn for_host(os: &str, arch: &str) -> Self {
+        let mut res = Self::default();
+
+        if os != "macos" {
+            return res;
+        }
+
+        // Homebrew lives in /opt/homebrew on Apple Silicon and /usr/local on Intel
+        let (brew, arch_flag) = match arch {
+            "aarch64" => ("/opt/homebrew", "-arch arm64"),
+            _ => ("/usr/local", "-arch x86_64"),
+        };
+
+        // The system iconv is missing symbols PHP wants, so point it at Homebrew's libiconv
+        let iconv = Path::new(brew).join("opt/libiconv");
+        if iconv.is_dir() {
+            res.configure_args
+                .push(format!("--with-iconv={}", iconv.display()));
+        }
+
+        for var in ["CFLAGS", "CXXFLAGS", "LDFLAGS"] {
+            res.env.push((var, arch_flag.to_string()));
+        }
+
+        let pkg_config = Path::new(brew).join("lib/pkgconfig");
+        if pkg_config.is_dir() {
+            res.env
+                .push(("PKG_CONFIG_PATH", pkg_config.display().to_string()));
+        }
+
+        res
+    }
+
+    /// Find an executable on `PATH`.
+    pub fn which(name: &str) -> Option<PathBuf> {
+        Self::which_all(name).into_iter().next()
+    }
+
+    /// Every `name` on `PATH` in the order the shell would consider them, so the first one is
+    /// what actually runs.
+    pub fn which_all(name: &str) -> Vec<PathBuf> {
+        env::var_os("PATH")
+            .map(|path| {
+                env::split_paths(&path)
+                    .map(|dir| dir.join(name))
+                    .filter(|path| path.is_file())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    const fn is_bsd(os: &str) -> bool {
+        matches!(
+            os.as_bytes(),
+            b"freebsd" | b"openbsd" | b"netbsd" | b"dragonfly"
+        )
+    }
+
+    /// Shell used to run hooks.  BSDs don't ship bash in the base system.
+    pub fn shell() -> &'static str {
+        if Self::which("bash").is_some() {
+            "bash"
+        } else {
+            "sh"
+        }
+    }
+
+    /// PHP's build system needs GNU make, which BSDs install as `gmake`.
+    pub fn make() -> &'static str {
+        if Self::is_bsd(env::consts::OS) && Self::which("gmake").is_some() {
+            "gmake"
+        } else {
+            "make"
+        }
+    }
+
+    /// The C library binaries built here link against.  Trees built against musl can't be reused
+    /// on glibc hosts and vice versa.
+    pub fn libc() -> &'static str {
+        if env::consts::OS != "linux" {
+            return "system";
+        }
+
+        let musl = std::fs::read_dir("/lib").is_ok_and(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .any(|e| e.file_name().to_string_lossy().starts_with("ld-musl-"))
+        });
+
+        if musl {
+            "musl"
+        } else {
+            "glibc"
+        }
+    }
+
+    /// How to install the build dependencies on this OS.
+    pub fn install_hint() -> Option<&'static str> {
+        match env::consts::OS {
+            "linux" if Self::libc() == "musl" => {
+                Some("apk add build-base musl-dev autoconf bison re2c pkgconf")
+            }
+            "freebsd" | "dragonfly" => Some("pkg install gmake autoconf bison re2c pkgconf"),
+            "openbsd" => Some(
+                "pkg_add gmake autoconf bison re2c pkgconf, and export AUTOCONF_VERSION (e.g. 2.71)",
+            ),
+            "netbsd" => Some("pkgin install gmake autoconf bison re2c pkgconf"),
+            "macos" => Some("brew install autoconf bison re2c pkg-config"),
+            _ => None,
+        }
+    }
+
+    /// Configure arguments as one string, e.g. for `./configure {configure_args}`.
+    pub fn configure_line(&self) -> String {
+        self.configure_args.join(" ")
+    }
+
+    /// The environment to add to hooks.  Variables the user set themselves win, except for
+    /// search paths which get our directories appended.  `cflags` is always appended to
+    /// `CFLAGS` since build fixes have to apply regardless.
+    pub fn hook_env(&self, cflags: Option<&str>) -> Vec<(&'static str, String)> {
+        let mut res: Vec<_> = self
+            .env
+            .iter()
+            .filter_map(|(name, value)| match env::var(name) {
+                Ok(current) if *name == "PKG_CONFIG_PATH" => {
+                    Some((*name, format!("{current}:{value}")))
+                }
+                Ok(_) => None,
+                Err(_) => Some((*name, value.clone())),
+            })
+            .collect();
+
+        res.push(("PHPDOWNLOADER_CONFIGURE_ARGS", self.configure_line()));
+
+        if let Some(cflags) = cflags {
+            let current = res
+                .iter()
+                .position(|(name, _)| *name == "CFLAGS")
+                .map(|pos| res.remove(pos).1)
+                .or_else(|| env::var("CFLAGS").ok());
+
+            let value = current.map_or_else(|| cflags.to_string(), |c| format!("{c} {cflags}"));
+            res.push(("CFLAGS", value));
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_defaults() {
+        let linux = Platform::for_host("linux", "aarch64");
+        assert!(linux.configure_args.is_empty() && linux.env.is_empty());
+
+        let mac = Platform::for_host("macos", "aarch64");
+        assert!(mac.env.contains(&("CFLAGS", "-arch arm64".to_string())));
+    }
+}
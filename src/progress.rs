@@ -0,0 +1,46 @@
+use crate::config::Config;
+use anyhow::Result;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{fmt::Display, sync::OnceLock};
+
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Every bar and spinner is drawn through this, so concurrent downloads, extractions and hooks
+/// each get a line of their own instead of drawing over one another.
+fn bars() -> &'static MultiProgress {
+    BARS.get_or_init(MultiProgress::new)
+}
+
+/// A bar counting up to `len` drawn with `template`, hidden when progress is reported as JSON.
+///
+/// # Errors
+///
+/// Fails if `template` isn't a valid indicatif template.
+pub fn bar(len: u64, template: &str) -> Result<ProgressBar> {
+    if Config::json_progress() {
+        return Ok(ProgressBar::hidden());
+    }
+
+    let pb = bars().add(ProgressBar::new(len));
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(template)?
+            .progress_chars("#>-"),
+    );
+
+    Ok(pb)
+}
+
+/// A spinner for work of unknown length, hidden when progress is reported as JSON.
+pub fn spinner() -> ProgressBar {
+    if Config::json_progress() {
+        return ProgressBar::hidden();
+    }
+
+    bars().add(ProgressBar::new_spinner())
+}
+
+/// Print a line to stderr without tearing whatever bars are currently drawn.
+pub fn note<D: Display>(line: D) {
+    bars().suspend(|| eprintln!("{line}"));
+}
@@ -0,0 +1,155 @@
+use crate::{
+    config::Config,
+    messages::{msg, Msg},
+};
+use anyhow::Result;
+use std::io;
+
+/// An answer to a yes/no question, `All` and `Quit` only being offered in batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+const YES_NO: [(&str, Answer); 2] = [("yes", Answer::Yes), ("no", Answer::No)];
+const BATCH: [(&str, Answer); 4] = [
+    ("yes", Answer::Yes),
+    ("no", Answer::No),
+    ("all", Answer::All),
+    ("quit", Answer::Quit),
+];
+
+// The choices by first letter for the prompt, the default capitalized as in `[y/N]`
+fn letters<T: PartialEq>(choices: &[(&str, T)], default: &T) -> String {
+    choices
+        .iter()
+        .map(|(word, value)| {
+            let letter = &word[..1];
+            if value == default {
+                letter.to_uppercase()
+            } else {
+                letter.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Ask until the answer is one of `choices`, given as the whole word or its first letter.  An
+/// empty answer means `default`, the end of input means `None`.
+///
+/// # Errors
+///
+/// Fails if stdin can't be read.
+pub fn choose<T: Copy + PartialEq>(
+    prompt: &str,
+    choices: &[(&str, T)],
+    default: T,
+) -> Result<Option<T>> {
+    let options = letters(choices, &default);
+
+    loop {
+        eprint!("{prompt} [{options}] ");
+
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input)? == 0 {
+            eprintln!();
+            return Ok(None);
+        }
+
+        let input = input.trim().to_lowercase();
+        if input.is_empty() {
+            return Ok(Some(default));
+        }
+
+        let answer = choices
+            .iter()
+            .find(|(word, _)| *word == input || word[..1] == input)
+            .map(|&(_, value)| value);
+
+        if answer.is_some() {
+            return Ok(answer);
+        }
+
+        let words: Vec<_> = choices.iter().map(|(word, _)| *word).collect();
+        let words = match words.split_last() {
+            Some((last, [])) => (*last).to_string(),
+            Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+            None => String::new(),
+        };
+        eprintln!("{}", msg!(Msg::PromptInvalid, choices = words));
+    }
+}
+
+/// Ask a yes/no question, defaulting to no.  `--yes` answers it without asking.
+///
+/// # Errors
+///
+/// Fails if stdin can't be read.
+pub fn confirm(prompt: &str) -> Result<bool> {
+    if Config::assume_yes() {
+        eprintln!("{}", msg!(Msg::ConfirmAssumed, prompt = prompt));
+        return Ok(true);
+    }
+
+    let prompt = msg!(Msg::ConfirmPrompt, prompt = prompt);
+    Ok(choose(&prompt, &YES_NO, Answer::No)? == Some(Answer::Yes))
+}
+
+/// The same question asked about several things in turn, where `all` says yes to the rest and
+/// `quit` (or the end of input) no.
+#[derive(Debug, Default)]
+pub struct Batch {
+    answer: Option<bool>,
+}
+
+impl Batch {
+    /// Ask about the next item unless an earlier answer covers the rest.
+    ///
+    /// # Errors
+    ///
+    /// Fails if stdin can't be read.
+    pub fn confirm(&mut self, prompt: &str) -> Result<bool> {
+        if let Some(answer) = self.answer {
+            return Ok(answer);
+        }
+
+        if Config::assume_yes() {
+            eprintln!("{}", msg!(Msg::ConfirmAssumed, prompt = prompt));
+            return Ok(true);
+        }
+
+        let prompt = msg!(Msg::ConfirmPrompt, prompt = prompt);
+        match choose(&prompt, &BATCH, Answer::No)? {
+            Some(Answer::Yes) => Ok(true),
+            Some(Answer::No) => Ok(false),
+            Some(Answer::All) => {
+                self.answer = Some(true);
+                Ok(true)
+            }
+            Some(Answer::Quit) | None => {
+                self.answer = Some(false);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Whether the rest were declined with `quit`, so there's no point going on.
+    pub const fn quit(&self) -> bool {
+        matches!(self.answer, Some(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_capitalized() {
+        assert_eq!(letters(&YES_NO, &Answer::No), "y/N");
+        assert_eq!(letters(&BATCH, &Answer::Yes), "Y/n/a/q");
+    }
+}
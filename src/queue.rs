@@ -0,0 +1,100 @@
+use crate::{
+    cache,
+    config::Config,
+    downloads::{Extension, Version},
+    messages::{msg, Msg},
+};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A download that failed during a batch download or while fetching a tarball to extract, kept
+/// in `~/.phpdownloader/queue.json` until `resume-all` gets it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedDownload {
+    pub version: Version,
+    pub extension: Extension,
+    /// Where `download` was saving it, the registry if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    pub added: i64,
+    pub error: String,
+}
+
+/// Everything still waiting to be downloaded.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn load() -> Result<Vec<QueuedDownload>> {
+    let path = Config::queue_path()?;
+
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let data = fs::read(&path).context(format!("Unable to read {path:?}"))?;
+    serde_json::from_slice(&data).context(format!("Unable to parse {path:?}"))
+}
+
+/// Replace the queue, removing the file once it's empty.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn save(queue: &[QueuedDownload]) -> Result<()> {
+    let path = Config::queue_path()?;
+
+    if queue.is_empty() {
+        if path.exists() {
+            fs::remove_file(&path).context(format!("Unable to remove {path:?}"))?;
+        }
+        return Ok(());
+    }
+
+    let data = serde_json::to_string_pretty(queue)? + "\n";
+    cache::write_atomic(&path, data.as_bytes())
+}
+
+fn update<F>(f: F) -> Result<()>
+where
+    F: FnOnce(&mut Vec<QueuedDownload>),
+{
+    let mut queue = load()?;
+    f(&mut queue);
+    save(&queue)
+}
+
+/// Queue a failed download, replacing any earlier failure for the same tarball.  `path` is the
+/// directory it was being saved to when that isn't the registry.  Losing the queue isn't worth
+/// failing the operation over, so this only warns.
+pub fn push(version: Version, extension: Extension, path: Option<&Path>, error: &anyhow::Error) {
+    let res = update(|queue| {
+        queue.retain(|q| (q.version, q.extension) != (version, extension));
+        queue.push(QueuedDownload {
+            version,
+            extension,
+            path: path.map(Path::to_path_buf),
+            added: Utc::now().timestamp(),
+            error: format!("{error:#}"),
+        });
+    });
+
+    match res {
+        Ok(()) => eprintln!(
+            "{}",
+            msg!(Msg::Queued, version = version, extension = extension)
+        ),
+        Err(e) => eprintln!(
+            "{}",
+            msg!(
+                Msg::QueueFailed,
+                version = version,
+                error = format!("{e:#}")
+            )
+        ),
+    }
+}
+
+/// Drop a tarball from the queue once we have it.
+pub fn remove(version: Version, extension: Extension) {
+    let _ = update(|queue| queue.retain(|q| (q.version, q.extension) != (version, extension)));
+}
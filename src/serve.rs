@@ -0,0 +1,225 @@
+use crate::{config::Config, downloads::DownloadInfo, mirror, signature};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use hyper::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    fs,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+use tokio::{fs::File, io::AsyncReadExt};
+
+/// The registry as last scanned, along with its `index.json` once something asked for it.
+struct Registry {
+    modified: SystemTime,
+    tarballs: Arc<Vec<DownloadInfo>>,
+    index: Option<Arc<String>>,
+}
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+// Adding or removing a tarball changes the registry directory's mtime, so that's all we need to
+// check before reusing the last scan
+#[allow(clippy::unnecessary_debug_formatting)]
+fn tarballs() -> Result<Arc<Vec<DownloadInfo>>> {
+    let path = Config::registry_path()?;
+    let modified = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .context(format!("Unable to read {path:?}"))?;
+
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(cached) = registry.as_ref().filter(|r| r.modified == modified) {
+        return Ok(Arc::clone(&cached.tarballs));
+    }
+
+    let tarballs = Arc::new(mirror::tarballs()?);
+    *registry = Some(Registry {
+        modified,
+        tarballs: Arc::clone(&tarballs),
+        index: None,
+    });
+    drop(registry);
+
+    Ok(tarballs)
+}
+
+// Building the index can mean hashing tarballs, so it's only done once per scan
+fn index_json(tarballs: &Arc<Vec<DownloadInfo>>) -> Result<Arc<String>> {
+    let current = |r: &&mut Registry| Arc::ptr_eq(&r.tarballs, tarballs);
+
+    let cached = REGISTRY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_mut()
+        .filter(current)
+        .and_then(|r| r.index.clone());
+    if let Some(index) = cached {
+        return Ok(index);
+    }
+
+    let index = Arc::new(mirror::index_json(tarballs)?);
+
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(registry) = registry.as_mut().filter(current) {
+        registry.index = Some(Arc::clone(&index));
+    }
+    drop(registry);
+
+    Ok(index)
+}
+
+// Send `file` in chunks rather than reading tarballs into memory
+fn stream(mut file: File) -> Body {
+    let (mut tx, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx
+                        .send_data(Bytes::copy_from_slice(&buf[..n]))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Warning:  Unable to read file ({e})");
+                    tx.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    body
+}
+
+// A file response, just the headers for HEAD requests
+#[allow(clippy::unnecessary_debug_formatting)]
+async fn file_response(path: &Path, content_type: &str, head: bool) -> Result<Response<Body>> {
+    let Ok(file) = File::open(path).await else {
+        return Ok(status(StatusCode::NOT_FOUND));
+    };
+    let len = file
+        .metadata()
+        .await
+        .context(format!("Unable to read {path:?}"))?
+        .len();
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, len)
+        .body(if head { Body::empty() } else { stream(file) })?)
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Body::empty());
+    *res.status_mut() = code;
+    res
+}
+
+// Paths follow the php.net layout `mirror export` writes, so a client can use either
+async fn respond(req: &Request<Body>) -> Result<Response<Body>> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let head = req.method() == Method::HEAD;
+    let path = req.uri().path().trim_start_matches('/');
+    let tarballs = tarballs()?;
+
+    let (content_type, body) = match path {
+        "releases/active" | "releases/active/" | "releases/active/index.html" => (
+            "application/json",
+            serde_json::to_vec(&mirror::active_json(&tarballs))?,
+        ),
+        "index.json" => (
+            "application/json",
+            index_json(&tarballs)?.as_bytes().to_vec(),
+        ),
+        _ => {
+            let (tarball, signature) = path
+                .strip_suffix(".asc")
+                .map_or((path, false), |p| (p, true));
+
+            let Some(info) = tarballs
+                .iter()
+                .find(|t| t.version.mirror_path(t.extension) == tarball)
+            else {
+                return Ok(status(StatusCode::NOT_FOUND));
+            };
+
+            let location = Path::new(&info.location);
+
+            return if signature {
+                file_response(
+                    &signature::signature_path(location),
+                    "application/pgp-signature",
+                    head,
+                )
+                .await
+            } else {
+                file_response(location, "application/octet-stream", head).await
+            };
+        }
+    };
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, body.len())
+        .body(if head {
+            Body::empty()
+        } else {
+            Body::from(body)
+        })?)
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let res = respond(&req).await.unwrap_or_else(|e| {
+        eprintln!("Warning:  {e:#}");
+        status(StatusCode::INTERNAL_SERVER_ERROR)
+    });
+
+    eprintln!(
+        "{} {} {}",
+        req.method(),
+        req.uri().path(),
+        res.status().as_u16()
+    );
+
+    Ok(res)
+}
+
+/// Serve the registry on `addr` as a `--base-url` mirror until interrupted.
+///
+/// # Errors
+///
+/// Fails if we can't listen on `addr` or the server stops with an error.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub async fn run(addr: SocketAddr) -> Result<()> {
+    let make = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+
+    let server = Server::try_bind(&addr)
+        .context(format!("Unable to listen on {addr}"))?
+        .serve(make);
+
+    eprintln!(
+        "Serving {:?} on http://{addr}, use it with --base-url",
+        Config::registry_path()?
+    );
+
+    server.await?;
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use crate::{
+    auth, cache,
+    config::Config,
+    downloads::{http_client, send, timed},
+    messages::{msg, Msg},
+    progress,
+};
+use anyhow::{bail, Context, Result};
+use openssl::base64;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::NamedTempFile;
+
+/// The release managers' keys at the time of the build, `~/.phpdownloader/php-keyring.gpg`
+/// replaces it when php.net adds new ones.
+const BUNDLED_KEYRING: &str = include_str!("../php-keyring.gpg");
+
+/// Where the detached signature of a tarball is kept, next to it in the registry.
+pub fn signature_path(tarball: &Path) -> PathBuf {
+    let mut path = tarball.as_os_str().to_owned();
+    path.push(".asc");
+    PathBuf::from(path)
+}
+
+/// Keep the signature next to `tarball` so later checks don't need the network.
+///
+/// # Errors
+///
+/// Fails if the signature can't be written.
+pub fn save(tarball: &Path, signature: &[u8]) -> Result<()> {
+    cache::write_atomic(&signature_path(tarball), signature)
+}
+
+// gpgv only reads binary keyrings, so strip the armor from ours
+fn dearmor(data: &[u8]) -> Result<Vec<u8>> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return Ok(data.to_vec());
+    };
+
+    if !text.trim_start().starts_with("-----BEGIN PGP") {
+        return Ok(data.to_vec());
+    }
+
+    let mut packets = vec![];
+    let mut lines = text.lines().map(str::trim);
+
+    while lines.any(|l| l.starts_with("-----BEGIN PGP")) {
+        // Armor headers run up to the first blank line, the body up to the CRC or footer
+        let body: String = lines
+            .by_ref()
+            .skip_while(|l| !l.is_empty())
+            .skip(1)
+            .take_while(|l| !l.starts_with('=') && !l.starts_with("-----END"))
+            .collect();
+
+        packets.extend(base64::decode_block(&body).context("Malformed armored keyring")?);
+    }
+
+    Ok(packets)
+}
+
+#[allow(clippy::unnecessary_debug_formatting)]
+fn keyring() -> Result<NamedTempFile> {
+    let path = Config::keyring_path()?;
+
+    let data = if path.exists() {
+        fs::read(&path).context(format!("Unable to read {path:?}"))?
+    } else {
+        BUNDLED_KEYRING.as_bytes().to_vec()
+    };
+
+    let mut tmp = NamedTempFile::new_in(Config::tmp_dir())?;
+    tmp.write_all(&dearmor(&data)?)?;
+
+    Ok(tmp)
+}
+
+/// Check `signature` is a good signature of `tarball` by one of the release managers.
+///
+/// # Errors
+///
+/// Fails if `gpgv` isn't installed or doesn't accept the signature.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn verify(tarball: &Path, signature: &[u8]) -> Result<()> {
+    let keyring = keyring()?;
+
+    let mut sig = NamedTempFile::new_in(Config::tmp_dir())?;
+    sig.write_all(signature)?;
+
+    let output = Command::new("gpgv")
+        .arg("--keyring")
+        .arg(keyring.path())
+        .arg(sig.path())
+        .arg(tarball)
+        .output()
+        .context("Unable to run gpgv, is GnuPG installed?")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Bad or unknown signature for {tarball:?}: {}",
+            stderr.lines().last().unwrap_or_default().trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Download the detached signature php.net publishes next to `url`.
+///
+/// # Errors
+///
+/// Fails if there is no signature or it can't be downloaded.
+pub async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let url = format!("{url}.asc");
+    let req = auth::authorize(http_client().get(&url), &url);
+    let res = send(req)
+        .await?
+        .error_for_status()
+        .context(format!("No signature at {url}"))?;
+
+    Ok(timed(res.bytes()).await?.to_vec())
+}
+
+/// Verify `tarball`, downloaded from `url`, when signature checks are enabled.  A signature saved
+/// next to the tarball is used if there is one.  Returns the signature that was checked so the
+/// caller can save it, or `None` if nothing was checked.
+///
+/// # Errors
+///
+/// Fails if the signature is missing or bad, unless `--insecure` was given.
+pub async fn check(url: &str, tarball: &Path) -> Result<Option<Vec<u8>>> {
+    if !Config::verify_signatures() {
+        return Ok(None);
+    }
+
+    let res = async {
+        let signature = match fs::read(signature_path(tarball)) {
+            Ok(signature) => signature,
+            Err(_) => fetch(url).await?,
+        };
+
+        let (path, sig) = (tarball.to_path_buf(), signature.clone());
+        tokio::task::spawn_blocking(move || verify(&path, &sig))
+            .await
+            .context("Background task failed")??;
+
+        Ok::<_, anyhow::Error>(signature)
+    }
+    .await;
+
+    match res {
+        Ok(signature) => Ok(Some(signature)),
+        Err(e) if Config::insecure() => {
+            progress::note(msg!(Msg::SignatureIgnored, error = format!("{e:#}")));
+            Ok(None)
+        }
+        Err(e) => Err(e.context(msg!(Msg::SignatureRefused))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dearmor_bundled_keyring() {
+        let packets =
+            dearmor(BUNDLED_KEYRING.as_bytes()).expect("Can't dearmor the bundled keyring");
+        // A public key packet, old or new format
+        assert!(matches!(packets.first(), Some(0x99 | 0xc6)));
+        assert_eq!(
+            dearmor(&packets).expect("Can't pass binary packets through"),
+            packets
+        );
+    }
+}
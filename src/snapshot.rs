@@ -0,0 +1,213 @@
+use crate::{
+    cache,
+    downloads::{http_client, send, timed, DownloadInfo, Extension, Version},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const COMMITS_URL: &str = "https://api.github.com/repos/php/php-src/commits";
+const ARCHIVE_URL: &str = "https://github.com/php/php-src/archive";
+const RAW_URL: &str = "https://raw.githubusercontent.com/php/php-src";
+
+/// Where the commit a snapshot tarball was made from is kept, next to it in the registry.
+pub fn commit_path(tarball: &Path) -> PathBuf {
+    let mut path = tarball.as_os_str().to_owned();
+    path.push(".commit");
+    PathBuf::from(path)
+}
+
+/// Record the commit `tarball` was made from.
+///
+/// # Errors
+///
+/// Fails if the file can't be written.
+pub fn save_commit(tarball: &Path, commit: &str) -> Result<()> {
+    cache::write_atomic(&commit_path(tarball), format!("{commit}\n").as_bytes())
+}
+
+/// The commit recorded for `tarball`, `None` for snapshots fetched before we recorded them.
+pub fn saved_commit(tarball: &Path) -> Option<String> {
+    let commit = fs::read_to_string(commit_path(tarball)).ok()?;
+    let commit = commit.trim();
+
+    is_commit(commit).then(|| commit.to_string())
+}
+
+fn is_commit(s: &str) -> bool {
+    s.len() == 40 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A commit abbreviated the way git does by default.
+pub fn short(commit: &str) -> &str {
+    commit.get(..12).unwrap_or(commit)
+}
+
+/// A php-src development branch to download a snapshot of, `master` or `branch:PHP-8.4`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    branch: String,
+}
+
+impl FromStr for Snapshot {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let branch = match s.split_once(':') {
+            Some(("branch", branch)) => branch,
+            _ if s == "master" => s,
+            _ => bail!("Not a snapshot, expected `master` or `branch:NAME`"),
+        };
+
+        if branch.is_empty()
+            || branch.starts_with('-')
+            || branch.contains("..")
+            || branch.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            bail!("Invalid branch name {branch:?}");
+        }
+
+        Ok(Self {
+            branch: branch.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.branch == "master" {
+            write!(f, "master")
+        } else {
+            write!(f, "branch:{}", self.branch)
+        }
+    }
+}
+
+// php_version.h carries the version the branch will be released as, e.g. "8.5.0-dev"
+fn parse_version_header(header: &str) -> Result<Version> {
+    let re = Regex::new(r#"#define\s+PHP_VERSION\s+"([^"]+)""#).expect("Can't parse regex");
+
+    let version = re
+        .captures(header)
+        .and_then(|caps| caps.get(1))
+        .ok_or_else(|| anyhow!("No PHP_VERSION in php_version.h"))?
+        .as_str();
+
+    let version: Version = version
+        .parse()
+        .context(format!("Unable to parse PHP_VERSION {version:?}"))?;
+
+    if !version.is_snapshot() {
+        bail!("PHP_VERSION {version} isn't a development version");
+    }
+
+    Ok(version)
+}
+
+impl Snapshot {
+    /// The commit at the head of the branch right now.
+    ///
+    /// # Errors
+    ///
+    /// Fails if GitHub can't be reached or the branch doesn't exist.
+    pub async fn head(&self) -> Result<String> {
+        let url = format!("{COMMITS_URL}/{}", self.branch);
+        let res = send(
+            http_client()
+                .get(&url)
+                .header("Accept", "application/vnd.github.sha"),
+        )
+        .await?
+        .error_for_status()
+        .context(format!("Unable to find php-src {self}"))?;
+
+        let commit = timed(res.text()).await?.trim().to_string();
+        if !is_commit(&commit) {
+            bail!("Unexpected commit {commit:?} for php-src {self}");
+        }
+
+        Ok(commit)
+    }
+
+    /// The pseudo-version of the branch at `commit`, which is what the registry files it under.
+    ///
+    /// # Errors
+    ///
+    /// Fails if GitHub can't be reached or the commit has no usable version header.
+    pub async fn version(&self, commit: &str) -> Result<Version> {
+        let url = format!("{RAW_URL}/{commit}/main/php_version.h");
+        let res = send(http_client().get(&url))
+            .await?
+            .error_for_status()
+            .context(format!("Unable to read the version of php-src {self}"))?;
+
+        parse_version_header(&timed(res.text()).await?)
+    }
+
+    /// Where to download the current head of the branch from, pinned to its commit so what we
+    /// record is what we get, along with that commit.  These are always gzipped.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the branch's head or version can't be determined.
+    pub async fn download_info(&self) -> Result<(DownloadInfo, String)> {
+        let commit = self.head().await?;
+        let version = self.version(&commit).await?;
+        let url = format!("{ARCHIVE_URL}/{commit}.tar.gz");
+
+        Ok((
+            DownloadInfo::new(version, &url, 0, None, Extension::GZ),
+            commit,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_snapshots() {
+        assert_eq!(
+            "master"
+                .parse::<Snapshot>()
+                .expect("Can't parse master")
+                .to_string(),
+            "master"
+        );
+        assert_eq!(
+            "branch:PHP-8.4"
+                .parse::<Snapshot>()
+                .expect("Can't parse branch")
+                .to_string(),
+            "branch:PHP-8.4"
+        );
+        assert!("8.4".parse::<Snapshot>().is_err());
+        assert!("branch:".parse::<Snapshot>().is_err());
+    }
+
+    #[test]
+    fn reads_dev_version() {
+        let header = "#define PHP_MINOR_VERSION 5\n#define PHP_VERSION \"8.5.0-dev\"\n";
+        let version = parse_version_header(header).expect("Can't read PHP_VERSION");
+
+        assert_eq!(version.to_string(), "8.5.0-dev");
+        assert!(parse_version_header("#define PHP_VERSION \"8.4.1\"").is_err());
+    }
+
+    #[test]
+    fn records_commits() {
+        let dir = tempfile::tempdir().expect("Can't create registry");
+        let tarball = dir.path().join("php-8.5.0-dev.tar.gz");
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+
+        assert_eq!(saved_commit(&tarball), None);
+        save_commit(&tarball, commit).expect("Can't save commit");
+        assert_eq!(saved_commit(&tarball).as_deref(), Some(commit));
+        assert_eq!(short(commit), "0123456789ab");
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{
+    audit::{self, Confirmation},
+    config::Config,
+    downloads, extract,
+    messages::{msg, Msg},
+    signature, snapshot,
+};
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+use tar::{Archive, Builder, Header};
+use tempfile::NamedTempFile;
+
+/// What makes up a setup worth moving to another machine, by name in the archive.  Caches, logs
+/// and build trees are left behind, tarballs only go along when asked for.
+fn items(tarballs: bool) -> Result<Vec<(&'static str, PathBuf)>> {
+    let mut items = vec![
+        (Config::APP_SETTINGS_FILE, Config::settings_path()?),
+        (Config::APP_HOOKS_PATH, Config::hooks_path()?),
+        (Config::APP_PATCHES_PATH, Config::patches_path()?),
+        (Config::APP_LOCALE_PATH, Config::locale_path()?),
+        (Config::APP_KEYRING_FILE, Config::keyring_path()?),
+        (Config::APP_QUEUE_FILE, Config::queue_path()?),
+    ];
+
+    if tarballs {
+        items.push((Config::APP_REGISTRY_PATH, Config::registry_path()?));
+    }
+
+    Ok(items)
+}
+
+/// What `export` wrote.
+#[derive(Debug, Default)]
+pub struct Exported {
+    pub items: Vec<&'static str>,
+    /// Inline mirror passwords and tokens left out of config.json
+    pub secrets_removed: usize,
+}
+
+// Drop the inline `password` and `token` of every mirror in `auth`, returning how many there were.
+// The `-env` variants only name a variable, so they can go along.
+fn remove_secrets(settings: &mut serde_json::Value) -> usize {
+    let Some(auth) = settings
+        .get_mut("auth")
+        .and_then(serde_json::Value::as_object_mut)
+    else {
+        return 0;
+    };
+
+    auth.values_mut()
+        .filter_map(serde_json::Value::as_object_mut)
+        .map(|mirror| {
+            ["password", "token"]
+                .iter()
+                .filter(|key| mirror.remove(**key).is_some())
+                .count()
+        })
+        .sum()
+}
+
+/// Write settings, hooks, patches, locales, the keyring and the download queue to a gzipped
+/// tarball at `dst`, along with the registry if `tarballs` is set.  Inline mirror credentials
+/// are left out of the settings unless `secrets` is set.
+///
+/// # Errors
+///
+/// Fails if anything can't be read or `dst` can't be written.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn export(dst: &Path, tarballs: bool, secrets: bool) -> Result<Exported> {
+    let dir = dst.parent().filter(|p| !p.as_os_str().is_empty());
+    let tmp = NamedTempFile::new_in(dir.unwrap_or_else(|| Path::new(".")))?;
+
+    let mut builder = Builder::new(GzEncoder::new(tmp.as_file(), Compression::default()));
+    builder.follow_symlinks(false);
+
+    let mut exported = Exported::default();
+
+    for (name, path) in items(tarballs)? {
+        let Ok(meta) = path.symlink_metadata() else {
+            continue;
+        };
+
+        if name == Config::APP_SETTINGS_FILE && !secrets {
+            let mut settings = Config::read_settings_json()?.unwrap_or_default();
+            exported.secrets_removed = remove_secrets(&mut settings);
+            let data = serde_json::to_vec_pretty(&settings)?;
+
+            let mut header = Header::new_gnu();
+            header.set_metadata(&meta);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, name, &data[..])
+        } else if meta.is_dir() {
+            builder.append_dir_all(name, &path)
+        } else {
+            builder.append_path_with_name(&path, name)
+        }
+        .context(format!("Unable to add {path:?}"))?;
+
+        exported.items.push(name);
+    }
+
+    builder.into_inner()?.finish()?;
+    tmp.persist(dst)
+        .context(format!("Unable to write {dst:?}"))?;
+
+    Ok(exported)
+}
+
+// Move the tarballs in an unpacked registry into ours.  Their checksum files came from the same
+// archive, so each tarball is hashed again: one that doesn't match is left behind, and the
+// checksum we record is the one we computed.
+#[allow(clippy::unnecessary_debug_formatting)]
+fn import_tarballs(src: &Path, dst: &Path) -> Result<()> {
+    for info in extract::Tarball::list(src)?.report() {
+        let path = PathBuf::from(&info.location);
+        let name = path.file_name().context("Tarball has no file name")?;
+        let actual = downloads::sha256_file(&path).context(format!("Unable to read {path:?}"))?;
+
+        if downloads::saved_checksum(&path).is_some_and(|expected| expected != actual) {
+            eprintln!(
+                "{}",
+                msg!(Msg::ImportChecksumMismatch, path = format!("{name:?}"))
+            );
+            continue;
+        }
+
+        let tarball = dst.join(name);
+        extract::move_path(&path, &tarball)?;
+        downloads::save_checksum(&tarball, &actual)?;
+
+        for (side, dst) in [
+            (
+                signature::signature_path(&path),
+                signature::signature_path(&tarball),
+            ),
+            (
+                snapshot::commit_path(&path),
+                snapshot::commit_path(&tarball),
+            ),
+        ] {
+            if side.exists() {
+                extract::move_path(&side, &dst)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Unpack a `state export` archive over this machine's state.  Each item in it replaces ours,
+/// except tarballs which are added to the registry.  Returns what was imported.
+///
+/// # Errors
+///
+/// Fails if the archive can't be read or holds nothing we know about.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn import(src: &Path) -> Result<Vec<&'static str>> {
+    let file = File::open(src).context(format!("Unable to open {src:?}"))?;
+
+    // Unpacked next to where things end up so moving them into place is a rename
+    let staging = tempfile::Builder::new()
+        .prefix(".phpdownloader-import")
+        .tempdir_in(Config::app_root()?)?;
+
+    let mut archive = Archive::new(GzDecoder::new(file));
+    archive.set_preserve_permissions(true);
+    archive
+        .unpack(staging.path())
+        .context(format!("Unable to unpack {src:?}"))?;
+
+    let mut imported = vec![];
+
+    for (name, dst) in items(true)? {
+        let src = staging.path().join(name);
+        if src.symlink_metadata().is_err() {
+            continue;
+        }
+
+        if name == Config::APP_REGISTRY_PATH {
+            import_tarballs(&src, &dst)?;
+        } else {
+            if dst.is_dir() {
+                fs::remove_dir_all(&dst).context(format!("Unable to replace {dst:?}"))?;
+            }
+            extract::move_path(&src, &dst)?;
+        }
+
+        audit::record("import", &dst, Confirmation::prompted());
+        imported.push(name);
+    }
+
+    if imported.is_empty() {
+        bail!("{src:?} isn't a php-downloader state archive");
+    }
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_leaves_out_inline_secrets() {
+        let mut settings = serde_json::json!({
+            "auth": {
+                "mirror.example.com": { "username": "me", "password": "hunter2" },
+                "other.example.com": { "token": "abc", "token-env": "MIRROR_TOKEN" },
+            }
+        });
+
+        assert_eq!(remove_secrets(&mut settings), 2);
+        assert_eq!(settings["auth"]["mirror.example.com"]["username"], "me");
+        assert!(settings["auth"]["mirror.example.com"]["password"].is_null());
+        assert!(settings["auth"]["other.example.com"]["token"].is_null());
+        assert_eq!(
+            settings["auth"]["other.example.com"]["token-env"],
+            "MIRROR_TOKEN"
+        );
+    }
+
+    #[test]
+    fn import_rehashes_tarballs() {
+        let src = tempfile::tempdir().expect("Can't create archive directory");
+        let dst = tempfile::tempdir().expect("Can't create registry");
+
+        let good = src.path().join("php-8.3.1.tar.bz2");
+        fs::write(&good, "good").expect("Can't write tarball");
+        let bad = src.path().join("php-8.3.2.tar.bz2");
+        fs::write(&bad, "tampered").expect("Can't write tarball");
+        let digest = downloads::sha256_file(&good).expect("Can't hash tarball");
+        downloads::save_checksum(&bad, &digest).expect("Can't write checksum");
+
+        import_tarballs(src.path(), dst.path()).expect("Can't import");
+
+        let imported = dst.path().join("php-8.3.1.tar.bz2");
+        assert_eq!(downloads::saved_checksum(&imported), Some(digest));
+        assert!(!dst.path().join("php-8.3.2.tar.bz2").exists());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{config::Config, downloads::Version, platform::Platform};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Expands `{name}` placeholders in configured hook commands and arguments.  A literal brace can
+/// be written as `{{` or `}}`.
+#[derive(Debug, Default)]
+pub struct Template {
+    vars: Vec<(&'static str, String)>,
+}
+
+impl Template {
+    pub const DEFAULT_PREFIX: &'static str = "/usr/local";
+
+    #[must_use]
+    pub fn set<S: Into<String>>(mut self, name: &'static str, value: S) -> Self {
+        self.vars.retain(|(n, _)| *n != name);
+        self.vars.push((name, value.into()));
+        self
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn for_version(version: Version) -> Self {
+        Self::default()
+            .set("version", version.to_string())
+            .set("major", version.major.to_string())
+            .set("minor", version.minor.to_string())
+            .set("patch", version.patch.unwrap_or(0).to_string())
+            .set("series", format!("{}.{}", version.major, version.minor))
+    }
+
+    /// The variables available to hooks run against a build root.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the configured prefix itself can't be expanded.
+    pub fn for_build(version: Version, build_root: &Path) -> Result<Self> {
+        let settings = Config::settings();
+
+        let jobs = settings.jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+        });
+
+        let vars = Self::for_version(version)
+            .set("build_root", build_root.to_string_lossy())
+            .set("jobs", jobs.to_string())
+            .set("configure_args", Platform::detect().configure_line());
+
+        // The prefix may be built from the other variables, e.g. "/opt/php/{version}"
+        let prefix = vars.expand(settings.prefix.as_deref().unwrap_or(Self::DEFAULT_PREFIX))?;
+
+        Ok(vars.set("prefix", prefix))
+    }
+
+    /// Replace every placeholder in `s`.
+    ///
+    /// # Errors
+    ///
+    /// Fails on an unknown variable or an unterminated placeholder.
+    pub fn expand(&self, s: &str) -> Result<String> {
+        self.expand_with(s, |_, value| value.to_string())
+    }
+
+    /// Replace every placeholder in `s` with what `escape` makes of the variable's name and
+    /// value, e.g. to quote them for a shell.
+    ///
+    /// # Errors
+    ///
+    /// Fails on an unknown variable or an unterminated placeholder.
+    pub fn expand_with<F>(&self, s: &str, escape: F) -> Result<String>
+    where
+        F: Fn(&str, &str) -> String,
+    {
+        let mut res = String::with_capacity(s.len());
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    res.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    res.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("Unterminated template variable in {s:?}"),
+                        }
+                    }
+
+                    match self.get(&name) {
+                        Some(value) => res.push_str(&escape(&name, value)),
+                        None => bail!("Unknown template variable {{{name}}} in {s:?}"),
+                    }
+                }
+                c => res.push(c),
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_variables() {
+        let tmpl = Template::default()
+            .set("major", "8")
+            .set("minor", "3")
+            .set("jobs", "4");
+
+        assert_eq!(
+            tmpl.expand("php-{major}.{minor} -j{jobs}")
+                .expect("Can't expand variables"),
+            "php-8.3 -j4"
+        );
+        assert_eq!(
+            tmpl.expand("{{major}}")
+                .expect("Can't expand escaped braces"),
+            "{major}"
+        );
+        assert_eq!(
+            tmpl.expand("no placeholders")
+                .expect("Can't expand plain text"),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn expand_unknown_variable() {
+        let tmpl = Template::default().set("major", "8");
+
+        assert!(tmpl.expand("{minor}").is_err());
+        assert!(tmpl.expand("{major").is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{auth, config::Config};
+use anyhow::{bail, Context, Result};
+use reqwest::{Certificate, NoProxy, Proxy, Url};
+use std::{fs, path::Path};
+
+const DEFAULT_IPFS_GATEWAY: &str = "https://ipfs.io";
+
+/// Turn a mirror URL into one we can fetch over HTTP.  Plain URLs pass through untouched, while
+/// `ipfs://<cid>` (e.g. a pinned `mirror export` directory) is rewritten to go through the
+/// `ipfs-gateway`.
+///
+/// # Errors
+///
+/// Fails for transports we can't use.
+pub fn mirror_url(url: &str) -> Result<String> {
+    if let Some(path) = url.strip_prefix("ipfs://") {
+        return Ok(ipfs_url(path));
+    }
+
+    if url.starts_with("magnet:") || url.ends_with(".torrent") {
+        bail!("BitTorrent mirrors aren't supported, publish the mirror over IPFS or HTTP instead");
+    }
+
+    Ok(url.to_string())
+}
+
+/// Route all requests through the proxy at `url`, overriding `HTTP_PROXY` and friends while
+/// still honouring `NO_PROXY`.  Credentials come from the URL, or failing that the `auth` setting
+/// or `.netrc` entry for the proxy host.
+///
+/// # Errors
+///
+/// Fails for malformed URLs and proxy schemes this build can't use.
+pub fn proxy(url: &str) -> Result<Proxy> {
+    let parsed = Url::parse(url).context(format!("Invalid proxy URL {url}"))?;
+
+    match parsed.scheme() {
+        "http" | "https" => {}
+        "socks5" | "socks5h" => {
+            bail!("Can't use {url}, SOCKS proxies need php-downloader built with reqwest's `socks` feature")
+        }
+        scheme => bail!("Unsupported proxy scheme {scheme}, use http, https or socks5"),
+    }
+
+    let mut proxy = Proxy::all(url)
+        .context(format!("Invalid proxy URL {url}"))?
+        .no_proxy(NoProxy::from_env());
+
+    if parsed.username().is_empty() {
+        if let Some((user, password)) = parsed.host_str().and_then(auth::proxy_credentials) {
+            proxy = proxy.basic_auth(&user, &password);
+        }
+    }
+
+    Ok(proxy)
+}
+
+/// The certificates in the PEM bundle at `path`.
+///
+/// # Errors
+///
+/// Fails if the file can't be read or holds no certificates.
+#[allow(clippy::unnecessary_debug_formatting)]
+pub fn ca_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let pem = fs::read(path).context(format!("Unable to read CA bundle {path:?}"))?;
+    let certs =
+        Certificate::from_pem_bundle(&pem).context(format!("Invalid CA bundle {path:?}"))?;
+
+    if certs.is_empty() {
+        bail!("No certificates in CA bundle {path:?}");
+    }
+
+    Ok(certs)
+}
+
+fn ipfs_url(path: &str) -> String {
+    let gateway = Config::settings()
+        .ipfs_gateway
+        .as_deref()
+        .unwrap_or(DEFAULT_IPFS_GATEWAY)
+        .trim_end_matches('/');
+
+    format!("{gateway}/ipfs/{path}")
+}
@@ -0,0 +1,57 @@
+use crate::{
+    constraint::VersionArg,
+    messages::{msg, Msg},
+};
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Files naming a project's PHP version, ours first so it can override one meant for phpenv.
+pub const NAMES: [&str; 2] = [".phpdownloader-version", ".php-version"];
+
+// The first line that isn't blank or a comment, without the `php-` prefix phpenv allows
+#[allow(clippy::unnecessary_debug_formatting)]
+fn parse(path: &Path) -> Result<VersionArg> {
+    let data = fs::read_to_string(path).context(format!("Unable to read {path:?}"))?;
+
+    let line = data
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .context(format!("{path:?} doesn't name a version"))?;
+
+    line.trim_start_matches("php-")
+        .parse()
+        .context(format!("Invalid version in {path:?}"))
+}
+
+/// The version file nearest `dir`, looking in it and then each of its parents.
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// The version the nearest version file above the current directory asks for.
+///
+/// # Errors
+///
+/// Fails if there's no version file or it can't be parsed.
+pub fn version() -> Result<VersionArg> {
+    let cwd = std::env::current_dir().context("Unable to determine the current directory")?;
+    let path = find(&cwd).context(msg!(Msg::NoVersionFile, path = format!("{cwd:?}")))?;
+
+    let version = parse(&path)?;
+    eprintln!(
+        "{}",
+        msg!(
+            Msg::VersionFromFile,
+            version = version,
+            path = format!("{path:?}")
+        )
+    );
+
+    Ok(version)
+}
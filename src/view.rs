@@ -1,10 +1,81 @@
-use crate::downloads::DownloadInfo;
+use crate::{
+    downloads::{ActiveRelease, DownloadInfo, Support, Version},
+    extract::{SourceInfo, Verdict},
+};
 
 use colored::Colorize;
+use serde::Serialize;
 use serde_json::to_string_pretty;
+use std::path::PathBuf;
 
 pub trait Viewer: Send + Sync {
     fn display(&self, data: &[DownloadInfo]);
+
+    /// Display downloads along with the newest upstream version in their series.
+    fn display_updates(&self, data: &[(DownloadInfo, Option<Version>, VersionStatus)]);
+
+    fn display_roots(&self, roots: &[RootInfo]);
+
+    /// Display the supported release series.
+    fn display_active(&self, releases: &[ActiveRelease]);
+
+    /// Display the outcome of `verify`.
+    fn display_verify(&self, results: &[VerifyResult]);
+
+    /// Display named application paths, see `paths`.
+    fn display_paths(&self, paths: &[(&str, PathBuf)]);
+}
+
+/// How a cached release stands against what php.net currently ships.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionStatus {
+    /// The newest release of a supported series.
+    Current,
+    /// A supported series with a newer patch release out.
+    Outdated,
+    /// The series no longer gets any fixes.
+    Eol,
+    /// We couldn't find out, or it's a snapshot.
+    Unknown,
+}
+
+impl VersionStatus {
+    /// Whether `cached --problems-only` should show it.
+    pub const fn is_problem(self) -> bool {
+        matches!(self, Self::Outdated | Self::Eol)
+    }
+}
+
+/// One tarball or build root checked by `verify`.
+#[derive(Debug, Serialize)]
+pub struct VerifyResult {
+    pub kind: &'static str,
+    pub version: Version,
+    pub path: PathBuf,
+    pub verdict: Verdict,
+    pub detail: String,
+}
+
+/// A discovered build root along with the details we show about it.
+#[derive(Debug, Serialize)]
+pub struct RootInfo {
+    pub path: PathBuf,
+    pub version: Version,
+    pub modifiers: String,
+    pub size: u64,
+    pub latest: Option<Version>,
+    pub source: Option<SourceInfo>,
+    pub pinned: bool,
+}
+
+#[derive(Serialize)]
+struct UpdateInfo<'a> {
+    #[serde(flatten)]
+    info: &'a DownloadInfo,
+    latest: Option<Version>,
+    outdated: bool,
+    status: VersionStatus,
 }
 
 struct CliViewer;
@@ -47,12 +118,150 @@ pub trait ToHumanSize {
     }
 }
 
+impl CliViewer {
+    fn update_note(version: Version, latest: Option<Version>) -> String {
+        match latest {
+            Some(latest) if latest > version => format!("\u{2192} {latest}").yellow().to_string(),
+            Some(_) => "up to date".green().to_string(),
+            None => "unknown".dimmed().to_string(),
+        }
+    }
+
+    fn status_note(latest: Option<Version>, status: VersionStatus) -> String {
+        match (status, latest) {
+            (VersionStatus::Eol, _) => "end of life".red().to_string(),
+            (VersionStatus::Outdated, Some(latest)) => {
+                format!("\u{2192} {latest}").yellow().to_string()
+            }
+            (VersionStatus::Current, _) => "up to date".green().to_string(),
+            _ => "unknown".dimmed().to_string(),
+        }
+    }
+
+    fn source_note(source: &SourceInfo) -> String {
+        let mut note = format!("({} {}", source.tarball, source.checksum);
+        if let Some(libc) = &source.libc {
+            note.push(' ');
+            note.push_str(libc);
+        }
+        note.push(')');
+        note
+    }
+}
+
 impl Viewer for CliViewer {
+    fn display_updates(&self, data: &[(DownloadInfo, Option<Version>, VersionStatus)]) {
+        let width = data
+            .iter()
+            .map(|(info, ..)| info.version.to_string().len())
+            .max()
+            .unwrap_or(0);
+
+        for (info, latest, status) in data {
+            let version = format!("{:<width$}", info.version.to_string());
+            let version = match status {
+                VersionStatus::Current => version.green(),
+                VersionStatus::Outdated => version.yellow(),
+                VersionStatus::Eol => version.red(),
+                VersionStatus::Unknown => version.normal(),
+            };
+
+            println!(
+                "{}\t{}\t{}",
+                version.bold(),
+                Self::status_note(*latest, *status),
+                info.location,
+            );
+        }
+    }
+
+    fn display_active(&self, releases: &[ActiveRelease]) {
+        for release in releases {
+            let support = match release.support {
+                Support::Security => "security fixes only".yellow(),
+                Support::Active => "active support".green(),
+            };
+
+            println!(
+                "{}\t{}\t{}\t{support}",
+                format!("{}.{}", release.major, release.minor).bold(),
+                release
+                    .latest
+                    .map_or_else(|| String::from("-"), |v| v.to_string()),
+                release.date.as_deref().unwrap_or("-"),
+            );
+        }
+    }
+
+    fn display_roots(&self, roots: &[RootInfo]) {
+        let max_lens = roots.iter().fold([0, 0, 0], |mut acc, root| {
+            acc[0] = acc[0].max(root.version.to_string().len());
+            acc[1] = acc[1].max(root.modifiers.len());
+            acc[2] = acc[2].max(root.size.to_human_size().len());
+            acc
+        });
+
+        for root in roots {
+            let mut note = Self::update_note(root.version, root.latest);
+            if root.pinned {
+                note = format!("{note} {}", "(pinned)".cyan());
+            }
+
+            println!(
+                "{:<width0$}\t{:<width1$}\t{:>width2$}\t{}\t{}\t{}",
+                root.version.to_string().bold(),
+                root.modifiers,
+                root.size.to_human_size(),
+                note,
+                root.path.display(),
+                root.source
+                    .as_ref()
+                    .map_or_else(String::new, Self::source_note)
+                    .dimmed(),
+                width0 = max_lens[0],
+                width1 = max_lens[1],
+                width2 = max_lens[2],
+            );
+        }
+    }
+
+    fn display_verify(&self, results: &[VerifyResult]) {
+        let width = results
+            .iter()
+            .map(|r| r.version.to_string().len())
+            .max()
+            .unwrap_or(0);
+
+        for res in results {
+            let verdict = match res.verdict {
+                Verdict::Ok => "ok".green(),
+                Verdict::Failed => "FAILED".red().bold(),
+                Verdict::Unchecked => "unchecked".yellow(),
+            };
+
+            println!(
+                "{verdict:<9}\t{:<7}\t{:<width$}\t{}\t{}",
+                res.kind,
+                res.version.to_string().bold(),
+                res.path.display(),
+                res.detail.dimmed(),
+            );
+        }
+    }
+
+    fn display_paths(&self, paths: &[(&str, PathBuf)]) {
+        let width = paths.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+
+        for (name, path) in paths {
+            println!("{name:<width$}\t{}", path.display());
+        }
+    }
+
     fn display(&self, urls: &[DownloadInfo]) {
         // Calculating the maximum lengths of each field in a more idiomatic way
         let max_lens = urls.iter().fold([0, 0, 0, 0], |mut acc, url| {
             acc[0] = acc[0].max(url.version.to_string().len());
-            acc[1] = acc[1].max(url.size.to_human_size().len());
+            acc[1] = acc[1].max(url.size_string().len());
             acc[2] = acc[2].max(url.date_string().len());
             acc[3] = acc[3].max(url.location.len());
             acc
@@ -64,7 +273,7 @@ impl Viewer for CliViewer {
             println!(
                 "{:<width0$}\t{:<width1$}\t{:>width2$}\t{:<width3$}",
                 url.version.to_string().bold(),
-                url.size.to_human_size(),
+                url.size_string(),
                 url.date_string(),
                 url.location,
                 width0 = max_lens[0],
@@ -81,6 +290,46 @@ impl Viewer for JsonViewer {
         let s = to_string_pretty(urls).unwrap_or_else(|_| String::from("Error generating JSON"));
         println!("{s}");
     }
+
+    fn display_active(&self, releases: &[ActiveRelease]) {
+        let s =
+            to_string_pretty(releases).unwrap_or_else(|_| String::from("Error generating JSON"));
+        println!("{s}");
+    }
+
+    fn display_verify(&self, results: &[VerifyResult]) {
+        let s = to_string_pretty(results).unwrap_or_else(|_| String::from("Error generating JSON"));
+        println!("{s}");
+    }
+
+    fn display_paths(&self, paths: &[(&str, PathBuf)]) {
+        let map: serde_json::Map<_, _> = paths
+            .iter()
+            .map(|(name, path)| ((*name).to_string(), path.to_string_lossy().into()))
+            .collect();
+        let s = to_string_pretty(&map).unwrap_or_else(|_| String::from("Error generating JSON"));
+        println!("{s}");
+    }
+
+    fn display_roots(&self, roots: &[RootInfo]) {
+        let s = to_string_pretty(roots).unwrap_or_else(|_| String::from("Error generating JSON"));
+        println!("{s}");
+    }
+
+    fn display_updates(&self, data: &[(DownloadInfo, Option<Version>, VersionStatus)]) {
+        let data: Vec<_> = data
+            .iter()
+            .map(|(info, latest, status)| UpdateInfo {
+                info,
+                latest: *latest,
+                outdated: latest.is_some_and(|l| l > info.version),
+                status: *status,
+            })
+            .collect();
+
+        let s = to_string_pretty(&data).unwrap_or_else(|_| String::from("Error generating JSON"));
+        println!("{s}");
+    }
 }
 
 pub fn get_viewer(json: bool) -> Box<dyn Viewer + Send + Sync> {